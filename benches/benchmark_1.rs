@@ -1,5 +1,9 @@
 use criterion::{criterion_group, criterion_main, Criterion, BatchSize, BenchmarkId};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsClient, KvsEngine, KvsServer, SledKvsEngine};
+use kvs::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool, WorkStealingThreadPool};
+use std::net::SocketAddr;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use rand::prelude::*;
 
@@ -14,7 +18,7 @@ pub fn set_benchmark(c: &mut Criterion) {
                                     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
                                     (KvStore::open(temp_dir.path()).unwrap(), temp_dir)
                                 },
-                                |(mut store, _tem_dir)| {
+                                |(store, _tem_dir)| {
                                     for i in 1..(1 << 12) {
                                         store.set(format!("key{}", i), "value".to_string()).unwrap();
                                     }
@@ -30,7 +34,7 @@ pub fn set_benchmark(c: &mut Criterion) {
                                     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
                                     (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
                                 },
-                                |(mut db, _tem_dir)| {
+                                |(db, _tem_dir)| {
                                     for i in 1..(1 << 12) {
                                         db.set(format!("key{}", i), "value".to_string()).unwrap();
                                     }
@@ -52,7 +56,7 @@ pub fn get_benchmark(c: &mut Criterion) {
                               &i,
                               |b, j| {
                                   let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-                                  let mut store = KvStore::open(temp_dir.path()).unwrap();
+                                  let store = KvStore::open(temp_dir.path()).unwrap();
                                   for key_i in 1..(1 << j) {
                                       store.set(format!("key{}", key_i), "value".to_string()).unwrap();
                                   }
@@ -64,7 +68,7 @@ pub fn get_benchmark(c: &mut Criterion) {
                               &i,
                               |b, j| {
                                   let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-                                  let mut store = SledKvsEngine::open(temp_dir.path()).unwrap();
+                                  let store = SledKvsEngine::open(temp_dir.path()).unwrap();
                                   for key_i in 1..(1 << j) {
                                       store.set(format!("key{}", key_i), "value".to_string()).unwrap();
                                   }
@@ -75,5 +79,87 @@ pub fn get_benchmark(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, get_benchmark, set_benchmark);
+const CONCURRENT_GETS: usize = 1 << 12;
+
+// Threads reading with a clone of the store each, the time of a batch should stay
+// about flat as threads are added since clones do not share file handles.
+pub fn concurrent_get_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_get_bench");
+    group.sample_size(20);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    for key_i in 0..(1 << 8) {
+        store.set(format!("key{}", key_i), "value".to_string()).unwrap();
+    }
+    for threads in [1, 2, 4, 8] {
+        group.bench_with_input(BenchmarkId::new("kvs", threads), &threads, |b, &threads| {
+            b.iter(|| {
+                let readers: Vec<_> = (0..threads)
+                    .map(|thread_id| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            let mut rng = SmallRng::from_seed([thread_id as u8; 16]);
+                            for _ in 0..CONCURRENT_GETS {
+                                store.get(format!("key{}", rng.gen_range(0, 1 << 8))).unwrap();
+                            }
+                        })
+                    })
+                    .collect();
+                for reader in readers {
+                    reader.join().unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+const SERVER_CLIENTS: usize = 8;
+const SERVER_REQUESTS: usize = 100;
+
+// Clients that each send many short requests on a connection of their own, the
+// workload of a server whose pool hands out every connection to a worker.
+fn server_workload<P: ThreadPool + Send + Sync + 'static>(c: &mut criterion::BenchmarkGroup<'_, criterion::measurement::WallTime>, name: &str, port: u16) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let server = KvsServer::new(KvStore::open(temp_dir.path()).unwrap(), P::new(SERVER_CLIENTS).unwrap()).unwrap();
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run(addr).unwrap());
+    thread::sleep(Duration::from_millis(200));
+
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            let clients: Vec<_> = (0..SERVER_CLIENTS)
+                .map(|client_id| {
+                    thread::spawn(move || {
+                        let mut client = KvsClient::connect(addr).unwrap();
+                        for i in 0..SERVER_REQUESTS {
+                            let key = format!("client{}_key{}", client_id, i);
+                            client.set(key.clone(), "value".to_owned()).unwrap();
+                            client.get(key).unwrap();
+                        }
+                    })
+                })
+                .collect();
+            for client in clients {
+                client.join().unwrap();
+            }
+        })
+    });
+
+    shutdown.shutdown();
+    running.join().unwrap();
+}
+
+pub fn server_pool_benchmark(c: &mut Criterion) {
+    let mut group = c.benchmark_group("server_pool_bench");
+    group.sample_size(20);
+    server_workload::<NaiveThreadPool>(&mut group, "naive", 4301);
+    server_workload::<SharedQueueThreadPool>(&mut group, "shared", 4302);
+    server_workload::<RayonThreadPool>(&mut group, "rayon", 4303);
+    server_workload::<WorkStealingThreadPool>(&mut group, "stealing", 4304);
+    group.finish();
+}
+
+criterion_group!(benches, get_benchmark, set_benchmark, concurrent_get_benchmark, server_pool_benchmark);
 criterion_main!(benches);
@@ -0,0 +1,204 @@
+use super::helper::{self, AsyncReader, Response};
+use super::Result;
+use crate::engine::{ScanOptions, WriteBatch};
+use crate::frame::{Frame, Incoming};
+use crate::thread_pool::PoolStats;
+use crate::watch::WatchEvent;
+use crate::KvStoreError;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Bound;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+// the id of the hello, requests are numbered from 1.
+const HELLO_ID: u64 = 0;
+
+/// The async counterpart of `KvsClient`, it talks to a `KvsServer` or an
+/// `AsyncKvsServer` alike.
+pub struct AsyncKvsClient {
+    reader: AsyncReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+    next_id: u64,
+    // the answer to the hello is read with the first response, like `KvsClient` does.
+    hello_pending: bool,
+}
+
+impl AsyncKvsClient {
+    pub async fn connect(addr: SocketAddr) -> Result<Self> {
+        let (reader, mut writer) = TcpStream::connect(addr).await?.into_split();
+        writer.write_all(&Frame::hello(HELLO_ID)?.to_bytes()).await?;
+        Ok(AsyncKvsClient { reader: AsyncReader::new(reader), writer, next_id: HELLO_ID + 1, hello_pending: true })
+    }
+
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.into_bytes()).await?.map(String::from_utf8).transpose()?)
+    }
+
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes()).await
+    }
+
+    pub async fn rm(&mut self, key: String) -> Result<()> {
+        self.rm_bytes(key.into_bytes()).await
+    }
+
+    pub async fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(helper::Request::Get(key)).await? {
+            Response::Value(value) => Ok(value),
+            response => response.unexpected(),
+        }
+    }
+
+    pub async fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request_done(helper::Request::Set { key, value }).await
+    }
+
+    /// Sets the value of the key, the key expires once `ttl` elapsed.
+    pub async fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.request_done(helper::Request::SetWithTtl { key, value, ttl }).await
+    }
+
+    /// Returns the time left before the key expires, `None` if it never does.
+    pub async fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.request(helper::Request::Ttl(key)).await? {
+            Response::Ttl(ttl) => Ok(ttl),
+            response => response.unexpected(),
+        }
+    }
+
+    /// Fails with `KvStoreError::KeyNotFound` when the key does not exist.
+    pub async fn rm_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.request_done(helper::Request::Rm(key)).await
+    }
+
+    /// Applies every write of the batch atomically.
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.request_done(helper::Request::Batch(batch)).await
+    }
+
+    /// Swaps the value of the key only if it is `expected`, returns whether it did.
+    pub async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        match self.request(helper::Request::Cas { key, expected, new }).await? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => response.unexpected(),
+        }
+    }
+
+    /// Returns the stats of the thread pool of the server.
+    pub async fn stats(&mut self) -> Result<PoolStats> {
+        match self.request(helper::Request::Stats).await? {
+            Response::Stats(stats) => Ok(stats),
+            response => response.unexpected(),
+        }
+    }
+
+    /// Starts a transaction, the `get`, `set` and `rm` calls that follow are part of
+    /// it until `commit` or `abort`.
+    pub async fn begin(&mut self) -> Result<()> {
+        self.request_done(helper::Request::Begin).await
+    }
+
+    /// Fails with `KvStoreError::TransactionConflict` when a key the transaction read
+    /// was written by someone else since.
+    pub async fn commit(&mut self) -> Result<()> {
+        self.request_done(helper::Request::Commit).await
+    }
+
+    pub async fn abort(&mut self) -> Result<()> {
+        self.request_done(helper::Request::Abort).await
+    }
+
+    /// Returns the pairs whose key is between `start` and `end`, use `options.limit`
+    /// to bound them.
+    pub async fn scan(&mut self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let id = self.send(helper::Request::Scan { start, end, options }).await?;
+        let mut pairs = Vec::new();
+        loop {
+            match self.response(id).await? {
+                Response::Item(key, value) => pairs.push((key, value)),
+                Response::Done => return Ok(pairs),
+                response => return response.unexpected(),
+            }
+        }
+    }
+
+    /// Returns the pairs whose key starts with `prefix`.
+    pub async fn scan_prefix(&mut self, prefix: Vec<u8>, options: ScanOptions) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let (start, end) = crate::engine::prefix_range(prefix);
+        self.scan(start, end, options).await
+    }
+
+    /// Streams the changes of the keys starting with `key_or_prefix`, see `KvsClient::watch`.
+    pub async fn watch(mut self, key_or_prefix: Vec<u8>) -> Result<AsyncWatchStream> {
+        let id = self.send(helper::Request::Watch { key_or_prefix }).await?;
+        match self.response(id).await? {
+            Response::Subscribed => Ok(AsyncWatchStream { client: self, id }),
+            response => response.unexpected(),
+        }
+    }
+
+    async fn request_done(&mut self, req: helper::Request) -> Result<()> {
+        match self.request(req).await? {
+            Response::Done => Ok(()),
+            response => response.unexpected(),
+        }
+    }
+
+    async fn request(&mut self, req: helper::Request) -> Result<Response> {
+        let id = self.send(req).await?;
+        self.response(id).await
+    }
+
+    // Sends the request, returns its id.
+    async fn send(&mut self, req: helper::Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.writer.write_all(&Frame::request(id, &req)?.to_bytes()).await?;
+        Ok(id)
+    }
+
+    async fn response(&mut self, id: u64) -> Result<Response> {
+        if self.hello_pending {
+            self.hello_pending = false;
+            match self.read_frame(HELLO_ID).await?.to_hello_reply()? {
+                Response::Hello { .. } => {}
+                response => return response.unexpected(),
+            }
+        }
+        self.read_response(id).await
+    }
+
+    async fn read_response(&mut self, id: u64) -> Result<Response> {
+        self.read_frame(id).await?.to_response()
+    }
+
+    async fn read_frame(&mut self, id: u64) -> Result<Frame> {
+        match self.reader.next_frame().await? {
+            Incoming::Frame(frame) if frame.id == id => Ok(frame),
+            Incoming::Frame(frame) => Err(KvStoreError::Protocol(format!("response to request {} while waiting for {}", frame.id, id))),
+            Incoming::Unknown { opcode, .. } => Err(KvStoreError::Protocol(format!("unknown opcode {}", opcode))),
+            Incoming::Closed => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection").into()),
+        }
+    }
+}
+
+/// The changes of a watch, `next` waits for the next change.
+pub struct AsyncWatchStream {
+    client: AsyncKvsClient,
+    id: u64,
+}
+
+impl AsyncWatchStream {
+    /// Returns `None` once the server closed the connection.
+    pub async fn next(&mut self) -> Option<Result<WatchEvent>> {
+        match self.client.read_response(self.id).await {
+            Ok(Response::Event(event)) => Some(Ok(event)),
+            Ok(response) => Some(response.unexpected()),
+            Err(KvStoreError::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
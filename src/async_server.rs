@@ -0,0 +1,230 @@
+use super::helper::{self, AsyncReader, ReplyTo, Request, Response};
+use super::Result;
+use crate::engine::{AsyncKvsEngine, KvsEngine, Transaction};
+use crate::frame::{self, Frame};
+use crate::server::{accept_failed, respond, DRAIN_TIMEOUT, EXPIRY_SWEEP_INTERVAL};
+use crate::shutdown::ShutdownHandle;
+use crate::watch::{Watchers, WATCH_BACKLOG, WATCH_LAGGED};
+use crate::KvStoreError;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::time;
+
+/// Serves the same protocol as `KvsServer` from tasks of a tokio runtime instead
+/// of a thread per connection, so idle connections cost no thread.
+///
+/// The requests run on the blocking threads of tokio through `AsyncKvsEngine`.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: AsyncKvsEngine<E>,
+    watchers: Arc<Watchers>,
+    shutdown: ShutdownHandle,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    pub fn new(engine: E) -> Result<Self> {
+        Ok(AsyncKvsServer {
+            engine: AsyncKvsEngine::new(engine),
+            watchers: Arc::new(Watchers::default()),
+            shutdown: ShutdownHandle::default(),
+        })
+    }
+
+    /// Returns a handle that stops `run`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves the clients connecting to `addr` until the server is shut down, it
+    /// must be polled from a tokio runtime.
+    ///
+    /// On shutdown the server stops accepting connections, lets the requests being
+    /// served finish for up to `DRAIN_TIMEOUT`, and flushes the engine to disk.
+    pub async fn run(self, addr: SocketAddr) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        self.shutdown.listening(listener.local_addr()?);
+        tokio::spawn(expiry_sweep(self.engine.clone(), self.shutdown.clone()));
+        // connections stop reading requests once `closing` is set, and hold `open`
+        // until they end.
+        let (closing, is_closing) = watch::channel(false);
+        let (open, mut closed) = mpsc::channel::<()>(1);
+        let mut backoff = Duration::ZERO;
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    backoff = accept_failed(&err, backoff);
+                    time::sleep(backoff).await;
+                    continue;
+                }
+            };
+            backoff = Duration::ZERO;
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let engine = self.engine.clone();
+            let watchers = Arc::clone(&self.watchers);
+            let is_closing = is_closing.clone();
+            let open = open.clone();
+            tokio::spawn(async move {
+                if let Err(err) = handle_connection(engine, watchers, stream, is_closing).await {
+                    eprintln!("Connection failed: {:?}", err);
+                }
+                drop(open);
+            });
+        }
+        drop(listener);
+        let _ = closing.send(true);
+        drop(open);
+        if time::timeout(DRAIN_TIMEOUT, closed.recv()).await.is_err() {
+            eprintln!("Connections still open after {:?}, shutting down anyway", DRAIN_TIMEOUT);
+        }
+        self.engine.flush().await
+    }
+}
+
+// Expired keys are hidden from reads as soon as they expire, the sweep reclaims
+// the space of the keys nobody reads anymore.
+async fn expiry_sweep<E: KvsEngine>(engine: AsyncKvsEngine<E>, shutdown: ShutdownHandle) {
+    loop {
+        time::sleep(EXPIRY_SWEEP_INTERVAL).await;
+        if shutdown.is_shutdown() {
+            return;
+        }
+        if let Err(err) = engine.remove_expired().await {
+            eprintln!("Expiry sweep failed: {:?}", err);
+        }
+    }
+}
+
+async fn handle_connection<E: KvsEngine>(
+    engine: AsyncKvsEngine<E>,
+    watchers: Arc<Watchers>,
+    stream: TcpStream,
+    mut is_closing: watch::Receiver<bool>,
+) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = AsyncReader::new(reader);
+    let legacy = match reader.peek().await? {
+        Some(byte) => frame::is_legacy(byte),
+        None => return Ok(()),
+    };
+    if !legacy {
+        let (id, response) = match reader.next_frame().await?.into_hello_reply() {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        let accepted = matches!(response, Response::Hello { .. });
+        writer.write_all(&Frame::hello_reply(id, &response)?.to_bytes()).await?;
+        if !accepted {
+            return Ok(());
+        }
+    }
+    let mut transaction: Option<Transaction<E>> = None;
+    loop {
+        let (to, req) = tokio::select! {
+            req = next_request(&mut reader, legacy) => match req {
+                Ok(Some(req)) => req,
+                Ok(None) => return Ok(()),
+                Err(KvStoreError::SerdeIo(err)) if legacy => {
+                    let mut response = Vec::new();
+                    helper::write_invalid_request(&mut response, &err)?;
+                    return Ok(writer.write_all(&response).await?);
+                }
+                Err(err) => return Err(err),
+            },
+            // the request being served still gets its response.
+            _ = is_closing.changed() => return Ok(()),
+        };
+        let response = match req {
+            // a request this server does not understand does not end the connection.
+            Err(response) => encode(to, response)?,
+            Ok(Request::Stats) => encode(to, Response::Err("The async server has no thread pool".to_owned()))?,
+            // the connection only streams events from here on.
+            Ok(Request::Watch { key_or_prefix }) => {
+                return watch(writer, reader, watchers, key_or_prefix, to, is_closing).await;
+            }
+            Ok(req) => {
+                let watchers = Arc::clone(&watchers);
+                // the transaction moves to the blocking thread with the request.
+                let (txn, response) = engine
+                    .run(move |engine| {
+                        let mut response = Vec::new();
+                        let result = respond(&engine, &watchers, &mut transaction, req, to, &mut response);
+                        (transaction, result.map(|()| response))
+                    })
+                    .await;
+                transaction = txn;
+                response?
+            }
+        };
+        writer.write_all(&response).await?;
+    }
+}
+
+// Reads the next request in the protocol of the connection, with where to send its
+// response. `None` once the client closed the connection.
+async fn next_request(
+    reader: &mut AsyncReader<OwnedReadHalf>,
+    legacy: bool,
+) -> Result<Option<(ReplyTo, std::result::Result<Request, Response>)>> {
+    if legacy {
+        let req = reader.next_json::<Request>().await?;
+        Ok(req.map(|req| (ReplyTo::Json(req.opcode()), Ok(req))))
+    } else {
+        let req = reader.next_frame().await?.into_request();
+        Ok(req.map(|(id, req)| (ReplyTo::Frame(id), req)))
+    }
+}
+
+fn encode(to: ReplyTo, response: Response) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    to.write(&mut bytes, response)?;
+    Ok(bytes)
+}
+
+// Streams the changes of the keys starting with `prefix` until the client closes
+// the connection or the server shuts down.
+async fn watch(
+    writer: OwnedWriteHalf,
+    reader: AsyncReader<OwnedReadHalf>,
+    watchers: Arc<Watchers>,
+    prefix: Vec<u8>,
+    to: ReplyTo,
+    is_closing: watch::Receiver<bool>,
+) -> Result<()> {
+    let (sender, events) = mpsc::channel(WATCH_BACKLOG);
+    let id = watchers.subscribe_with(prefix, move |event| sender.try_send(event).is_ok());
+    let result = stream_events(writer, reader, events, to, is_closing).await;
+    watchers.unsubscribe(id);
+    result
+}
+
+async fn stream_events(
+    mut writer: OwnedWriteHalf,
+    mut reader: AsyncReader<OwnedReadHalf>,
+    mut events: mpsc::Receiver<crate::WatchEvent>,
+    to: ReplyTo,
+    mut is_closing: watch::Receiver<bool>,
+) -> Result<()> {
+    writer.write_all(&encode(to, Response::Subscribed)?).await?;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => writer.write_all(&encode(to, Response::Event(event))?).await?,
+                // the subscriber was dropped for falling behind.
+                None => {
+                    writer.write_all(&encode(to, Response::Err(WATCH_LAGGED.to_owned()))?).await?;
+                    return Ok(());
+                }
+            },
+            // the client sends nothing after a watch, reading only tells when it leaves.
+            closed = reader.closed() => return closed,
+            _ = is_closing.changed() => return Ok(()),
+        }
+    }
+}
@@ -1,8 +1,11 @@
 extern crate clap;
 use clap::{App, Arg, SubCommand};
 use kvs::KvStoreError;
-use kvs::{KvsClient, Result};
+use kvs::{KvsClient, Reply, Result, ScanOptions};
+use std::io::{BufRead, Write};
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::time::Duration;
 use std::process::exit;
 
 fn main() -> Result<()> {
@@ -23,6 +26,7 @@ fn main() -> Result<()> {
                 .about("set a value")
                 .arg(Arg::with_name("KEY").help("A string key").required(true))
                 .arg(Arg::with_name("VALUE").help("value").required(true))
+                .arg(Arg::from_usage("--ttl [SECONDS] the key expires after SECONDS"))
                 .arg(
                     Arg::from_usage("--addr [IP-PORT] Optionally accepts an IP address, with the format IP:PORT")
                         .help("accepts an IP address with port")
@@ -39,6 +43,70 @@ fn main() -> Result<()> {
                         .default_value("127.0.0.1:4000")
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("ttl")
+                .about("time left before a key expires, in seconds")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(
+                    Arg::from_usage("--addr [IP-PORT] Optionally accepts an IP address, with the format IP:PORT")
+                        .help("accepts an IP address with port")
+                        .default_value("127.0.0.1:4000")
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("cas")
+                .about("set a value only if the current one matches")
+                .arg(Arg::with_name("KEY").help("A string key").required(true))
+                .arg(Arg::from_usage("--expected [VALUE] current value, the key must not exist when omitted"))
+                .arg(Arg::from_usage("--new [VALUE] value to set, the key is removed when omitted"))
+                .arg(
+                    Arg::from_usage("--addr [IP-PORT] Optionally accepts an IP address, with the format IP:PORT")
+                        .help("accepts an IP address with port")
+                        .default_value("127.0.0.1:4000")
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("print the changes of the keys starting with KEY until interrupted")
+                .arg(Arg::with_name("KEY").help("A string key or prefix").required(true))
+                .arg(
+                    Arg::from_usage("--addr [IP-PORT] Optionally accepts an IP address, with the format IP:PORT")
+                        .help("accepts an IP address with port")
+                        .default_value("127.0.0.1:4000")
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+                .about("list the pairs in a key range, ordered by key")
+                .arg(Arg::from_usage("--start [KEY] first key of the range"))
+                .arg(Arg::from_usage("--end [KEY] key ending the range, not included"))
+                .arg(Arg::from_usage("--prefix [PREFIX] only the keys starting with PREFIX").conflicts_with_all(&["start", "end"]))
+                .arg(Arg::from_usage("--limit [N] maximum number of pairs"))
+                .arg(Arg::from_usage("--reverse 'list the pairs from the last key'"))
+                .arg(
+                    Arg::from_usage("--addr [IP-PORT] Optionally accepts an IP address, with the format IP:PORT")
+                        .help("accepts an IP address with port")
+                        .default_value("127.0.0.1:4000")
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stats")
+                .about("show the load of the thread pool of the server")
+                .arg(
+                    Arg::from_usage("--addr [IP-PORT] Optionally accepts an IP address, with the format IP:PORT")
+                        .help("accepts an IP address with port")
+                        .default_value("127.0.0.1:4000")
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("bulk")
+                .about("run the commands read from stdin, one per line: get KEY, set KEY VALUE or rm KEY")
+                .arg(
+                    Arg::from_usage("--addr [IP-PORT] Optionally accepts an IP address, with the format IP:PORT")
+                        .help("accepts an IP address with port")
+                        .default_value("127.0.0.1:4000")
+                ),
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -46,9 +114,14 @@ fn main() -> Result<()> {
             let key = _matches.value_of("KEY").expect("KEY argument missing");
             let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
             let mut client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
-            let value_o = client.get(key.to_owned())?;
+            let value_o = client.get_bytes(key.as_bytes().to_vec())?;
             match value_o {
-                Some(v) => println!("{}", v),
+                Some(v) => {
+                    // values are printed as they are stored, they may not be UTF-8.
+                    let mut stdout = std::io::stdout();
+                    stdout.write_all(&v)?;
+                    stdout.write_all(b"\n")?;
+                }
                 None => println!("Key not found"),
             }
         }
@@ -56,8 +129,33 @@ fn main() -> Result<()> {
             let key = _matches.value_of("KEY").expect("KEY argument missing");
             let value = _matches.value_of("VALUE").expect("VALUE argument missing");
             let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let ttl = match _matches.value_of("ttl") {
+                Some(ttl) => match ttl.parse::<u64>() {
+                    Ok(seconds) => Some(Duration::from_secs(seconds)),
+                    Err(_) => {
+                        eprintln!("invalid ttl: {}", ttl);
+                        exit(1);
+                    }
+                },
+                None => None,
+            };
             let mut client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
-            client.set(key.to_owned(), value.to_owned())?;
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key.as_bytes().to_vec(), value.as_bytes().to_vec(), ttl)?,
+                None => client.set(key.to_owned(), value.to_owned())?,
+            }
+        }
+        ("ttl", Some(_matches)) => {
+            let key = _matches.value_of("KEY").expect("KEY argument missing");
+            let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
+            match client.ttl(key.as_bytes().to_vec()) {
+                // rounded up so a key that still exists never shows 0.
+                Ok(Some(ttl)) => println!("{}", ttl.as_millis().div_ceil(1000)),
+                Ok(None) => println!("No expiry"),
+                Err(KvStoreError::KeyNotFound) => println!("Key not found"),
+                Err(e) => return Err(e),
+            }
         }
         ("rm", Some(_matches)) => {
             let key = _matches.value_of("KEY").expect("KEY argument missing");
@@ -66,7 +164,7 @@ fn main() -> Result<()> {
             match client.rm(key.to_owned()) {
                 Ok(()) => {}
                 Err(KvStoreError::KeyNotFound) => {
-                    println!("Key not found");
+                    eprintln!("Key not found");
                     exit(1);
                 }
                 Err(e) => {
@@ -75,6 +173,129 @@ fn main() -> Result<()> {
                 }
             }
         }
+        ("cas", Some(_matches)) => {
+            let key = _matches.value_of("KEY").expect("KEY argument missing");
+            let expected = _matches.value_of("expected").map(|value| value.as_bytes().to_vec());
+            let new = _matches.value_of("new").map(|value| value.as_bytes().to_vec());
+            let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
+            if !client.compare_and_swap(key.as_bytes().to_vec(), expected, new)? {
+                println!("Value mismatch");
+                exit(1);
+            }
+        }
+        ("watch", Some(_matches)) => {
+            let key = _matches.value_of("KEY").expect("KEY argument missing");
+            let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
+            let mut stdout = std::io::stdout();
+            // one line per change: the sequence number, set or rm, the key and the new value.
+            for event in client.watch(key.as_bytes().to_vec())? {
+                let event = event?;
+                match event.value {
+                    Some(value) => {
+                        write!(stdout, "{}\tset\t", event.seq)?;
+                        stdout.write_all(&event.key)?;
+                        stdout.write_all(b"\t")?;
+                        stdout.write_all(&value)?;
+                    }
+                    None => {
+                        write!(stdout, "{}\trm\t", event.seq)?;
+                        stdout.write_all(&event.key)?;
+                    }
+                }
+                stdout.write_all(b"\n")?;
+                stdout.flush()?;
+            }
+        }
+        ("scan", Some(_matches)) => {
+            let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let limit = match _matches.value_of("limit") {
+                Some(limit) => match limit.parse::<usize>() {
+                    Ok(limit) => Some(limit),
+                    Err(_) => {
+                        eprintln!("invalid limit: {}", limit);
+                        exit(1);
+                    }
+                },
+                None => None,
+            };
+            let options = ScanOptions { limit, reverse: _matches.is_present("reverse") };
+            let bound = |name| _matches.value_of(name).map(|key| key.as_bytes().to_vec());
+            let mut client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
+            let pairs = match bound("prefix") {
+                Some(prefix) => client.scan_prefix(prefix, options)?,
+                None => client.scan(
+                    bound("start").map_or(Bound::Unbounded, Bound::Included),
+                    bound("end").map_or(Bound::Unbounded, Bound::Excluded),
+                    options,
+                )?,
+            };
+            let mut stdout = std::io::stdout();
+            for pair in pairs {
+                let (key, value) = pair?;
+                stdout.write_all(&key)?;
+                stdout.write_all(b"\t")?;
+                stdout.write_all(&value)?;
+                stdout.write_all(b"\n")?;
+            }
+        }
+        ("stats", Some(_matches)) => {
+            let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
+            let stats = client.stats()?;
+            println!("active\t{}", stats.active);
+            println!("idle\t{}", stats.idle);
+            println!("queued\t{}", stats.queued);
+            println!("completed\t{}", stats.completed);
+            println!("panics\t{}", stats.panics);
+            println!("average_latency_us\t{}", stats.average_latency.as_micros());
+        }
+        ("bulk", Some(_matches)) => {
+            let addr = _matches.value_of("addr").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(addr.parse::<SocketAddr>()?)?;
+            let mut pipeline = client.pipeline();
+            let mut failed = false;
+            // the value of a set is the rest of the line, spaces included.
+            for (number, line) in std::io::stdin().lock().lines().enumerate() {
+                let line = line?;
+                let mut words = line.splitn(3, ' ');
+                match (words.next(), words.next(), words.next()) {
+                    (Some("get"), Some(key), None) => pipeline.get(key.as_bytes().to_vec())?,
+                    (Some("set"), Some(key), Some(value)) => pipeline.set(key.as_bytes().to_vec(), value.as_bytes().to_vec())?,
+                    (Some("rm"), Some(key), None) => pipeline.rm(key.as_bytes().to_vec())?,
+                    (Some(""), None, None) => {}
+                    _ => {
+                        eprintln!("invalid command on line {}: {}", number + 1, line);
+                        failed = true;
+                    }
+                }
+            }
+            // one line per get, in the order of the commands.
+            let mut stdout = std::io::stdout().lock();
+            for reply in pipeline.execute()? {
+                match reply {
+                    Ok(Reply::Value(Some(value))) => {
+                        stdout.write_all(&value)?;
+                        stdout.write_all(b"\n")?;
+                    }
+                    Ok(Reply::Value(None)) => writeln!(stdout, "Key not found")?,
+                    Ok(_) => {}
+                    Err(KvStoreError::KeyNotFound) => {
+                        eprintln!("Key not found");
+                        failed = true;
+                    }
+                    Err(e) => {
+                        eprintln!("error: {:?}", e);
+                        failed = true;
+                    }
+                }
+            }
+            stdout.flush()?;
+            if failed {
+                exit(1);
+            }
+        }
         _ => unreachable!(),
     }
     Ok(())
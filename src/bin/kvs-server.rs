@@ -3,17 +3,24 @@ use kvs::KvStoreError;
 use std::io::prelude::*;
 
 use clap::{App, Arg};
+use kvs::Durability;
 use kvs::KvStore;
+use kvs::KvsEngine;
+use kvs::NaiveThreadPool;
+use kvs::QueuePolicy;
 use kvs::RayonThreadPool;
 use kvs::SharedQueueThreadPool;
 use kvs::SledKvsEngine;
 use kvs::ThreadPool;
-use kvs::{KvsServer, Result};
+use kvs::WorkStealingThreadPool;
+use kvs::{KvsServer, Protocol, Result};
 use slog::{info, o, Drain};
 use std::env::current_dir;
 use std::fs::OpenOptions;
 use std::net::SocketAddr;
 
+const POOL_THREADS: usize = 4;
+
 fn main() -> Result<()> {
     let log_path = "stderr";
     let file = OpenOptions::new()
@@ -27,7 +34,7 @@ fn main() -> Result<()> {
     let drain = slog_async::Async::new(drain).build().fuse();
     let log = slog::Logger::root(drain, o!());
     info!(log, "CARGO_PKG_VERSION: {}", env!("CARGO_PKG_VERSION"));
-    let matches = App::new(env!("CARGO_PKG_NAME"))
+    let app = App::new(env!("CARGO_PKG_NAME"))
         .version(env!("CARGO_PKG_VERSION"))
         .arg(
             Arg::from_usage(
@@ -41,30 +48,116 @@ fn main() -> Result<()> {
                 .help("engine: kvs or sled")
                 .default_value("kvs"),
         )
-        .get_matches();
+        .arg(
+            Arg::from_usage("--durability [MODE] Optionally when writes are synced to disk")
+                .help("durability: always, group[:MILLIS] or none, defaults to the engine's mode"),
+        )
+        .arg(
+            Arg::from_usage("--pool [POOL] Optionally which thread pool serves the connections")
+                .help("pool: naive, shared, rayon or stealing, defaults to rayon for kvs and shared for sled")
+                .possible_values(&["naive", "shared", "rayon", "stealing"]),
+        )
+        .arg(
+            Arg::from_usage("--queue-capacity [JOBS] Optionally bounds the job queue of the shared pool")
+                .help("the connections waiting for a thread of the shared pool, unbounded by default")
+                .validator(|capacity| match capacity.parse::<usize>() {
+                    Ok(capacity) if capacity > 0 => Ok(()),
+                    _ => Err("the capacity must be a positive number".to_owned()),
+                }),
+        )
+        .arg(
+            Arg::from_usage("--queue-policy [POLICY] Optionally what a full queue does with a new connection")
+                .help("policy: block, reject or drop-oldest, defaults to reject")
+                .possible_values(&["block", "reject", "drop-oldest"])
+                .requires("queue-capacity"),
+        )
+        .arg(
+            Arg::from_usage("--protocol [PROTOCOL] Optionally which protocol the clients speak")
+                .help("protocol: kvs for kvs-client or resp for the Redis clients, defaults to kvs")
+                .possible_values(&["kvs", "resp"]),
+        )
+        .arg(
+            Arg::from_usage("--http-addr [IP-PORT] Optionally also serves the HTTP gateway on IP:PORT")
+                .help("accepts an IP address with port for the HTTP gateway"),
+        );
+    #[cfg(feature = "async")]
+    let app = app.arg(
+        Arg::from_usage("--async 'serves the connections from tokio tasks instead of a thread pool'")
+            .conflicts_with_all(&["pool", "queue-capacity", "protocol", "http-addr"]),
+    );
+    let matches = app.get_matches();
     let engine = matches.value_of("engine").unwrap_or("kvs");
     info!(log, "Engine: {}", engine);
     let addr = matches.value_of("addr").unwrap_or("127.0.0.1:4000");
     info!(log, "Addr: {}", addr);
-    start_server(engine.to_owned(), addr.to_owned())
+    let durability = matches
+        .value_of("durability")
+        .map(str::parse::<Durability>)
+        .transpose()?;
+    if let Some(durability) = durability {
+        info!(log, "Durability: {:?}", durability);
+    }
+    let mut pool = matches.value_of("pool").map(str::to_owned);
+    // the async server takes the place of the pool.
+    if matches.is_present("async") {
+        pool = Some("async".to_owned());
+    }
+    if let Some(pool) = &pool {
+        info!(log, "Pool: {}", pool);
+    }
+    let queue = match matches.value_of("queue-capacity") {
+        Some(capacity) => {
+            // only the shared pool has a queue to bound.
+            match pool.get_or_insert_with(|| "shared".to_owned()).as_str() {
+                "shared" => {}
+                other => clap::Error::with_description(
+                    &format!("--queue-capacity needs the shared pool, not {}", other),
+                    clap::ErrorKind::ArgumentConflict,
+                )
+                .exit(),
+            }
+            let policy = matches.value_of("queue-policy").unwrap_or("reject").parse::<QueuePolicy>()?;
+            info!(log, "Queue: {} jobs, {:?}", capacity, policy);
+            Some((capacity.parse::<usize>().unwrap(), policy))
+        }
+        None => None,
+    };
+    let protocol = matches.value_of("protocol").unwrap_or("kvs").parse::<Protocol>()?;
+    info!(log, "Protocol: {:?}", protocol);
+    let http_addr = matches.value_of("http-addr").map(str::parse::<SocketAddr>).transpose()?;
+    if let Some(http_addr) = http_addr {
+        info!(log, "HTTP addr: {}", http_addr);
+    }
+    start_server(engine.to_owned(), addr.to_owned(), durability, pool, queue, protocol, http_addr)
 }
 
-fn start_server(engine: String, addr: String) -> Result<()> {
+fn start_server(
+    engine: String,
+    addr: String,
+    durability: Option<Durability>,
+    pool: Option<String>,
+    queue: Option<(usize, QueuePolicy)>,
+    protocol: Protocol,
+    http_addr: Option<SocketAddr>,
+) -> Result<()> {
     let path = current_dir()?;
     let engine_check = check_engine(engine.to_owned())?;
+    let addr = addr.parse::<SocketAddr>()?;
     if engine_check {
         match engine.as_ref() {
             "kvs" => {
-                let store: KvStore = KvStore::open(path)?;
-                let thread_pool = RayonThreadPool::new(4).unwrap();
-                let server = KvsServer::new(store, thread_pool)?;
-                server.run(addr.parse::<SocketAddr>()?)
+                let store: KvStore = match durability {
+                    Some(durability) => KvStore::open_with_durability(path, durability)?,
+                    None => KvStore::open(path)?,
+                };
+                run_with_pool(store, pool.as_deref().unwrap_or("rayon"), queue, addr, protocol, http_addr)
             }
             "sled" => {
-                let store = SledKvsEngine::open(path)?;
-                let thread_pool = SharedQueueThreadPool::new(4).unwrap();
-                let server = KvsServer::new(store, thread_pool)?;
-                server.run(addr.parse::<SocketAddr>()?)
+                let store = match durability {
+                    Some(durability) => SledKvsEngine::open_with_durability(path, durability)?,
+                    None => SledKvsEngine::open(path)?,
+                };
+                run_with_pool(store, pool.as_deref().unwrap_or("shared"), queue, addr, protocol, http_addr)
             }
             _ => unreachable!(),
         }
@@ -72,12 +165,69 @@ fn start_server(engine: String, addr: String) -> Result<()> {
         Err(KvStoreError::EngineError)
     }
 }
+
+fn run_with_pool<E: KvsEngine>(
+    engine: E,
+    pool: &str,
+    queue: Option<(usize, QueuePolicy)>,
+    addr: SocketAddr,
+    protocol: Protocol,
+    http_addr: Option<SocketAddr>,
+) -> Result<()> {
+    match pool {
+        "naive" => run(engine, NaiveThreadPool::new(POOL_THREADS)?, addr, protocol, http_addr),
+        "shared" => {
+            let pool = match queue {
+                Some((capacity, policy)) => SharedQueueThreadPool::with_queue(POOL_THREADS, capacity, policy)?,
+                None => SharedQueueThreadPool::new(POOL_THREADS)?,
+            };
+            run(engine, pool, addr, protocol, http_addr)
+        }
+        "rayon" => run(engine, RayonThreadPool::new(POOL_THREADS)?, addr, protocol, http_addr),
+        "stealing" => run(engine, WorkStealingThreadPool::new(POOL_THREADS)?, addr, protocol, http_addr),
+        #[cfg(feature = "async")]
+        "async" => run_async(engine, addr),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(feature = "async")]
+fn run_async<E: KvsEngine>(engine: E, addr: SocketAddr) -> Result<()> {
+    let server = kvs::AsyncKvsServer::new(engine)?;
+    let handle = server.shutdown_handle();
+    ctrlc::set_handler(move || handle.shutdown())?;
+    tokio::runtime::Runtime::new()?.block_on(server.run(addr))
+}
+
+fn run<E: KvsEngine, T: ThreadPool + Send + Sync + 'static>(
+    engine: E,
+    thread_pool: T,
+    addr: SocketAddr,
+    protocol: Protocol,
+    http_addr: Option<SocketAddr>,
+) -> Result<()> {
+    let server = KvsServer::with_protocol(engine, thread_pool, protocol)?;
+    shutdown_on_signal(&server)?;
+    match http_addr {
+        Some(http_addr) => server.run_with_http(addr, http_addr),
+        None => server.run(addr),
+    }
+}
+
+// SIGINT and SIGTERM stop the server gracefully, `run` returns once it is done.
+fn shutdown_on_signal<E: KvsEngine, T: ThreadPool + Send + Sync + 'static>(server: &KvsServer<E, T>) -> Result<()> {
+    let handle = server.shutdown_handle();
+    ctrlc::set_handler(move || handle.shutdown())?;
+    Ok(())
+}
+
 fn check_engine(engine: String) -> Result<bool> {
     let path = current_dir()?;
     let file = path.join("config.log");
     let mut config = OpenOptions::new()
         .read(true)
         .create(true)
+        .truncate(false)
         .write(true)
         .open(&file)?;
     let size = config.metadata()?.len();
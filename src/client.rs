@@ -1,62 +1,374 @@
 use std::net::TcpStream;
 use super::Result;
-use serde_json::de::{Deserializer, IoRead};
-use serde::Deserialize;
-use super::helper;
-use std::io::{BufWriter, BufReader, Write};
+use super::helper::{self, Response};
+use std::collections::VecDeque;
+use std::io::{self, BufWriter, BufReader, Write};
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::time::Duration;
+use crate::engine::{ScanOptions, WriteBatch};
+use crate::frame::{Frame, Incoming};
+use crate::thread_pool::PoolStats;
+use crate::watch::WatchEvent;
+use crate::KvStoreError;
 
+// the id of the hello, requests are numbered from 1.
+const HELLO_ID: u64 = 0;
+// the requests a pipeline sends before it reads the oldest response. Neither side
+// reads while its writes are blocked, so an unbounded pipeline could fill both socket
+// buffers and stall forever.
+const PIPELINE_WINDOW: usize = 1024;
+
+/// Talks the framed protocol, see the `frame` module.
 pub struct KvsClient{
-    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    reader: BufReader<TcpStream>,
     writer: BufWriter<TcpStream>,
-    reader2: BufReader<TcpStream>,
+    next_id: u64,
+    // the answer to the hello is read with the first response, so connecting does not
+    // wait for the server.
+    hello_pending: bool,
 }
 
 
 impl KvsClient {
     pub fn connect(addr: SocketAddr) -> Result<Self> {
         let reader = TcpStream::connect(addr)?;
-        let reader2 = reader.try_clone()?;
         let writer = reader.try_clone()?;
-        Ok(KvsClient{
-            reader: Deserializer::from_reader(BufReader::new(reader)),
+        let mut client = KvsClient{
+            reader: BufReader::new(reader),
             writer: BufWriter::new(writer),
-            reader2: BufReader::new(reader2),
-        })
+            next_id: HELLO_ID + 1,
+            hello_pending: true,
+        };
+        Frame::hello(HELLO_ID)?.write_to(&mut client.writer)?;
+        client.writer.flush()?;
+        Ok(client)
     }
 
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let mut cmd = helper::Request::Get(key);
-        serde_json::to_writer(&mut self.writer, &mut cmd)?;
-        self.writer.flush()?;
-        let mut result2 = Deserializer::from_reader(&mut self.reader2); // this can be abstracted on the struct definition
-        let result3 = helper::GetResponse::deserialize(&mut result2)?;
-        match result3 {
-            helper::GetResponse::Ok(Some(value)) => Ok(Some(value)),
-            helper::GetResponse::Ok(None) => Ok(None),
-            helper::GetResponse::Err(err) => Err(crate::KvStoreError::ServerResponseErr(err))
-        }
+        Ok(self.get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
     }
 
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let mut cmd = helper::Request::Set{key, value};
-        serde_json::ser::to_writer(&mut self.writer, &mut cmd)?;
-        self.writer.flush()?;
-        let result = helper::SetResponse::deserialize(&mut self.reader)?;
-        match result {
-            helper::SetResponse::Ok(_) => Ok(()),
-            helper::SetResponse::Err(err) => Err(crate::KvStoreError::ServerResponseErr(err))
-        }
+        self.set_bytes(key.into_bytes(), value.into_bytes())
     }
 
     pub fn rm(&mut self, key: String) -> Result<()> {
-        let mut cmd = helper::Request::Rm(key);
-        serde_json::ser::to_writer(&mut self.writer, &mut cmd)?;
+        self.rm_bytes(key.into_bytes())
+    }
+
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.request(helper::Request::Get(key))? {
+            Response::Value(value) => Ok(value),
+            response => response.unexpected(),
+        }
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.request_done(helper::Request::Set{key, value})
+    }
+
+    /// Sets the value of the key, the key expires once `ttl` elapsed.
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.request_done(helper::Request::SetWithTtl{key, value, ttl})
+    }
+
+    /// Returns the time left before the key expires, `None` if it never does.
+    pub fn ttl(&mut self, key: Vec<u8>) -> Result<Option<Duration>> {
+        match self.request(helper::Request::Ttl(key))? {
+            Response::Ttl(ttl) => Ok(ttl),
+            response => response.unexpected(),
+        }
+    }
+
+    /// Fails with `KvStoreError::KeyNotFound` when the key does not exist.
+    pub fn rm_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.request_done(helper::Request::Rm(key))
+    }
+
+    /// Applies every write of the batch atomically.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.request_done(helper::Request::Batch(batch))
+    }
+
+    /// Swaps the value of the key only if it is `expected`, returns whether it did.
+    pub fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        match self.request(helper::Request::Cas{key, expected, new})? {
+            Response::Swapped(swapped) => Ok(swapped),
+            response => response.unexpected(),
+        }
+    }
+
+    /// Returns the stats of the thread pool of the server.
+    pub fn stats(&mut self) -> Result<PoolStats> {
+        match self.request(helper::Request::Stats)? {
+            Response::Stats(stats) => Ok(stats),
+            response => response.unexpected(),
+        }
+    }
+
+    /// Starts a transaction, the `get`, `set` and `rm` calls that follow are part of
+    /// it until `commit` or `abort`.
+    pub fn begin(&mut self) -> Result<()> {
+        self.request_done(helper::Request::Begin)
+    }
+
+    /// Commits the open transaction, fails with `KvStoreError::TransactionConflict`
+    /// when a key it read was written by someone else in the meantime.
+    pub fn commit(&mut self) -> Result<()> {
+        self.request_done(helper::Request::Commit)
+    }
+
+    /// Drops the writes of the open transaction.
+    pub fn abort(&mut self) -> Result<()> {
+        self.request_done(helper::Request::Abort)
+    }
+
+    /// Streams the changes of the keys starting with `key_or_prefix`, a key alone
+    /// also matches the longer keys starting with it.
+    ///
+    /// The connection is dedicated to the watch, so the client is consumed. The
+    /// changes made after this returns are all part of the stream.
+    pub fn watch(mut self, key_or_prefix: Vec<u8>) -> Result<WatchStream> {
+        let id = self.send(helper::Request::Watch{key_or_prefix})?;
+        match self.response(id)? {
+            Response::Subscribed => Ok(WatchStream{client: self, id}),
+            response => response.unexpected(),
+        }
+    }
+
+    /// Returns the pairs whose key is between `start` and `end`, the pairs are
+    /// read from the connection while iterating.
+    pub fn scan(&mut self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, options: ScanOptions) -> Result<ScanStream<'_>> {
+        let id = self.send(helper::Request::Scan{start, end, options})?;
+        Ok(ScanStream{client: self, id, done: false})
+    }
+
+    /// Returns the pairs whose key starts with `prefix`.
+    pub fn scan_prefix(&mut self, prefix: Vec<u8>, options: ScanOptions) -> Result<ScanStream<'_>> {
+        let (start, end) = crate::engine::prefix_range(prefix);
+        self.scan(start, end, options)
+    }
+
+    /// Returns a pipeline, which sends its requests without waiting for the previous
+    /// responses so a bulk load does not pay a round trip per key.
+    pub fn pipeline(&mut self) -> Pipeline<'_> {
+        Pipeline{client: self, pending: VecDeque::new(), replies: Vec::new()}
+    }
+
+    fn request_done(&mut self, req: helper::Request) -> Result<()> {
+        match self.request(req)? {
+            Response::Done => Ok(()),
+            response => response.unexpected(),
+        }
+    }
+
+    fn request(&mut self, req: helper::Request) -> Result<Response> {
+        let id = self.send(req)?;
+        self.response(id)
+    }
+
+    // Sends the request, returns its id.
+    fn send(&mut self, req: helper::Request) -> Result<u64> {
+        let id = self.queue(req)?;
         self.writer.flush()?;
-        let result:helper::RmResponse = helper::RmResponse::deserialize(&mut self.reader)?;
-        match result {
-            helper::RmResponse::Ok(()) => Ok(()),
-            helper::RmResponse::Err(err) => Err(crate::KvStoreError::ServerResponseErr(err))
+        Ok(id)
+    }
+
+    // Buffers the request without flushing it, returns its id.
+    fn queue(&mut self, req: helper::Request) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+        Frame::request(id, &req)?.write_to(&mut self.writer)?;
+        Ok(id)
+    }
+
+    // Reads the response to the request `id`, the responses come in the order of the requests.
+    fn response(&mut self, id: u64) -> Result<Response> {
+        if self.hello_pending {
+            self.hello_pending = false;
+            match self.read_frame(HELLO_ID)?.to_hello_reply()? {
+                Response::Hello{..} => {}
+                response => return response.unexpected(),
+            }
+        }
+        self.read_response(id)
+    }
+
+    fn read_response(&mut self, id: u64) -> Result<Response> {
+        self.read_frame(id)?.to_response()
+    }
+
+    fn read_frame(&mut self, id: u64) -> Result<Frame> {
+        match Frame::read_from(&mut self.reader)? {
+            Incoming::Frame(frame) if frame.id == id => Ok(frame),
+            Incoming::Frame(frame) => Err(KvStoreError::Protocol(format!("response to request {} while waiting for {}", frame.id, id))),
+            Incoming::Unknown{opcode, ..} => Err(KvStoreError::Protocol(format!("unknown opcode {}", opcode))),
+            Incoming::Closed => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the server closed the connection").into()),
+        }
+    }
+}
+
+/// The changes of a watch, each call to `next` blocks until the next change.
+pub struct WatchStream{
+    client: KvsClient,
+    id: u64,
+}
+
+impl Iterator for WatchStream {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.read_response(self.id) {
+            Ok(Response::Event(event)) => Some(Ok(event)),
+            Ok(response) => Some(response.unexpected()),
+            // the server closed the connection.
+            Err(KvStoreError::Io(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+/// The pairs of a scan, as they are streamed by the server.
+///
+/// Dropping the stream before its end reads the remaining pairs so the
+/// connection can be used for the next request.
+pub struct ScanStream<'a>{
+    client: &'a mut KvsClient,
+    id: u64,
+    done: bool,
+}
+
+impl Iterator for ScanStream<'_> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        match self.client.response(self.id) {
+            Ok(Response::Item(key, value)) => Some(Ok((key, value))),
+            Ok(Response::Done) => {
+                self.done = true;
+                None
+            }
+            Ok(response) => {
+                self.done = true;
+                Some(response.unexpected())
+            }
+            Err(err) => {
+                self.done = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+impl Drop for ScanStream<'_> {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+    }
+}
+
+/// What a request of a `Pipeline` returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply{
+    /// a `set`, `set_with_ttl` or `rm` went through.
+    Done,
+    /// the value read by a `get`.
+    Value(Option<Vec<u8>>),
+    /// whether a `compare_and_swap` swapped the value.
+    Swapped(bool),
+    /// the time left before the key of a `ttl` expires.
+    Ttl(Option<Duration>),
+}
+
+/// Requests sent back to back on the connection of a client, see `KvsClient::pipeline`.
+///
+/// The server answers the requests in order, each response carries the id of its
+/// request. Dropping the pipeline without `execute` still reads the responses so the
+/// client can be used for the next request.
+pub struct Pipeline<'a>{
+    client: &'a mut KvsClient,
+    // the ids of the requests whose response was not read yet, oldest first.
+    pending: VecDeque<u64>,
+    replies: Vec<Result<Reply>>,
+}
+
+impl Pipeline<'_> {
+    pub fn get(&mut self, key: Vec<u8>) -> Result<()> {
+        self.push(helper::Request::Get(key))
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.push(helper::Request::Set{key, value})
+    }
+
+    pub fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.push(helper::Request::SetWithTtl{key, value, ttl})
+    }
+
+    pub fn ttl(&mut self, key: Vec<u8>) -> Result<()> {
+        self.push(helper::Request::Ttl(key))
+    }
+
+    pub fn rm(&mut self, key: Vec<u8>) -> Result<()> {
+        self.push(helper::Request::Rm(key))
+    }
+
+    pub fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        self.push(helper::Request::Cas{key, expected, new})
+    }
+
+    /// Sends the requests still buffered and returns a reply per request, in the order
+    /// of the requests. A request that failed on the server, like the `rm` of a missing
+    /// key, has an error as reply; the error of the connection is returned instead.
+    pub fn execute(mut self) -> Result<Vec<Result<Reply>>> {
+        self.client.writer.flush()?;
+        while !self.pending.is_empty() {
+            self.read_oldest()?;
+        }
+        Ok(std::mem::take(&mut self.replies))
+    }
+
+    fn push(&mut self, req: helper::Request) -> Result<()> {
+        if self.pending.len() == PIPELINE_WINDOW {
+            self.client.writer.flush()?;
+            self.read_oldest()?;
+        }
+        let id = self.client.queue(req)?;
+        self.pending.push_back(id);
+        Ok(())
+    }
+
+    fn read_oldest(&mut self) -> Result<()> {
+        let id = match self.pending.pop_front() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let reply = match self.client.response(id)? {
+            Response::Done => Ok(Reply::Done),
+            Response::Value(value) => Ok(Reply::Value(value)),
+            Response::Swapped(swapped) => Ok(Reply::Swapped(swapped)),
+            Response::Ttl(ttl) => Ok(Reply::Ttl(ttl)),
+            response => response.unexpected(),
+        };
+        self.replies.push(reply);
+        Ok(())
+    }
+}
+
+impl Drop for Pipeline<'_> {
+    fn drop(&mut self) {
+        if self.pending.is_empty() || self.client.writer.flush().is_err() {
+            return;
+        }
+        while !self.pending.is_empty() {
+            if self.read_oldest().is_err() {
+                return;
+            }
         }
     }
 }
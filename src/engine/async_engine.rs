@@ -0,0 +1,85 @@
+use crate::Result;
+use super::{KvsEngine, ScanOptions, WriteBatch};
+use std::future::Future;
+use std::ops::Bound;
+use std::panic;
+use std::time::Duration;
+
+/// Runs the calls of a `KvsEngine` on the blocking threads of tokio, so the disk
+/// I/O of the engine does not stall the tasks of the runtime.
+///
+/// Clones share the same engine, like the clones of the engine itself.
+#[derive(Clone)]
+pub struct AsyncKvsEngine<E: KvsEngine> {
+    engine: E,
+}
+
+impl<E: KvsEngine> AsyncKvsEngine<E> {
+    pub fn new(engine: E) -> AsyncKvsEngine<E> {
+        AsyncKvsEngine { engine }
+    }
+
+    /// The wrapped engine, its calls block the current thread.
+    pub fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    /// Runs `f` with a clone of the engine on a blocking thread, a panic of `f` is
+    /// resumed in the caller.
+    ///
+    /// The future does not borrow `self`, engines are `Send` but not always `Sync`.
+    pub fn run<F, T>(&self, f: F) -> impl Future<Output = T> + Send + 'static
+    where
+        F: FnOnce(E) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let engine = self.engine.clone();
+        async move {
+            match tokio::task::spawn_blocking(move || f(engine)).await {
+                Ok(result) => result,
+                Err(err) => panic::resume_unwind(err.into_panic()),
+            }
+        }
+    }
+
+    pub fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.set_bytes(key, value))
+    }
+
+    pub fn get_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<Vec<u8>>>> + Send + 'static {
+        self.run(move |engine| engine.get_bytes(key))
+    }
+
+    pub fn remove_bytes(&self, key: Vec<u8>) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.remove_bytes(key))
+    }
+
+    pub fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.set_with_ttl(key, value, ttl))
+    }
+
+    pub fn ttl(&self, key: Vec<u8>) -> impl Future<Output = Result<Option<Duration>>> + Send + 'static {
+        self.run(move |engine| engine.ttl(key))
+    }
+
+    pub fn write_batch(&self, batch: WriteBatch) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(move |engine| engine.write_batch(batch))
+    }
+
+    pub fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> impl Future<Output = Result<bool>> + Send + 'static {
+        self.run(move |engine| engine.compare_and_swap(key, expected, new))
+    }
+
+    /// Collects the pairs of the scan, use `options.limit` to bound them.
+    pub fn scan(&self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, options: ScanOptions) -> impl Future<Output = Result<Vec<(Vec<u8>, Vec<u8>)>>> + Send + 'static {
+        self.run(move |engine| engine.scan((start, end), options)?.collect())
+    }
+
+    pub fn remove_expired(&self) -> impl Future<Output = Result<usize>> + Send + 'static {
+        self.run(|engine| engine.remove_expired())
+    }
+
+    pub fn flush(&self) -> impl Future<Output = Result<()>> + Send + 'static {
+        self.run(|engine| engine.flush())
+    }
+}
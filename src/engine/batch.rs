@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+
+/// Writes applied as one unit by `KvsEngine::write_batch`: after a crash either
+/// all of them are visible or none is.
+///
+/// The writes are applied in the order they were added, so a later write of a
+/// key wins over an earlier one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BatchOp {
+    Set { key: Vec<u8>, value: Vec<u8> },
+    /// removing a key that does not exist is not an error in a batch.
+    Remove(Vec<u8>),
+}
+
+impl WriteBatch {
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set { key, value });
+        self
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Remove(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
@@ -0,0 +1,94 @@
+use crate::KvStoreError;
+use crate::Result;
+use std::str::FromStr;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_GROUP_COMMIT_INTERVAL: Duration = Duration::from_millis(2);
+
+/// When a write is considered durable.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// Every write is synced to disk before it is acknowledged.
+    Always,
+    /// Writes are acknowledged once a sync covering them finished. Concurrent
+    /// writers wait up to `interval` and share a single sync.
+    GroupCommit { interval: Duration },
+    /// Writes are handed to the OS, which decides when they reach the disk.
+    None,
+}
+
+/// Parses `always`, `none`, `group` or `group:<millis>`.
+impl FromStr for Durability {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Durability> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "none" => Ok(Durability::None),
+            "group" => Ok(Durability::GroupCommit { interval: DEFAULT_GROUP_COMMIT_INTERVAL }),
+            _ => match s.strip_prefix("group:").map(str::parse::<u64>) {
+                Some(Ok(millis)) => Ok(Durability::GroupCommit { interval: Duration::from_millis(millis) }),
+                _ => Err(KvStoreError::InvalidDurability(s.to_owned())),
+            },
+        }
+    }
+}
+
+/// Batches the syncs of concurrent writers.
+///
+/// A writer registers its write with `written` once it reached the OS and then
+/// calls `wait`. The first waiter becomes the leader: it waits for the group
+/// interval so other writers can join, syncs once for all of them and wakes
+/// them up.
+#[derive(Debug, Default)]
+pub(crate) struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct CommitState {
+    written: u64,
+    synced: u64,
+    syncing: bool,
+}
+
+impl GroupCommit {
+    /// Registers a write that reached the OS and returns its ticket.
+    pub(crate) fn written(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.written += 1;
+        state.written
+    }
+
+    /// Blocks until a sync covering `ticket` finished.
+    pub(crate) fn wait<F>(&self, ticket: u64, interval: Duration, sync: F) -> Result<()>
+    where
+        F: Fn() -> Result<()>,
+    {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if !state.syncing {
+                state.syncing = true;
+                drop(state);
+                thread::sleep(interval);
+                // every write registered up to here already reached the OS, so the sync covers it.
+                let target = self.state.lock().unwrap().written;
+                let result = sync();
+                let mut state = self.state.lock().unwrap();
+                state.syncing = false;
+                if result.is_ok() && target > state.synced {
+                    state.synced = target;
+                }
+                self.synced.notify_all();
+                return result;
+            }
+            state = self.synced.wait(state).unwrap();
+        }
+    }
+}
@@ -1,19 +1,27 @@
-use std::collections::{HashMap, BTreeMap};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::path::{Path, PathBuf};
 use std::fs::{OpenOptions, File};
 use std::io::{BufWriter, BufReader, SeekFrom};
-use serde::{Serialize, Deserialize};
-use serde_json;
 use std::io::prelude::*;
 use std::fs;
+use std::ops::RangeBounds;
 use std::ffi::OsStr;
 use crate::Result;
 use crate::KvStoreError;
-use super::KvsEngine;
+use super::{expiry_millis, now_millis, Keys, KvPairs, KvsEngine, KvsSnapshot, ReadVersion, ScanOptions, WriteBatch};
+use super::durability::{Durability, GroupCommit};
+use super::record::{self, Command};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, MutexGuard, RwLock};
+use std::thread;
+use std::time::Duration;
 
 const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+// versions only have to tell apart the writes of a key while the store is open, every
+// key loaded from disk starts with the same one. A missing key has version 0.
+const INITIAL_VERSION: u64 = 1;
 
 /// This is an example doc test
 ///
@@ -29,104 +37,295 @@ const COMPACTION_THRESHOLD: u64 = 1024 * 1024;
 /// ```
 #[derive(Debug, Clone)]
 pub struct KvStore {
-    index: Arc<Mutex<BTreeMap<String, CommandPos>>>,
-    path: PathBuf,
+    // readers only hold the read lock while copying a `CommandPos`, every modification
+    // of the index happens while holding the writer lock.
+    index: Arc<RwLock<BTreeMap<Vec<u8>, CommandPos>>>,
+    path: Arc<PathBuf>,
     writer: Arc<Mutex<BufWriterWithPos<File>>>,
-    readers: Arc<Mutex<HashMap<u64, BufReaderWithPos<File>>>>,
+    reader: KvStoreReader,
     current_log: Arc<Mutex<u64>>,
     uncompacted: Arc<Mutex<u64>>,
+    // set while a background compaction is running so `set` does not start a second one.
+    compacting: Arc<AtomicBool>,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    // last version given to a write, only incremented while holding the writer lock.
+    version: Arc<AtomicU64>,
+    pins: Arc<LogPins>,
+    // shared by the handles given to users, `None` in the clone used by the compaction thread.
+    open: Option<Arc<OpenStore>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-enum Command{
-    Set{key: String, value: String},
-    Rm(String),
+// Dropped with the last handle of the store, it waits for a running compaction so the
+// directory can be opened again without the compaction deleting logs under it.
+#[derive(Debug, Default)]
+struct OpenStore {
+    compaction: Mutex<Option<thread::JoinHandle<()>>>,
 }
 
-#[derive(Debug)]
+impl Drop for OpenStore {
+    fn drop(&mut self) {
+        if let Some(compaction) = self.compaction.lock().unwrap().take() {
+            let _ = compaction.join();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 struct CommandPos {
     pos: u64,
     len: u64,
     log_id: u64,
+    // in milliseconds since the unix epoch, copied from the record so expired keys are
+    // found without reading the logs.
+    expires_at: Option<u64>,
+    // changes on every write of the key, transactions use it to detect conflicting writes.
+    version: u64,
+}
+
+impl CommandPos {
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires_at, Some(expires_at) if expires_at <= now)
+    }
 }
 
 impl KvStore {
 
+    /// Opens the store, writes are handed to the OS without waiting for the disk.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        fs::create_dir_all(&path)?;
+        KvStore::open_with_durability(path, Durability::None)
+    }
+
+    /// Opens the store, writes are acknowledged according to `durability`.
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
         remove_empty_logs(&path)?;
-        let log_ids = get_log_ids(&path)?;
-        let index = Arc::new(Mutex::new(BTreeMap::new()));
-        let mut readers = HashMap::new();
+        let log_ids = get_file_ids(&path, "log")?;
+        let mut index = BTreeMap::new();
+        // the logs before the one covered by a hint were compacted into it, only the
+        // logs after it have to be replayed.
+        let replay_from = match load_hint(&path, &log_ids, &mut index)? {
+            Some(hint_id) => {
+                // logs kept after a compaction for a snapshot that never got dropped.
+                for &id in log_ids.iter().filter(|&&id| id < hint_id) {
+                    fs::remove_file(construct_file(id, &path))?;
+                }
+                hint_id + 1
+            }
+            None => 0,
+        };
         let mut uncompacted = 0;
-        for &id in &log_ids {
+        for &id in log_ids.iter().filter(|&&id| id >= replay_from) {
             let mut reader = BufReaderWithPos::new(File::open(construct_file(id, &path))?)?;
-            uncompacted += deserialize_cmds(&mut reader, &mut index.lock().unwrap(), id)?;
-            readers.insert(id, reader);
+            uncompacted += deserialize_cmds(&mut reader, &mut index, id, &path)?;
         }
         let last_log_to_write = log_ids.last().unwrap_or(&0) + 1;
-        let writer = create_new_writer_log(&path, last_log_to_write, &mut readers)?;
+        let writer = create_new_writer_log(&path, last_log_to_write)?;
         Ok(KvStore{
+            reader: KvStoreReader::new(Arc::clone(&path)),
+            pins: Arc::new(LogPins::new(Arc::clone(&path))),
             path,
-            index,
+            index: Arc::new(RwLock::new(index)),
             writer: Arc::new(Mutex::new(writer)),
-            readers: Arc::new(Mutex::new(readers)),
             current_log: Arc::new(Mutex::new(last_log_to_write)),
             uncompacted: Arc::new(Mutex::new(uncompacted)),
+            compacting: Arc::new(AtomicBool::new(false)),
+            durability,
+            group_commit: Arc::new(GroupCommit::default()),
+            version: Arc::new(AtomicU64::new(INITIAL_VERSION)),
+            open: Some(Arc::new(OpenStore::default())),
         })
     }
 
+    // Makes the last write durable according to `self.durability`. The writer lock
+    // is released before waiting for a group commit so other writers can join it.
+    fn sync_write(&self, writer: MutexGuard<'_, BufWriterWithPos<File>>) -> Result<()> {
+        match self.durability {
+            Durability::Always => writer.writer.get_ref().sync_data()?,
+            Durability::GroupCommit{interval} => {
+                drop(writer);
+                let ticket = self.group_commit.written();
+                self.group_commit.wait(ticket, interval, || {
+                    self.writer.lock().unwrap().writer.get_ref().sync_data()?;
+                    Ok(())
+                })?
+            }
+            Durability::None => {}
+        }
+        Ok(())
+    }
+
+    // Reads the value of the key and its version.
+    fn read(&self, key: &[u8]) -> Result<Option<(Vec<u8>, u64)>> {
+        loop {
+            let cmd_pos = match self.index.read().unwrap().get(key) {
+                Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => *cmd_pos,
+                _ => return Ok(None),
+            };
+            match self.reader.read_command(cmd_pos) {
+                Ok(cmd) => return Ok(value_of(cmd, key).map(|value| (value, cmd_pos.version))),
+                // the log was retired by a compaction after the index lookup, the index
+                // already points to the compacted log. A log missing for any other reason
+                // leaves the entry as it was and is an error.
+                Err(KvStoreError::Io(ref err))
+                    if err.kind() == std::io::ErrorKind::NotFound
+                        && self.index.read().unwrap().get(key) != Some(&cmd_pos) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Writes a command at the end of the current log and points the index to it.
+    // The caller holds the writer lock and syncs the write afterwards.
+    fn append(&self, writer: &mut BufWriterWithPos<File>, cmd: Command) -> Result<()> {
+        let latest_post = writer.pos;
+        writer.write_all(&cmd.encode())?;
+        writer.flush()?;
+        let current_log = self.current_log.lock().unwrap();
+        let mut uncompacted = self.uncompacted.lock().unwrap();
+        let new_pos = CommandPos{
+            pos: latest_post,
+            len: (writer.pos - latest_post),
+            log_id: *current_log,
+            expires_at: None,
+            version: self.version.fetch_add(1, Ordering::SeqCst) + 1,
+        };
+        *uncompacted += apply_command(&mut self.index.write().unwrap(), cmd, new_pos);
+        if *uncompacted > COMPACTION_THRESHOLD {
+            self.trigger_compaction();
+        }
+        Ok(())
+    }
+
+    // Starts a compaction on a background thread unless one is already running.
+    fn trigger_compaction(&self) {
+        if self.compacting.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let store = KvStore{open: None, ..self.clone()};
+        let compaction = thread::spawn(move || {
+            if let Err(err) = store.compaction() {
+                eprintln!("Compaction failed: {:?}", err);
+            }
+            store.compacting.store(false, Ordering::SeqCst);
+        });
+        if let Some(open) = &self.open {
+            *open.compaction.lock().unwrap() = Some(compaction);
+        }
+    }
+
 
     ///////////////////////////////////////////////////////////////////////////
-    // This operation needs to create a new log compacted file in which only the latest cmd are going to be added.
-    // In order to achieve this is necessary to:
-    // 1. Create an log file N in which all the previous commands are going to be compacted. This means increments the current id.
-    // 2. The current log is updated with the next reference after the compacted file.
-    // 3. All the files previous to N are going to be deleted.
-    // 4. A future open call  would read only from the new created and compated file.
-    // 5. Possible new operations would use a file which id is the next after the compacted file.
+    // This operation creates a new compacted log file in which only the latest cmd of each key is kept.
+    // It runs on a background thread, so reads and writes continue while the live entries are copied:
+    // 1. Under the writer lock, reserve log N for the compacted entries, move the writer to N + 1 and take a
+    //    copy of the index. Every entry of that copy lives in a log older than N.
+    // 2. Without holding any lock, copy each entry of the copy into N using private file handles.
+    //    Expired keys are not copied.
+    // 3. Under the writer lock, point every key that was not overwritten in the meantime to its entry in N.
+    //    Keys written during step 2 already point to N + 1 or later and are left untouched.
+    //    Before that, the position of every entry in N is written to the hint file of N.
+    // 4. All the files previous to N are retired, a future open call would load the hint of N and
+    //    replay the logs after N. Logs still referenced by a snapshot are deleted once it is dropped.
     ///////////////////////////////////////////////////////////////////////////
 
     fn compaction(&self) -> Result<()> {
-        let mut  current_log = self.current_log.lock().unwrap();
-        let compacted_log_file_id = (*current_log) + 1;
-        let mut uncompacted = self.uncompacted.lock().unwrap();
-        *current_log += 2;
-        let mut readers = self.readers.lock().unwrap();
-        let mut writer = self.writer.lock().unwrap();
-        *writer = create_new_writer_log(&self.path, *current_log , &mut readers)?;
-        let mut compacted_writer = create_new_writer_log(&self.path, compacted_log_file_id, &mut readers)?;
-        let mut new_pos = 0;
-        for cmd_log in &mut self.index.lock().unwrap().values_mut() {
-            let reader = readers
-                             .get_mut(&cmd_log.log_id)
-                             .expect("Unable to find log");
+        let (compacted_log_file_id, entries) = {
+            let mut writer = self.writer.lock().unwrap();
+            let mut current_log = self.current_log.lock().unwrap();
+            let compacted_log_file_id = *current_log + 1;
+            let new_log = *current_log + 2;
+            if self.durability != Durability::None {
+                // writes waiting for a group commit would otherwise only sync the new log.
+                writer.writer.get_ref().sync_data()?;
+            }
+            *writer = create_new_writer_log(&self.path, new_log)?;
+            *current_log = new_log;
+            *self.uncompacted.lock().unwrap() = 0;
+            let entries: Vec<(Vec<u8>, CommandPos)> = self.index
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(key, cmd_pos)| (key.clone(), *cmd_pos))
+                .collect();
+            (compacted_log_file_id, entries)
+        };
+
+        let mut compacted_writer = create_new_writer_log(&self.path, compacted_log_file_id)?;
+        let mut readers: BTreeMap<u64, BufReaderWithPos<File>> = BTreeMap::new();
+        let mut moved = Vec::with_capacity(entries.len());
+        let mut expired = Vec::new();
+        let now = now_millis();
+        for (key, cmd_log) in entries {
+            if cmd_log.is_expired(now) {
+                expired.push((key, cmd_log));
+                continue;
+            }
+            let reader = match readers.entry(cmd_log.log_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    BufReaderWithPos::new(File::open(construct_file(cmd_log.log_id, &self.path))?)?
+                ),
+            };
             if reader.pos != cmd_log.pos {
                 reader.seek(SeekFrom::Start(cmd_log.pos))?;
             }
-            let mut cmd_writer = reader.take(cmd_log.len);
-            let len = std::io::copy(&mut cmd_writer, &mut compacted_writer)?;
+            // entries are decoded and encoded again so records of legacy JSON logs are
+            // migrated to the binary format.
+            // the entries of a batch are written as individual set records.
+            let value = match record::read_command(&mut reader.take(cmd_log.len))? {
+                Some((cmd, _)) => value_of(cmd, &key),
+                None => None,
+            };
+            let value = value.ok_or_else(|| KvStoreError::InvalidRecord("missing record".to_owned()))?;
+            let new_pos = compacted_writer.pos;
+            compacted_writer.write_all(&Command::Set{key: key.clone(), value, expires_at: cmd_log.expires_at}.encode())?;
             let new_cmd_log = CommandPos{
-                pos: (new_pos + len) - new_pos,
-                len,
+                pos: new_pos,
+                len: compacted_writer.pos - new_pos,
                 log_id: compacted_log_file_id,
+                expires_at: cmd_log.expires_at,
+                version: cmd_log.version,
             };
-            *cmd_log = new_cmd_log;
-            new_pos += len;
+            moved.push((key, cmd_log, new_cmd_log));
         }
         compacted_writer.flush()?;
+        compacted_writer.writer.get_ref().sync_data()?;
+        write_hint(&self.path, compacted_log_file_id, &moved)?;
+
+        let mut garbage = 0;
+        {
+            let _writer = self.writer.lock().unwrap();
+            let mut index = self.index.write().unwrap();
+            for (key, old_cmd_log, new_cmd_log) in moved {
+                match index.get_mut(&key) {
+                    Some(cmd_log) if *cmd_log == old_cmd_log => *cmd_log = new_cmd_log,
+                    // the key was overwritten or removed while the compaction was running.
+                    _ => garbage += new_cmd_log.len,
+                }
+            }
+            // expired keys were not copied, their records are about to be deleted.
+            for (key, old_cmd_log) in expired {
+                if index.get(&key) == Some(&old_cmd_log) {
+                    index.remove(&key);
+                }
+            }
+        }
 
-        let stale_files: Vec<_> = readers
-                             .keys()
-                             .filter(|&&key| key < compacted_log_file_id)
-                             .cloned()
-                             .collect();
-        for file_id in stale_files {
-            readers.remove(&file_id);
-            fs::remove_file(construct_file(file_id, &self.path))?;
+        // from here on no index entry refers to a log older than the compacted one.
+        self.reader.safe_point.store(compacted_log_file_id, Ordering::SeqCst);
+        let retired: Vec<u64> = get_file_ids(&self.path, "log")?
+            .into_iter()
+            .filter(|&file_id| file_id < compacted_log_file_id)
+            .collect();
+        self.pins.retire(retired)?;
+        for file_id in get_file_ids(&self.path, "hint")? {
+            if file_id < compacted_log_file_id {
+                fs::remove_file(construct_hint_file(file_id, &self.path))?;
+            }
         }
-        *uncompacted = 0;
+        *self.uncompacted.lock().unwrap() += garbage;
         Ok(())
     }
 
@@ -134,98 +333,482 @@ impl KvStore {
 
 
 impl KvsEngine for KvStore {
+    type Snapshot = KvStoreSnapshot;
+
     /// assing a value to a specific key
     ///
     /// if the key already exists the value is overwritten
-    fn set(&self, key: String, value: String) -> Result<()> {
-        let cmd: Command = Command::Set{key: key.to_owned(), value: value.to_owned()};
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         let mut writer = self.writer.lock().unwrap();
-        let latest_post = writer.pos;
-        serde_json::to_writer(&mut *writer, &cmd)?;
-        writer.writer.flush()?;
-        let current_log = self.current_log.lock().unwrap();
-        let mut uncompacted = self.uncompacted.lock().unwrap();
-        let new_pos = CommandPos{pos: latest_post, len: (writer.pos - latest_post), log_id: *current_log};
-        if let Some(cmd_old) = self.index.lock().unwrap().insert(key, new_pos) {
-            *uncompacted += cmd_old.len
+        self.append(&mut writer, Command::Set{key, value, expires_at: None})?;
+        self.sync_write(writer)
+    }
+
+    /// the expiry time is stored in the record, so the key stays expired after a restart.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        self.append(&mut writer, Command::Set{key, value, expires_at: Some(expiry_millis(ttl))})?;
+        self.sync_write(writer)
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        match self.index.read().unwrap().get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now) => {
+                Ok(cmd_pos.expires_at.map(|expires_at| Duration::from_millis(expires_at - now)))
+            }
+            _ => Err(KvStoreError::KeyNotFound),
         }
-        if *uncompacted > COMPACTION_THRESHOLD {
-            // Should this operation done in a different thread.
-            self.compaction()?
+    }
+
+    /// expired keys are only dropped from the index, their records already carry the
+    /// expiry time and are discarded by the next compaction.
+    fn remove_expired(&self) -> Result<usize> {
+        let _writer = self.writer.lock().unwrap();
+        let mut uncompacted = self.uncompacted.lock().unwrap();
+        let mut index = self.index.write().unwrap();
+        let now = now_millis();
+        let expired: Vec<Vec<u8>> = index
+            .iter()
+            .filter(|(_, cmd_pos)| cmd_pos.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            if let Some(cmd_pos) = index.remove(key) {
+                *uncompacted += cmd_pos.len;
+            }
         }
-        Ok(())
+        Ok(expired.len())
     }
 
     /// gets the value of a specific key if there is some or none.
-    fn get(&self, key: String) -> Result<Option<String>> {
-        match self.index.lock().unwrap().get(&key) {
-            Some(a) => {
-                let mut readers = self.readers.lock().unwrap();
-                let reader = readers
-                                 .get_mut(&a.log_id)
-                                 .expect("unable to find current log");
-                reader.seek(SeekFrom::Start(a.pos))?;
-                let cmd_writer = reader.take(a.len);
-                if let Command::Set{value,  ..} = serde_json::from_reader(cmd_writer)? {
-                    Ok(Some(value))
-                } else {
-                    Ok(None)
+    ///
+    /// Only the index read lock and the file handles of this clone are touched,
+    /// so gets from different clones run in parallel.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.read(&key)?.map(|(value, _)| value))
+    }
+
+    /// the versions are checked while holding the writer lock, so no other write can
+    /// happen between the check and the batch.
+    fn commit_versioned(&self, reads: Vec<(Vec<u8>, ReadVersion)>, batch: WriteBatch) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        for (key, read_version) in reads {
+            let version = match read_version {
+                ReadVersion::Version(version) => version,
+                ReadVersion::Value(value) => {
+                    if self.get_bytes(key)? != value {
+                        return Err(KvStoreError::TransactionConflict);
+                    }
+                    continue;
                 }
-            }
-            None => {
-                Ok(None)
+            };
+            let current = match self.index.read().unwrap().get(&key) {
+                Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => cmd_pos.version,
+                _ => 0,
+            };
+            if current != version {
+                return Err(KvStoreError::TransactionConflict);
             }
         }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let cmd = Command::Batch(batch.into_ops().into_iter().map(Command::from).collect());
+        self.append(&mut writer, cmd)?;
+        self.sync_write(writer)
     }
 
     /// removes the the key and the associated value.
-    fn remove(&self, key: String) -> Result<()> {
-        if let Some(cmd_old) = self.index.lock().unwrap().remove(&key) {
-            let cmd: Command = Command::Rm(key.to_owned());
-            let mut writer = self.writer.lock().unwrap();
-            serde_json::to_writer(&mut writer.writer, &cmd)?;
-            let mut uncompacted = self.uncompacted.lock().unwrap();
-            *uncompacted += cmd_old.len;
-            writer.flush()?;
-            Ok(())
-        } else {
-            Err(KvStoreError::KeyNotFound)
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        match self.index.read().unwrap().get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(now_millis()) => {}
+            _ => return Err(KvStoreError::KeyNotFound),
         }
+        self.append(&mut writer, Command::Rm(key))?;
+        self.sync_write(writer)
     }
+
+    fn flush(&self) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        writer.flush()?;
+        writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// the whole batch is logged as a single record and the index is updated once
+    /// the record was written.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let cmd = Command::Batch(batch.into_ops().into_iter().map(Command::from).collect());
+        let mut writer = self.writer.lock().unwrap();
+        self.append(&mut writer, cmd)?;
+        self.sync_write(writer)
+    }
+
+    /// the current value is read while holding the writer lock, so no other write
+    /// can happen between the comparison and the swap.
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        let mut writer = self.writer.lock().unwrap();
+        let current = self.get_bytes(key.clone())?;
+        if current != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.append(&mut writer, Command::Set{key, value, expires_at: None})?,
+            None if current.is_some() => self.append(&mut writer, Command::Rm(key))?,
+            None => return Ok(true),
+        }
+        self.sync_write(writer)?;
+        Ok(true)
+    }
+
+    /// the index is copied and the logs it references are kept until the snapshot
+    /// and its clones are dropped.
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        // a compaction swaps the index under its write lock before it retires the logs,
+        // so the copy and the pin can not miss it.
+        let index = self.index.read().unwrap();
+        let oldest_log = index.values().map(|cmd_pos| cmd_pos.log_id).min().unwrap_or(u64::MAX);
+        let pin = self.pins.pin(oldest_log);
+        Ok(KvStoreSnapshot{
+            index: Arc::new(index.clone()),
+            reader: KvStoreReader::new(Arc::clone(&self.path)),
+            _pin: Arc::new(pin),
+            lsn: self.version.load(Ordering::SeqCst),
+            taken_at: now_millis(),
+        })
+    }
+
+    /// the keys in the range are collected from the index and their values are read
+    /// lazily, a key removed in the meantime is skipped.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<KvPairs> {
+        let keys = index_keys(&self.index.read().unwrap(), range, options, now_millis());
+        let store = self.clone();
+        Ok(Box::new(keys.into_iter().filter_map(move |key| {
+            match store.get_bytes(key.clone()) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            }
+        })))
+    }
+
+    /// the keys come from the index alone, no log is read.
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<Keys> {
+        let keys = index_keys(&self.index.read().unwrap(), range, options, now_millis());
+        Ok(Box::new(keys.into_iter().map(Ok)))
+    }
+}
+
+/// A read-only view of a `KvStore`, see `KvsEngine::snapshot`.
+///
+/// Clones share the same view.
+#[derive(Debug, Clone)]
+pub struct KvStoreSnapshot {
+    index: Arc<BTreeMap<Vec<u8>, CommandPos>>,
+    reader: KvStoreReader,
+    // keeps the logs referenced by the index until the last clone is dropped.
+    _pin: Arc<LogPin>,
+    lsn: u64,
+    // keys expire as of the time the snapshot was taken.
+    taken_at: u64,
 }
 
+impl KvStoreSnapshot {
+    /// The version of the last write visible in the snapshot.
+    pub fn lsn(&self) -> u64 {
+        self.lsn
+    }
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(self.taken_at) => {
+                Ok(value_of(self.reader.read_command(*cmd_pos)?, &key))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, ReadVersion)> {
+        match self.index.get(&key) {
+            Some(cmd_pos) if !cmd_pos.is_expired(self.taken_at) => {
+                let value = value_of(self.reader.read_command(*cmd_pos)?, &key);
+                Ok((value, ReadVersion::Version(cmd_pos.version)))
+            }
+            _ => Ok((None, ReadVersion::Version(0))),
+        }
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<KvPairs> {
+        let keys = index_keys(&self.index, range, options, self.taken_at);
+        let snapshot = self.clone();
+        Ok(Box::new(keys.into_iter().filter_map(move |key| {
+            match snapshot.get_bytes(key.clone()) {
+                Ok(Some(value)) => Some(Ok((key, value))),
+                Ok(None) => None,
+                Err(err) => Some(Err(err)),
+            }
+        })))
+    }
+}
+
+// The keys of `index` in `range` that are not expired at `now`, in the order and up
+// to the limit of `options`.
+fn index_keys<R: RangeBounds<Vec<u8>>>(index: &BTreeMap<Vec<u8>, CommandPos>, range: R, options: ScanOptions, now: u64) -> Vec<Vec<u8>> {
+    if super::is_empty_range(&range) {
+        return Vec::new();
+    }
+    let keys = index
+        .range((range.start_bound().cloned(), range.end_bound().cloned()))
+        .filter(|(_, cmd_pos)| !cmd_pos.is_expired(now));
+    let limit = options.limit.unwrap_or(usize::MAX);
+    if options.reverse {
+        keys.rev().take(limit).map(|(key, _)| key.clone()).collect()
+    } else {
+        keys.take(limit).map(|(key, _)| key.clone()).collect()
+    }
+}
+
+/// Logs retired by a compaction while snapshots still read them.
+///
+/// A snapshot pins every log from the oldest one its index references, a
+/// retired log is deleted once no pin covers it anymore.
+#[derive(Debug)]
+struct LogPins {
+    path: Arc<PathBuf>,
+    state: Mutex<PinState>,
+}
+
+#[derive(Debug, Default)]
+struct PinState {
+    next_pin: u64,
+    // oldest log pinned by each live snapshot.
+    pinned: BTreeMap<u64, u64>,
+    retired: Vec<u64>,
+}
+
+impl LogPins {
+    fn new(path: Arc<PathBuf>) -> LogPins {
+        LogPins {path, state: Mutex::new(PinState::default())}
+    }
+
+    fn pin(self: &Arc<Self>, oldest_log: u64) -> LogPin {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_pin;
+        state.next_pin += 1;
+        state.pinned.insert(id, oldest_log);
+        LogPin {pins: Arc::clone(self), id}
+    }
+
+    // Deletes the logs no snapshot reads, the others are deleted when their last pin is dropped.
+    fn retire(&self, log_ids: Vec<u64>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.retired.extend(log_ids);
+        self.remove_unpinned(&mut state)
+    }
+
+    fn remove_unpinned(&self, state: &mut PinState) -> Result<()> {
+        let oldest_pinned = state.pinned.values().copied().min().unwrap_or(u64::MAX);
+        let (pinned, unpinned) = state.retired.iter().partition(|&&log_id| log_id >= oldest_pinned);
+        state.retired = pinned;
+        for log_id in unpinned {
+            match fs::remove_file(construct_file(log_id, &self.path)) {
+                // the same log may have been retired by two compactions.
+                Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct LogPin {
+    pins: Arc<LogPins>,
+    id: u64,
+}
+
+impl Drop for LogPin {
+    fn drop(&mut self) {
+        let mut state = self.pins.state.lock().unwrap();
+        state.pinned.remove(&self.id);
+        if let Err(err) = self.pins.remove_unpinned(&mut state) {
+            eprintln!("Failed to remove retired logs: {:?}", err);
+        }
+    }
+}
+
+/// File handles used by `get`.
+///
+/// Every clone of a `KvStore` opens its own handles lazily, so readers on
+/// different threads never share a seek position. The lock is only taken by
+/// the threads sharing one clone, clones never wait for each other.
+#[derive(Debug)]
+struct KvStoreReader {
+    path: Arc<PathBuf>,
+    // logs older than this one were retired by a compaction, their handles can be closed.
+    safe_point: Arc<AtomicU64>,
+    readers: Mutex<BTreeMap<u64, BufReaderWithPos<File>>>,
+}
+
+impl KvStoreReader {
+    fn new(path: Arc<PathBuf>) -> KvStoreReader {
+        KvStoreReader {
+            path,
+            safe_point: Arc::new(AtomicU64::new(0)),
+            readers: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn read_command(&self, cmd_pos: CommandPos) -> Result<Command> {
+        let mut readers = self.readers.lock().unwrap();
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        while let Some(entry) = readers.first_entry() {
+            if *entry.key() >= safe_point {
+                break;
+            }
+            entry.remove();
+        }
+        let reader = match readers.entry(cmd_pos.log_id) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                BufReaderWithPos::new(File::open(construct_file(cmd_pos.log_id, &self.path))?)?
+            ),
+        };
+        reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        match record::read_command(&mut reader.take(cmd_pos.len))? {
+            Some((cmd, _)) => Ok(cmd),
+            None => Err(KvStoreError::InvalidRecord("missing record".to_owned())),
+        }
+    }
+}
+
+impl Clone for KvStoreReader {
+    fn clone(&self) -> KvStoreReader {
+        KvStoreReader {
+            path: Arc::clone(&self.path),
+            safe_point: Arc::clone(&self.safe_point),
+            readers: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+// Replays every record of a log into the index.
+//
+// A crash in the middle of a write leaves a partial record at the end of the log, or after a power
+// loss a tail of zeroes or garbage. When a record can not be decoded and no valid record follows it,
+// the log is truncated back to the last good record. A record that can not be decoded before a valid
+// one is a real corruption and is reported with the log id and the offset of the record.
 fn deserialize_cmds(
     reader: &mut BufReaderWithPos<File>,
-    index: &mut BTreeMap<String, CommandPos>,
+    index: &mut BTreeMap<Vec<u8>, CommandPos>,
     log_id: u64,
+    path: &Path,
 ) -> Result<u64>{
     let mut pos = reader.seek(SeekFrom::Start(0))?;
-    let mut stream = serde_json::Deserializer::from_reader(reader).into_iter();
     let mut uncompacted = 0;
-    while let Some(cmd) = stream.next() {
-        let current_pos = stream.byte_offset() as u64;
-        match cmd? {
-            Command::Set{key, ..} => {
-                let pos = CommandPos{pos, len: (current_pos - pos), log_id};
-                if let Some(old_cmd) = index.insert(key, pos) {
-                    uncompacted += old_cmd.len
-                }
-            }
-            Command::Rm(key) => {
-                if let Some(old_cmd) = index.remove(&key) {
-                    uncompacted += old_cmd.len
+    loop {
+        let (cmd, len) = match record::read_command(reader) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => break,
+            Err(err) if is_decode_error(&err) => {
+                let log = fs::read(construct_file(log_id, path))?;
+                if record::contains_record(&log[pos as usize + 1..]) {
+                    return Err(KvStoreError::CorruptedLog{log_id, offset: pos, reason: err.to_string()});
                 }
-                // in the next compaction process this remove cmd  entry has to be deleted too because
-                // it would not be necessary anymore.
-                uncompacted += current_pos - pos
+                eprintln!(
+                    "Discarding {} bytes of a torn record at offset {} of {}.log: {}",
+                    log.len() as u64 - pos, pos, log_id, err
+                );
+                OpenOptions::new().write(true).open(construct_file(log_id, path))?.set_len(pos)?;
+                break;
             }
+            Err(err) => return Err(err),
         };
+        let current_pos = pos + len;
+        if reader.pos != current_pos {
+            // the JSON deserializer of legacy records may read past the end of the record.
+            reader.seek(SeekFrom::Start(current_pos))?;
+        }
+        uncompacted += apply_command(index, cmd, CommandPos{
+            pos,
+            len: (current_pos - pos),
+            log_id,
+            expires_at: None,
+            version: INITIAL_VERSION,
+        });
         pos = current_pos;
     };
     Ok(uncompacted)
 }
 
-fn remove_empty_logs(path: & PathBuf) -> Result<()> {
+// Applies a command found at `cmd_pos` to the index, returns the number of bytes it made stale.
+fn apply_command(index: &mut BTreeMap<Vec<u8>, CommandPos>, cmd: Command, cmd_pos: CommandPos) -> u64 {
+    let mut uncompacted = 0;
+    match cmd {
+        Command::Set{key, expires_at, ..} => {
+            if let Some(old_cmd) = index.insert(key, CommandPos{expires_at, ..cmd_pos}) {
+                uncompacted += old_cmd.len
+            }
+        }
+        Command::Rm(key) => {
+            if let Some(old_cmd) = index.remove(&key) {
+                uncompacted += old_cmd.len
+            }
+            // in the next compaction process this remove cmd  entry has to be deleted too because
+            // it would not be necessary anymore.
+            uncompacted += cmd_pos.len
+        }
+        // every key of the batch points to the batch record. A batch whose keys were all
+        // overwritten is counted once per key, which only makes the next compaction come sooner.
+        Command::Batch(cmds) => {
+            for cmd in cmds {
+                match cmd {
+                    Command::Rm(key) => {
+                        if let Some(old_cmd) = index.remove(&key) {
+                            uncompacted += old_cmd.len
+                        }
+                    }
+                    cmd => uncompacted += apply_command(index, cmd, cmd_pos),
+                }
+            }
+        }
+    }
+    uncompacted
+}
+
+// The value a command gives to `key`, the last command of a batch touching the key wins.
+fn value_of(cmd: Command, key: &[u8]) -> Option<Vec<u8>> {
+    match cmd {
+        Command::Set{value, ..} => Some(value),
+        Command::Rm(_) => None,
+        Command::Batch(cmds) => cmds
+            .into_iter()
+            .rev()
+            .find(|cmd| match cmd {
+                Command::Set{key: cmd_key, ..} | Command::Rm(cmd_key) => cmd_key == key,
+                Command::Batch(_) => false,
+            })
+            .and_then(|cmd| value_of(cmd, key)),
+    }
+}
+
+// Whether the error comes from the content of a record rather than from the file system.
+fn is_decode_error(err: &KvStoreError) -> bool {
+    match err {
+        KvStoreError::Io(err) => err.kind() == std::io::ErrorKind::UnexpectedEof,
+        KvStoreError::SerdeIo(_) | KvStoreError::InvalidRecord(_) | KvStoreError::StringUtf8Error(_) => true,
+        _ => false,
+    }
+}
+
+fn remove_empty_logs(path: &Path) -> Result<()> {
     let files = fs::read_dir(path)?;
     let target = std::ffi::OsString::from("log");
     files
@@ -244,9 +827,9 @@ fn remove_empty_logs(path: & PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn get_log_ids(path: &PathBuf) -> Result<Vec<u64>> {
+fn get_file_ids(path: &Path, extension: &str) -> Result<Vec<u64>> {
     let files = fs::read_dir(path)?;
-    let target = std::ffi::OsString::from("log");
+    let target = std::ffi::OsString::from(extension);
     let mut a: Vec<u64> = files
         .filter_map(std::io::Result::ok)
         .map(|e| e.path())
@@ -254,7 +837,8 @@ fn get_log_ids(path: &PathBuf) -> Result<Vec<u64>> {
         .flat_map(|entry| {
             entry.file_name()
                  .and_then(OsStr::to_str)
-                 .map(|file| file.trim_end_matches(".log"))
+                 .and_then(|file| file.strip_suffix(extension))
+                 .and_then(|file| file.strip_suffix('.'))
                  .map(str::parse::<u64>)
         })
         .flatten()
@@ -263,26 +847,77 @@ fn get_log_ids(path: &PathBuf) -> Result<Vec<u64>> {
     Ok(a)
 }
 
-fn construct_file(id: u64, path: &PathBuf) -> PathBuf {
+fn construct_file(id: u64, path: &Path) -> PathBuf {
     path.join(format!("{}.log", id))
 }
 
+fn construct_hint_file(id: u64, path: &Path) -> PathBuf {
+    path.join(format!("{}.hint", id))
+}
+
+// Writes the hint of the compacted log `log_id`. The hint is renamed into place once
+// synced, so a crash never leaves a partial hint behind.
+fn write_hint(path: &Path, log_id: u64, moved: &[(Vec<u8>, CommandPos, CommandPos)]) -> Result<()> {
+    let entries: Vec<record::HintEntry> = moved
+        .iter()
+        .map(|(key, _, cmd_pos)| record::HintEntry{
+            key: key.clone(),
+            log_id: cmd_pos.log_id,
+            pos: cmd_pos.pos,
+            len: cmd_pos.len,
+            expires_at: cmd_pos.expires_at,
+        })
+        .collect();
+    let tmp = path.join(format!("{}.hint.tmp", log_id));
+    let mut file = File::create(&tmp)?;
+    file.write_all(&record::encode_hint(&entries))?;
+    file.sync_data()?;
+    fs::rename(tmp, construct_hint_file(log_id, path))?;
+    Ok(())
+}
+
+// Loads the latest hint whose log still exists into the index and returns the id of its log.
+// An unreadable hint is ignored, the logs are then replayed from the beginning.
+fn load_hint(path: &Path, log_ids: &[u64], index: &mut BTreeMap<Vec<u8>, CommandPos>) -> Result<Option<u64>> {
+    let hint_id = match get_file_ids(path, "hint")?
+        .into_iter()
+        .rev()
+        .find(|id| log_ids.contains(id)) {
+        Some(hint_id) => hint_id,
+        None => return Ok(None),
+    };
+    match record::decode_hint(&fs::read(construct_hint_file(hint_id, path))?) {
+        Ok(entries) => {
+            for entry in entries {
+                index.insert(entry.key, CommandPos{
+                    pos: entry.pos,
+                    len: entry.len,
+                    log_id: entry.log_id,
+                    expires_at: entry.expires_at,
+                    version: INITIAL_VERSION,
+                });
+            }
+            Ok(Some(hint_id))
+        }
+        Err(err) => {
+            eprintln!("Ignoring {}.hint: {}", hint_id, err);
+            Ok(None)
+        }
+    }
+}
+
 
 fn create_new_writer_log(
-    path: &PathBuf,
+    path: &Path,
     id: u64,
-    readers: &mut HashMap<u64, BufReaderWithPos<File>>,
 ) -> Result<BufWriterWithPos<File>> {
-    let log = construct_file(id, &path);
+    let log = construct_file(id, path);
     let writer = BufWriterWithPos::new(
         OpenOptions::new()
         .append(true)
         .create(true)
-        .write(true)
         .open(&log)?
     )?;
-    let reader = BufReaderWithPos::new(File::open(log)?)?;
-    readers.insert(id, reader);
     Ok(writer)
 
 }
@@ -296,7 +931,7 @@ struct BufReaderWithPos<R: Read + Seek> {
 
 impl <R: Read + Seek> BufReaderWithPos<R> {
     fn new(mut reader: R) -> Result<Self> {
-        let pos = reader.seek(SeekFrom::Current(0))?;
+        let pos = reader.stream_position()?;
         Ok(BufReaderWithPos{
             reader: BufReader::new(reader),
             pos
@@ -319,6 +954,17 @@ impl <R: Read + Seek> Read for BufReaderWithPos<R> {
     }
 
 }
+
+impl <R: Read + Seek> BufRead for BufReaderWithPos<R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.pos += amt as u64;
+    }
+}
 /////////// Writer
 #[derive(Debug)]
 struct BufWriterWithPos<W: Write + Seek> {
@@ -348,7 +994,7 @@ impl <W: Write + Seek> Write for BufWriterWithPos<W> {
 
 impl<W: Write + Seek> BufWriterWithPos<W> {
     fn new(mut writer: W) -> Result<Self> {
-        let pos = writer.seek(SeekFrom::Current(0))?;
+        let pos = writer.stream_position()?;
         Ok(BufWriterWithPos{
             writer: BufWriter::new(writer),
             pos
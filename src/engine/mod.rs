@@ -1,12 +1,175 @@
 use super::Result;
+use serde::{Deserialize, Serialize};
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// Options of a range scan.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct ScanOptions {
+    /// maximum number of pairs returned.
+    pub limit: Option<usize>,
+    /// returns the pairs from the largest key to the smallest one.
+    pub reverse: bool,
+}
+
+/// Key/value pairs returned by a scan, in key order.
+pub type KvPairs = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
+
+/// Keys returned by `KvsEngine::scan_keys`, in key order.
+pub type Keys = Box<dyn Iterator<Item = Result<Vec<u8>>> + Send>;
+
+/// Keys and values are arbitrary bytes, the string methods are a convenience
+/// layer on top of the byte oriented ones.
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
-    fn get(&self, key: String) -> Result<Option<String>>;
-    fn remove(&self, key: String) -> Result<()>;
+    /// read-only view returned by `snapshot`.
+    type Snapshot: KvsSnapshot;
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// fails with `KvStoreError::StringUtf8Error` when the value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.into_bytes())?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// sets the key to the value, the key expires once `ttl` elapsed.
+    ///
+    /// A later write of the key without a ttl makes it permanent again.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// returns the time left before the key expires, or `None` if it never does.
+    ///
+    /// fails with `KvStoreError::KeyNotFound` when the key does not exist.
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// deletes the expired keys and returns how many there were.
+    ///
+    /// Expired keys are already hidden from reads, this only reclaims their space.
+    fn remove_expired(&self) -> Result<usize>;
+
+    /// writes every acknowledged write to disk, whatever the durability of the engine.
+    fn flush(&self) -> Result<()>;
+
+    /// applies every write of the batch atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// sets the key to `new`, or removes it when `new` is `None`, only if its current
+    /// value is `expected`, where `None` means the key does not exist.
+    ///
+    /// Returns whether the swap happened.
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool>;
+
+    /// returns a read-only view of the engine as it is now, later writes are not
+    /// visible through it.
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+
+    /// starts a transaction reading through a snapshot of this engine, see `Transaction`.
+    fn begin(&self) -> Result<Transaction<Self>> {
+        Transaction::new(self.clone())
+    }
+
+    /// applies the batch atomically if every key of `reads` is still at the version
+    /// it was read at, fails with `KvStoreError::TransactionConflict` otherwise.
+    fn commit_versioned(&self, reads: Vec<(Vec<u8>, ReadVersion)>, batch: WriteBatch) -> Result<()>;
+
+    /// returns the pairs whose key is in `range`.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<KvPairs>;
+
+    /// returns the pairs whose key starts with `prefix`.
+    fn scan_prefix(&self, prefix: Vec<u8>, options: ScanOptions) -> Result<KvPairs> {
+        self.scan(prefix_range(prefix), options)
+    }
+
+    /// returns the keys `scan` would, engines that can skip reading the values do.
+    fn scan_keys<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<Keys> {
+        Ok(Box::new(self.scan(range, options)?.map(|pair| pair.map(|(key, _)| key))))
+    }
+}
+
+/// A point-in-time view of an engine, see `KvsEngine::snapshot`.
+pub trait KvsSnapshot: Send + 'static {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// returns the value of the key along with what `KvsEngine::commit_versioned`
+    /// checks to find out whether the key changed since the snapshot was taken.
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, ReadVersion)>;
+
+    /// fails with `KvStoreError::StringUtf8Error` when the value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        Ok(self.get_bytes(key.into_bytes())?.map(String::from_utf8).transpose()?)
+    }
+
+    /// returns the pairs whose key is in `range`.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<KvPairs>;
+
+    /// returns the pairs whose key starts with `prefix`.
+    fn scan_prefix(&self, prefix: Vec<u8>, options: ScanOptions) -> Result<KvPairs> {
+        self.scan(prefix_range(prefix), options)
+    }
+}
+
+/// The current time in milliseconds since the unix epoch, the unit of expiry times.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// The expiry time of a key written now with `ttl`.
+pub(crate) fn expiry_millis(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// The range of the keys starting with `prefix`.
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // the first key after the prefix is the prefix without its trailing 0xff bytes and
+    // the last byte incremented, there is none when the prefix is only 0xff bytes.
+    let mut end = prefix.clone();
+    while end.last() == Some(&0xff) {
+        end.pop();
+    }
+    let end = match end.last_mut() {
+        Some(last) => {
+            *last += 1;
+            Bound::Excluded(end)
+        }
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix), end)
+}
+
+// `BTreeMap::range` panics on these ranges, they never contain a key.
+pub(crate) fn is_empty_range<R: RangeBounds<Vec<u8>>>(range: &R) -> bool {
+    match (range.start_bound(), range.end_bound()) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
 }
 
+#[cfg(feature = "async")]
+mod async_engine;
+mod batch;
+mod durability;
 mod kvs;
+mod record;
 mod sled;
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
+mod transaction;
+#[cfg(feature = "async")]
+pub use self::async_engine::AsyncKvsEngine;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::durability::Durability;
+pub use self::kvs::{KvStore, KvStoreSnapshot};
+pub use self::sled::{SledKvsEngine, SledSnapshot};
+pub use self::transaction::{ReadVersion, Transaction};
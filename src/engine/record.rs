@@ -0,0 +1,324 @@
+use crate::KvStoreError;
+use crate::Result;
+use super::BatchOp;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::io::{BufRead, Read};
+
+///////////////////////////////////////////////////////////////////////////
+// Layout of a record in a `N.log` file, all integers are little endian:
+//
+// | magic "KV" (2) | version (1) | kind (1) | payload len (4) | crc32 (4) | payload |
+//
+// The crc32 covers the version, kind and length bytes plus the payload.
+// A `Set` payload is the key length (4) followed by the key and the value bytes,
+// a `SetExpiring` payload is the expiry time in milliseconds since the unix epoch (8)
+// followed by a `Set` payload, a `Rm` payload is the key bytes. A `Batch` payload is the number of commands (4)
+// followed by | kind (1) | payload len (4) | payload | for each of them, a batch
+// is covered by a single crc32 so it is replayed entirely or not at all.
+//
+// Logs written before this format are a stream of JSON `Command`s. A JSON record
+// always starts with `{`, which never collides with the magic, so both kinds of
+// records are detected one by one and old logs are still readable. Compaction
+// rewrites every live entry with the binary format.
+///////////////////////////////////////////////////////////////////////////
+
+const MAGIC: &[u8; 2] = b"KV";
+const FORMAT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 12;
+const KIND_SET: u8 = 1;
+const KIND_RM: u8 = 2;
+const KIND_BATCH: u8 = 3;
+const KIND_SET_EXPIRING: u8 = 4;
+
+#[derive(Debug)]
+pub enum Command {
+    /// `expires_at` is in milliseconds since the unix epoch.
+    Set { key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64> },
+    Rm(Vec<u8>),
+    Batch(Vec<Command>),
+}
+
+// Commands of the legacy JSON logs, keys and values were always strings.
+#[derive(Debug, Serialize, Deserialize)]
+enum LegacyCommand {
+    Set { key: String, value: String },
+    Rm(String),
+}
+
+impl From<LegacyCommand> for Command {
+    fn from(cmd: LegacyCommand) -> Command {
+        match cmd {
+            LegacyCommand::Set { key, value } => {
+                Command::Set { key: key.into_bytes(), value: value.into_bytes(), expires_at: None }
+            }
+            LegacyCommand::Rm(key) => Command::Rm(key.into_bytes()),
+        }
+    }
+}
+
+impl From<BatchOp> for Command {
+    fn from(op: BatchOp) -> Command {
+        match op {
+            BatchOp::Set { key, value } => Command::Set { key, value, expires_at: None },
+            BatchOp::Remove(key) => Command::Rm(key),
+        }
+    }
+}
+
+impl Command {
+    /// Encodes the command as a binary record.
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, payload) = self.payload();
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(MAGIC);
+        record.push(FORMAT_VERSION);
+        record.push(kind);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        let crc = checksum(&record[2..8], &payload);
+        record.extend_from_slice(&crc.to_le_bytes());
+        record.extend_from_slice(&payload);
+        record
+    }
+
+    fn payload(&self) -> (u8, Vec<u8>) {
+        match self {
+            Command::Set { key, value, expires_at } => {
+                let mut payload = Vec::with_capacity(12 + key.len() + value.len());
+                if let Some(expires_at) = expires_at {
+                    payload.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                payload.extend_from_slice(&(key.len() as u32).to_le_bytes());
+                payload.extend_from_slice(key);
+                payload.extend_from_slice(value);
+                (if expires_at.is_some() { KIND_SET_EXPIRING } else { KIND_SET }, payload)
+            }
+            Command::Rm(key) => (KIND_RM, key.clone()),
+            Command::Batch(cmds) => {
+                let mut payload = Vec::new();
+                payload.extend_from_slice(&(cmds.len() as u32).to_le_bytes());
+                for cmd in cmds {
+                    let (kind, cmd_payload) = cmd.payload();
+                    payload.push(kind);
+                    payload.extend_from_slice(&(cmd_payload.len() as u32).to_le_bytes());
+                    payload.extend_from_slice(&cmd_payload);
+                }
+                (KIND_BATCH, payload)
+            }
+        }
+    }
+
+    fn decode(kind: u8, mut payload: Vec<u8>) -> Result<Command> {
+        match kind {
+            KIND_SET => {
+                let (key, value) = decode_set(payload)?;
+                Ok(Command::Set { key, value, expires_at: None })
+            }
+            KIND_SET_EXPIRING => {
+                if payload.len() < 8 {
+                    return Err(KvStoreError::InvalidRecord("truncated set payload".to_owned()));
+                }
+                let set_payload = payload.split_off(8);
+                let expires_at = u64::from_le_bytes(payload[..].try_into().unwrap());
+                let (key, value) = decode_set(set_payload)?;
+                Ok(Command::Set { key, value, expires_at: Some(expires_at) })
+            }
+            KIND_RM => Ok(Command::Rm(payload)),
+            KIND_BATCH => {
+                let mut rest = &payload[..];
+                let count = u32::from_le_bytes(take_batch_array(&mut rest)?);
+                let mut cmds = Vec::new();
+                for _ in 0..count {
+                    let [kind] = take_batch_array(&mut rest)?;
+                    let len = u32::from_le_bytes(take_batch_array(&mut rest)?) as usize;
+                    if rest.len() < len || kind == KIND_BATCH {
+                        return Err(KvStoreError::InvalidRecord("invalid batch payload".to_owned()));
+                    }
+                    let (cmd_payload, tail) = rest.split_at(len);
+                    rest = tail;
+                    cmds.push(Command::decode(kind, cmd_payload.to_vec())?);
+                }
+                if !rest.is_empty() {
+                    return Err(KvStoreError::InvalidRecord("invalid batch payload".to_owned()));
+                }
+                Ok(Command::Batch(cmds))
+            }
+            kind => Err(KvStoreError::InvalidRecord(format!("unknown record kind {}", kind))),
+        }
+    }
+}
+
+// Splits a `Set` payload into the key and the value.
+fn decode_set(mut payload: Vec<u8>) -> Result<(Vec<u8>, Vec<u8>)> {
+    if payload.len() < 4 {
+        return Err(KvStoreError::InvalidRecord("truncated set payload".to_owned()));
+    }
+    let key_len = u32::from_le_bytes([payload[0], payload[1], payload[2], payload[3]]) as usize;
+    if payload.len() < 4 + key_len {
+        return Err(KvStoreError::InvalidRecord("truncated set payload".to_owned()));
+    }
+    let value = payload.split_off(4 + key_len);
+    let key = payload.split_off(4);
+    Ok((key, value))
+}
+
+fn take_batch_array<const N: usize>(rest: &mut &[u8]) -> Result<[u8; N]> {
+    take_array(rest).map_err(|_| KvStoreError::InvalidRecord("invalid batch payload".to_owned()))
+}
+
+/// Reads the record at the current position of `reader`, either a binary
+/// record or a legacy JSON one.
+///
+/// Returns the command and the number of bytes it takes in the log, or `None`
+/// at the end of the log.
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<(Command, u64)>> {
+    let first = match reader.fill_buf()?.first() {
+        Some(&byte) => byte,
+        None => return Ok(None),
+    };
+    if first == b'{' {
+        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter::<LegacyCommand>();
+        return match stream.next() {
+            Some(cmd) => Ok(Some((cmd?.into(), stream.byte_offset() as u64))),
+            None => Ok(None),
+        };
+    }
+
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if &header[0..2] != MAGIC {
+        return Err(KvStoreError::InvalidRecord("bad magic".to_owned()));
+    }
+    if header[2] != FORMAT_VERSION {
+        return Err(KvStoreError::InvalidRecord(format!("unsupported format version {}", header[2])));
+    }
+    let kind = header[3];
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    // a torn or corrupted length must not turn into a huge allocation.
+    let mut payload = Vec::new();
+    reader.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    if checksum(&header[2..8], &payload) != crc {
+        return Err(KvStoreError::InvalidRecord("checksum mismatch".to_owned()));
+    }
+
+    let cmd = Command::decode(kind, payload)?;
+    Ok(Some((cmd, (HEADER_LEN + len) as u64)))
+}
+
+/// Whether a whole binary record, with its magic and a matching crc32, or the start
+/// of a legacy JSON record is anywhere in `log`. The bytes after a torn write hold
+/// none, a corruption in the middle of a log is followed by the records written after it.
+pub fn contains_record(log: &[u8]) -> bool {
+    let legacy = |start: usize| log[start..].starts_with(b"{\"Set\":") || log[start..].starts_with(b"{\"Rm\":");
+    (0..log.len()).any(legacy) || (0..log.len().saturating_sub(HEADER_LEN - 1)).any(|start| {
+        let header = &log[start..start + HEADER_LEN];
+        if &header[0..2] != MAGIC || header[2] != FORMAT_VERSION {
+            return false;
+        }
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let crc = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+        match log[start + HEADER_LEN..].get(..len) {
+            Some(payload) => checksum(&header[2..8], payload) == crc,
+            None => false,
+        }
+    })
+}
+
+fn checksum(header: &[u8], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(header);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+///////////////////////////////////////////////////////////////////////////
+// A `N.hint` file is written beside the compacted log `N.log` and lists where
+// the entry of every key lives, so `open` does not need to decode the log:
+//
+// | magic "KH" (2) | version (1) | entries | crc32 (4) |
+//
+// where each entry is | key len (4) | key | log id (8) | pos (8) | len (8) | expires at (8) |
+// and the crc32 covers everything before it. An expiry of 0 means the key never expires,
+// hints of version 1 have no expiry field.
+///////////////////////////////////////////////////////////////////////////
+
+const HINT_MAGIC: &[u8; 2] = b"KH";
+const HINT_VERSION: u8 = 2;
+
+#[derive(Debug)]
+pub struct HintEntry {
+    pub key: Vec<u8>,
+    pub log_id: u64,
+    pub pos: u64,
+    pub len: u64,
+    pub expires_at: Option<u64>,
+}
+
+/// Encodes the entries of a hint file.
+pub fn encode_hint(entries: &[HintEntry]) -> Vec<u8> {
+    let mut hint = Vec::new();
+    hint.extend_from_slice(HINT_MAGIC);
+    hint.push(HINT_VERSION);
+    for entry in entries {
+        hint.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        hint.extend_from_slice(&entry.key);
+        hint.extend_from_slice(&entry.log_id.to_le_bytes());
+        hint.extend_from_slice(&entry.pos.to_le_bytes());
+        hint.extend_from_slice(&entry.len.to_le_bytes());
+        hint.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+    }
+    let crc = crc32fast::hash(&hint);
+    hint.extend_from_slice(&crc.to_le_bytes());
+    hint
+}
+
+/// Decodes the content of a hint file.
+pub fn decode_hint(hint: &[u8]) -> Result<Vec<HintEntry>> {
+    if hint.len() < 7 || &hint[0..2] != HINT_MAGIC {
+        return Err(KvStoreError::InvalidRecord("bad hint magic".to_owned()));
+    }
+    let version = hint[2];
+    if version != 1 && version != HINT_VERSION {
+        return Err(KvStoreError::InvalidRecord(format!("unsupported hint version {}", hint[2])));
+    }
+    let (content, crc) = hint.split_at(hint.len() - 4);
+    if crc32fast::hash(content) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return Err(KvStoreError::InvalidRecord("hint checksum mismatch".to_owned()));
+    }
+    let mut entries = Vec::new();
+    let mut rest = &content[3..];
+    while !rest.is_empty() {
+        let key_len = u32::from_le_bytes(take_array(&mut rest)?) as usize;
+        if rest.len() < key_len {
+            return Err(KvStoreError::InvalidRecord("truncated hint entry".to_owned()));
+        }
+        let (key, tail) = rest.split_at(key_len);
+        rest = tail;
+        entries.push(HintEntry {
+            key: key.to_vec(),
+            log_id: u64::from_le_bytes(take_array(&mut rest)?),
+            pos: u64::from_le_bytes(take_array(&mut rest)?),
+            len: u64::from_le_bytes(take_array(&mut rest)?),
+            expires_at: match version {
+                1 => None,
+                _ => Some(u64::from_le_bytes(take_array(&mut rest)?)).filter(|&expires_at| expires_at != 0),
+            },
+        });
+    }
+    Ok(entries)
+}
+
+fn take_array<const N: usize>(rest: &mut &[u8]) -> Result<[u8; N]> {
+    if rest.len() < N {
+        return Err(KvStoreError::InvalidRecord("truncated hint entry".to_owned()));
+    }
+    let (bytes, tail) = rest.split_at(N);
+    *rest = tail;
+    let mut array = [0; N];
+    array.copy_from_slice(bytes);
+    Ok(array)
+}
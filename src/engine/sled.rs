@@ -1,50 +1,425 @@
 use crate::Result;
-use super::KvsEngine;
-use sled::Db;
+use super::{expiry_millis, now_millis, BatchOp, KvPairs, KvsEngine, KvsSnapshot, ReadVersion, ScanOptions, WriteBatch};
+use super::durability::{Durability, GroupCommit};
+use sled::transaction::{ConflictableTransactionResult, TransactionalTree};
+use sled::{Db, IVec, Transactional, Tree};
+use std::collections::BTreeMap;
 use std::fs;
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, Weak};
+use std::time::Duration;
 use crate::KvStoreError;
 
+type TxResult<T> = ConflictableTransactionResult<T, sled::Error>;
+
+/// Clones share the same database, sled handles are cheap to clone.
+#[derive(Clone)]
 pub struct SledKvsEngine {
-    bd: Db
+    bd: Db,
+    // expiry time of the keys written with a ttl, in milliseconds since the unix epoch.
+    // Every write of a key updates both trees in a single transaction.
+    ttl: Tree,
+    durability: Durability,
+    group_commit: Arc<GroupCommit>,
+    // the undo logs of the live snapshots. Writers hold it shared while they write,
+    // taking a snapshot holds it exclusively so no write is half done at that time.
+    snapshots: Arc<RwLock<Vec<Weak<Mutex<UndoLog>>>>>,
 }
 
+// The value and the expiry time every key written since a snapshot was taken had at
+// that time, `None` for a key that did not exist.
+type UndoLog = BTreeMap<Vec<u8>, Option<(IVec, Option<u64>)>>;
+
 impl SledKvsEngine {
+    /// Opens the database, every write is flushed to disk before it is acknowledged.
     pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        SledKvsEngine::open_with_durability(path, Durability::Always)
+    }
+
+    /// Opens the database, writes are acknowledged according to `durability`.
+    ///
+    /// With `Durability::None` sled flushes its log in the background.
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<SledKvsEngine> {
         let path = path.into();
         fs::create_dir_all(&path)?;
         let bd: Db = sled::open(&path)?;
+        let ttl = bd.open_tree("ttl")?;
         Ok(
-            SledKvsEngine{bd}
+            SledKvsEngine{bd, ttl, durability, group_commit: Arc::new(GroupCommit::default()), snapshots: Arc::default()}
         )
     }
+
+    // Makes the last write durable according to `self.durability`.
+    fn sync_write(&self) -> Result<()> {
+        match self.durability {
+            Durability::Always => {
+                self.bd.flush()?;
+            }
+            Durability::GroupCommit{interval} => {
+                let ticket = self.group_commit.written();
+                self.group_commit.wait(ticket, interval, || {
+                    self.bd.flush()?;
+                    Ok(())
+                })?
+            }
+            Durability::None => {}
+        }
+        Ok(())
+    }
 }
 
-impl Clone for SledKvsEngine {
+// The undo logs of the live snapshots, the guard must be held until the write is done.
+struct Undo<'a> {
+    _guard: RwLockReadGuard<'a, Vec<Weak<Mutex<UndoLog>>>>,
+    logs: Vec<Arc<Mutex<UndoLog>>>,
+}
 
-    fn clone(&self) -> Self {
-        unimplemented!()
+impl SledKvsEngine {
+    fn undo(&self) -> Undo<'_> {
+        let guard = self.snapshots.read().unwrap();
+        let logs = guard.iter().filter_map(Weak::upgrade).collect();
+        Undo{_guard: guard, logs}
     }
 }
 
+impl Undo<'_> {
+    // Saves what the key is before the transaction writes it into the undo logs that
+    // have nothing for it yet. A transaction that is retried saves the same thing, and a
+    // later write of the key saved the value it replaced before it was committed.
+    fn save(&self, bd: &TransactionalTree, ttl: &TransactionalTree, key: &[u8]) -> TxResult<()> {
+        if self.logs.is_empty() {
+            return Ok(());
+        }
+        let expires_at = ttl.get(key)?.map(|expires_at| decode_expiry(&expires_at));
+        let before = bd.get(key)?.map(|value| (value, expires_at));
+        for log in &self.logs {
+            log.lock().unwrap().entry(key.to_vec()).or_insert_with(|| before.clone());
+        }
+        Ok(())
+    }
+}
+
+fn is_expired(expires_at: Option<IVec>, now: u64) -> bool {
+    matches!(expires_at.map(|expires_at| decode_expiry(&expires_at)), Some(expires_at) if expires_at <= now)
+}
+
+fn decode_expiry(expires_at: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(expires_at);
+    u64::from_be_bytes(bytes)
+}
+
 impl KvsEngine for SledKvsEngine {
+    type Snapshot = SledSnapshot;
 
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.bd.insert(key.into_bytes(), value.into_bytes())?;
-        self.bd.flush()?;
-        Ok(())
+    /// sled 0.34 has no point-in-time view of a tree, so from the time a snapshot is
+    /// taken every write saves what it replaces in the undo log of the snapshot. Taking
+    /// one copies nothing, it only waits for the writes in progress.
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let undo = Arc::new(Mutex::new(UndoLog::new()));
+        let mut snapshots = self.snapshots.write().unwrap();
+        snapshots.retain(|log| log.strong_count() > 0);
+        snapshots.push(Arc::downgrade(&undo));
+        Ok(SledSnapshot{bd: self.bd.clone(), ttl: self.ttl.clone(), undo, taken_at: now_millis()})
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let undo = self.undo();
+        (&*self.bd, &self.ttl).transaction(|(bd, ttl)| -> TxResult<()> {
+            undo.save(bd, ttl, &key)?;
+            bd.insert(&key[..], &value[..])?;
+            ttl.remove(&key[..])?;
+            Ok(())
+        })?;
+        self.sync_write()
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_millis(ttl).to_be_bytes();
+        let undo = self.undo();
+        (&*self.bd, &self.ttl).transaction(|(bd, ttl)| -> TxResult<()> {
+            undo.save(bd, ttl, &key)?;
+            bd.insert(&key[..], &value[..])?;
+            ttl.insert(&key[..], &expires_at[..])?;
+            Ok(())
+        })?;
+        self.sync_write()
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        if self.bd.get(&key)?.is_none() {
+            return Err(KvStoreError::KeyNotFound);
+        }
+        match self.ttl.get(&key)?.map(|expires_at| decode_expiry(&expires_at)) {
+            Some(expires_at) if expires_at <= now => Err(KvStoreError::KeyNotFound),
+            Some(expires_at) => Ok(Some(Duration::from_millis(expires_at - now))),
+            None => Ok(None),
+        }
+    }
+
+    fn remove_expired(&self) -> Result<usize> {
+        let now = now_millis();
+        let mut removed = 0;
+        for entry in self.ttl.iter() {
+            let (key, expires_at) = entry?;
+            if decode_expiry(&expires_at) > now {
+                continue;
+            }
+            // the key may have been written again since it was listed.
+            let undo = self.undo();
+            let expired = (&*self.bd, &self.ttl).transaction(|(bd, ttl)| -> TxResult<bool> {
+                if !is_expired(ttl.get(&key)?, now) {
+                    return Ok(false);
+                }
+                undo.save(bd, ttl, &key)?;
+                bd.remove(&key)?;
+                ttl.remove(&key)?;
+                Ok(true)
+            })?;
+            if expired {
+                removed += 1;
+            }
+        }
+        Ok(removed)
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(self.bd.get(key.into_bytes())?
-            .map(|i_vec| AsRef::<[u8]>::as_ref(&i_vec).to_vec())
-            .map(String::from_utf8)
-            .transpose()?)
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = match self.bd.get(&key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if is_expired(self.ttl.get(&key)?, now_millis()) {
+            return Ok(None);
+        }
+        Ok(Some(value.to_vec()))
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.bd.remove(key.to_owned())?.ok_or(KvStoreError::KeyNotFound)?;
+    /// sled does not track versions, the values read are compared again inside the
+    /// transaction applying the batch.
+    fn commit_versioned(&self, reads: Vec<(Vec<u8>, ReadVersion)>, batch: WriteBatch) -> Result<()> {
+        let now = now_millis();
+        let ops = batch.into_ops();
+        let undo = self.undo();
+        let committed = (&*self.bd, &self.ttl).transaction(|(bd, ttl)| -> TxResult<bool> {
+            for (key, read_version) in &reads {
+                let mut current = bd.get(&key[..])?;
+                if is_expired(ttl.get(&key[..])?, now) {
+                    current = None;
+                }
+                let unchanged = match read_version {
+                    ReadVersion::Value(value) => current.as_deref() == value.as_deref(),
+                    ReadVersion::Version(_) => false,
+                };
+                if !unchanged {
+                    return Ok(false);
+                }
+            }
+            for op in &ops {
+                let (BatchOp::Set{key, ..} | BatchOp::Remove(key)) = op;
+                undo.save(bd, ttl, key)?;
+            }
+            for op in &ops {
+                match op {
+                    BatchOp::Set{key, value} => {
+                        bd.insert(&key[..], &value[..])?;
+                        ttl.remove(&key[..])?;
+                    }
+                    BatchOp::Remove(key) => {
+                        bd.remove(&key[..])?;
+                        ttl.remove(&key[..])?;
+                    }
+                }
+            }
+            Ok(true)
+        })?;
+        if !committed {
+            return Err(KvStoreError::TransactionConflict);
+        }
+        self.sync_write()
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let now = now_millis();
+        let undo = self.undo();
+        let removed = (&*self.bd, &self.ttl).transaction(|(bd, ttl)| -> TxResult<bool> {
+            undo.save(bd, ttl, &key)?;
+            let value = bd.remove(&key[..])?;
+            let expires_at = ttl.remove(&key[..])?;
+            Ok(value.is_some() && !is_expired(expires_at, now))
+        })?;
+        if !removed {
+            return Err(KvStoreError::KeyNotFound);
+        }
+        self.sync_write()
+    }
+
+    fn flush(&self) -> Result<()> {
         self.bd.flush()?;
         Ok(())
     }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut sled_batch = sled::Batch::default();
+        let mut ttl_batch = sled::Batch::default();
+        let mut keys = Vec::new();
+        for op in batch.into_ops() {
+            let (BatchOp::Set{key, ..} | BatchOp::Remove(key)) = &op;
+            keys.push(key.clone());
+            match op {
+                BatchOp::Set{key, value} => {
+                    ttl_batch.remove(&key[..]);
+                    sled_batch.insert(key, value);
+                }
+                BatchOp::Remove(key) => {
+                    ttl_batch.remove(&key[..]);
+                    sled_batch.remove(key);
+                }
+            }
+        }
+        let undo = self.undo();
+        (&*self.bd, &self.ttl).transaction(|(bd, ttl)| -> TxResult<()> {
+            for key in &keys {
+                undo.save(bd, ttl, key)?;
+            }
+            bd.apply_batch(&sled_batch)?;
+            ttl.apply_batch(&ttl_batch)?;
+            Ok(())
+        })?;
+        self.sync_write()
+    }
+
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<bool> {
+        // an expired key counts as missing, so the comparison reads both trees in a transaction
+        // rather than using the compare-and-swap of sled.
+        let now = now_millis();
+        let undo = self.undo();
+        let swapped = (&*self.bd, &self.ttl).transaction(|(bd, ttl)| -> TxResult<bool> {
+            let mut current = bd.get(&key[..])?;
+            if is_expired(ttl.get(&key[..])?, now) {
+                current = None;
+            }
+            if current.as_deref() != expected.as_deref() {
+                return Ok(false);
+            }
+            undo.save(bd, ttl, &key)?;
+            match &new {
+                Some(value) => bd.insert(&key[..], &value[..])?,
+                None => bd.remove(&key[..])?,
+            };
+            ttl.remove(&key[..])?;
+            Ok(true)
+        })?;
+        if swapped {
+            self.sync_write()?;
+        }
+        Ok(swapped)
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<KvPairs> {
+        if super::is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let iter = self.bd.range(range);
+        let iter: Box<dyn Iterator<Item = sled::Result<(sled::IVec, sled::IVec)>> + Send> = if options.reverse {
+            Box::new(iter.rev())
+        } else {
+            Box::new(iter)
+        };
+        let ttl = self.ttl.clone();
+        let now = now_millis();
+        Ok(Box::new(iter
+            .filter_map(move |pair| {
+                let pair = pair.and_then(|(key, value)| Ok((is_expired(ttl.get(&key)?, now), key, value)));
+                match pair {
+                    Ok((true, _, _)) => None,
+                    Ok((false, key, value)) => Some(Ok((key.to_vec(), value.to_vec()))),
+                    Err(err) => Some(Err(err.into())),
+                }
+            })
+            .take(options.limit.unwrap_or(usize::MAX))))
+    }
+}
+
+/// A read-only view of a `SledKvsEngine`, see `KvsEngine::snapshot`.
+///
+/// Reads go to the live trees, the keys written since the snapshot was taken are read
+/// from its undo log instead. Clones share the same view.
+#[derive(Clone)]
+pub struct SledSnapshot {
+    bd: Db,
+    ttl: Tree,
+    undo: Arc<Mutex<UndoLog>>,
+    // keys expire as of the time the snapshot was taken.
+    taken_at: u64,
+}
+
+impl std::fmt::Debug for SledSnapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SledSnapshot").field("taken_at", &self.taken_at).finish()
+    }
+}
+
+impl SledSnapshot {
+    // The value of the key as of the snapshot, given its live value and expiry time.
+    //
+    // The undo log is looked up after the live trees were read: a live value written
+    // after the snapshot was taken had its predecessor saved before it was committed.
+    fn visible(&self, key: &[u8], live: Option<(IVec, Option<u64>)>) -> Option<IVec> {
+        let entry = match self.undo.lock().unwrap().get(key) {
+            Some(before) => before.clone(),
+            None => live,
+        };
+        match entry {
+            Some((_, Some(expires_at))) if expires_at <= self.taken_at => None,
+            Some((value, _)) => Some(value),
+            None => None,
+        }
+    }
+
+    fn live(&self, key: &[u8], value: Option<IVec>) -> Result<Option<(IVec, Option<u64>)>> {
+        let expires_at = self.ttl.get(key)?.map(|expires_at| decode_expiry(&expires_at));
+        Ok(value.map(|value| (value, expires_at)))
+    }
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let live = self.live(&key, self.bd.get(&key)?)?;
+        Ok(self.visible(&key, live).map(|value| value.to_vec()))
+    }
+
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, ReadVersion)> {
+        let value = self.get_bytes(key)?;
+        Ok((value.clone(), ReadVersion::Value(value)))
+    }
+
+    /// the live pairs of the range are read first, then merged with the undo log, so
+    /// the pairs are collected before the first one is returned.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, options: ScanOptions) -> Result<KvPairs> {
+        if super::is_empty_range(&range) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let bounds = (range.start_bound().cloned(), range.end_bound().cloned());
+        let mut live = BTreeMap::new();
+        for pair in self.bd.range(bounds.clone()) {
+            let (key, value) = pair?;
+            let entry = self.live(&key, Some(value))?;
+            live.insert(key.to_vec(), entry);
+        }
+        // a key removed since the snapshot was taken is only in the undo log.
+        let removed: Vec<Vec<u8>> = self.undo.lock().unwrap().range(bounds).map(|(key, _)| key.clone()).collect();
+        for key in removed {
+            live.entry(key).or_insert(None);
+        }
+        let pairs = live.into_iter().filter_map(|(key, entry)| self.visible(&key, entry).map(|value| Ok((key, value.to_vec()))));
+        let limit = options.limit.unwrap_or(usize::MAX);
+        let pairs: Vec<_> = if options.reverse {
+            pairs.rev().take(limit).collect()
+        } else {
+            pairs.take(limit).collect()
+        };
+        Ok(Box::new(pairs.into_iter()))
+    }
 }
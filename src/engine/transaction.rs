@@ -0,0 +1,103 @@
+use super::{KvsEngine, KvsSnapshot, WriteBatch};
+use crate::Result;
+use std::collections::BTreeMap;
+
+/// What a transaction saw of a key, checked again when it commits.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadVersion {
+    /// Version of the key in an engine that tracks them, 0 when the key did not exist.
+    Version(u64),
+    /// Value of the key in an engine that does not track versions.
+    Value(Option<Vec<u8>>),
+}
+
+/// A read-modify-write over several keys, with snapshot isolation.
+///
+/// `begin` takes a snapshot of the engine and every `get` reads through it, so the
+/// transaction sees the engine as it was at `begin` plus its own writes. Writes are
+/// buffered until `commit`, which applies all of them atomically only if none of the
+/// keys the transaction read or wrote changed since the snapshot was taken: of two
+/// transactions writing the same key, the first one to commit wins. Otherwise it fails
+/// with `KvStoreError::TransactionConflict` and nothing is written, the transaction
+/// can then be retried from the start.
+///
+/// Validating the keys read as well also rejects the write skew plain snapshot
+/// isolation lets through.
+///
+/// ```
+/// # use kvs::{KvStore, KvsEngine, Result};
+/// # fn main() -> Result<()> {
+/// # let dir = tempfile::TempDir::new()?;
+/// let store = KvStore::open(dir.path())?;
+/// store.set("alice".to_owned(), "10".to_owned())?;
+/// let mut txn = store.begin()?;
+/// let alice = txn.get(b"alice".to_vec())?.unwrap_or_default();
+/// txn.set(b"alice".to_vec(), b"5".to_vec());
+/// txn.set(b"bob".to_vec(), alice);
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    snapshot: E::Snapshot,
+    reads: BTreeMap<Vec<u8>, (Option<Vec<u8>>, ReadVersion)>,
+    // `None` removes the key.
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(crate) fn new(engine: E) -> Result<Transaction<E>> {
+        let snapshot = engine.snapshot()?;
+        Ok(Transaction { engine, snapshot, reads: BTreeMap::new(), writes: BTreeMap::new() })
+    }
+
+    /// Returns the value of the key as seen by the transaction, including its own writes.
+    pub fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        if let Some((value, _)) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.snapshot.get_versioned(key.clone())?;
+        self.reads.insert(key, (value.clone(), version));
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes the key, removing a key that does not exist is not an error.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    // The writes of the transaction, `None` for a removed key.
+    pub(crate) fn changes(&self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.writes.iter().map(|(key, value)| (key.clone(), value.clone())).collect()
+    }
+
+    /// Applies the writes of the transaction if none of the keys it read or wrote
+    /// changed since `begin`.
+    pub fn commit(self) -> Result<()> {
+        let mut versions = Vec::new();
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            if !self.reads.contains_key(&key) {
+                let (_, version) = self.snapshot.get_versioned(key.clone())?;
+                versions.push((key.clone(), version));
+            }
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        versions.extend(self.reads.into_iter().map(|(key, (_, version))| (key, version)));
+        self.engine.commit_versioned(versions, batch)
+    }
+
+    /// Drops the writes of the transaction.
+    pub fn rollback(self) {}
+}
@@ -1,6 +1,5 @@
 use std::result;
 use std::fmt;
-use sled;
 
 pub enum KvStoreError{
     SerdeIo(serde_json::Error),
@@ -11,6 +10,15 @@ pub enum KvStoreError{
     StringUtf8Error(std::string::FromUtf8Error),
     EngineError,
     AddrParseError(std::net::AddrParseError),
+    InvalidRecord(String),
+    CorruptedLog{log_id: u64, offset: u64, reason: String},
+    InvalidDurability(String),
+    InvalidQueuePolicy(String),
+    TransactionConflict,
+    SignalHandler(ctrlc::Error),
+    QueueFull,
+    Protocol(String),
+    InvalidProtocol(String),
 }
 pub type Result<T> = result::Result<T, KvStoreError>;
 
@@ -40,6 +48,22 @@ impl From<sled::Error> for KvStoreError {
 
 }
 
+impl From<sled::transaction::TransactionError> for KvStoreError {
+    fn from(err: sled::transaction::TransactionError) -> Self {
+        match err {
+            sled::transaction::TransactionError::Abort(err) | sled::transaction::TransactionError::Storage(err) => {
+                KvStoreError::SledError(err)
+            }
+        }
+    }
+}
+
+impl From<ctrlc::Error> for KvStoreError {
+    fn from(err: ctrlc::Error) -> Self {
+        KvStoreError::SignalHandler(err)
+    }
+}
+
 impl From<std::string::FromUtf8Error> for KvStoreError {
     fn from(err: std::string::FromUtf8Error) -> Self {
         KvStoreError::StringUtf8Error(err)
@@ -49,7 +73,7 @@ impl From<std::string::FromUtf8Error> for KvStoreError {
 
 impl fmt::Debug for KvStoreError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match &*self {
+        match self {
             KvStoreError::SerdeIo(ref err) => err.fmt(f),
             KvStoreError::Io(ref err) => err.fmt(f),
             KvStoreError::KeyNotFound => write!(f, "Key not found"),
@@ -58,13 +82,24 @@ impl fmt::Debug for KvStoreError {
             KvStoreError::StringUtf8Error(ref err) => err.fmt(f),
             KvStoreError::EngineError => write!(f, "Already created an engine conf"),
             KvStoreError::AddrParseError(ref err) => err.fmt(f),
+            KvStoreError::InvalidRecord(ref reason) => write!(f, "Invalid log record: {}", reason),
+            KvStoreError::CorruptedLog{log_id, offset, ref reason} => {
+                write!(f, "Corrupted record in {}.log at byte offset {}: {}", log_id, offset, reason)
+            }
+            KvStoreError::InvalidDurability(ref mode) => write!(f, "Invalid durability mode: {}", mode),
+            KvStoreError::InvalidQueuePolicy(ref policy) => write!(f, "Invalid queue policy: {}", policy),
+            KvStoreError::TransactionConflict => write!(f, "Transaction conflict"),
+            KvStoreError::SignalHandler(ref err) => write!(f, "Failed to set signal handler: {}", err),
+            KvStoreError::QueueFull => write!(f, "Job queue is full"),
+            KvStoreError::Protocol(ref reason) => write!(f, "Protocol error: {}", reason),
+            KvStoreError::InvalidProtocol(ref protocol) => write!(f, "Invalid protocol: {}", protocol),
         }
     }
    
 }
 impl fmt::Display for KvStoreError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &*self {
+        match self {
             KvStoreError::SerdeIo(ref err) => err.fmt(f),
             KvStoreError::Io(ref err) => err.fmt(f),
             KvStoreError::KeyNotFound => write!(f, "Key not found"),
@@ -73,6 +108,17 @@ impl fmt::Display for KvStoreError {
             KvStoreError::StringUtf8Error(ref err) => err.fmt(f),
             KvStoreError::EngineError => write!(f, "Already created an engine conf"),
             KvStoreError::AddrParseError(ref err) => err.fmt(f),
+            KvStoreError::InvalidRecord(ref reason) => write!(f, "Invalid log record: {}", reason),
+            KvStoreError::CorruptedLog{log_id, offset, ref reason} => {
+                write!(f, "Corrupted record in {}.log at byte offset {}: {}", log_id, offset, reason)
+            }
+            KvStoreError::InvalidDurability(ref mode) => write!(f, "Invalid durability mode: {}", mode),
+            KvStoreError::InvalidQueuePolicy(ref policy) => write!(f, "Invalid queue policy: {}", policy),
+            KvStoreError::TransactionConflict => write!(f, "Transaction conflict"),
+            KvStoreError::SignalHandler(ref err) => write!(f, "Failed to set signal handler: {}", err),
+            KvStoreError::QueueFull => write!(f, "Job queue is full"),
+            KvStoreError::Protocol(ref reason) => write!(f, "Protocol error: {}", reason),
+            KvStoreError::InvalidProtocol(ref protocol) => write!(f, "Invalid protocol: {}", protocol),
         }
     }
 }
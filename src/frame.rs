@@ -0,0 +1,323 @@
+//! The framed protocol. Every message is a frame
+//!
+//! ```text
+//! length: u32 | request id: u64 | opcode: u8 | payload
+//! ```
+//!
+//! with the integers in big endian and `length` counting the bytes after it. The
+//! payload of a request holds its fields in bincode, a response echoes the id of the
+//! request it answers and holds a `helper::Response` in bincode. Keys and values are
+//! written as a little endian u64 length followed by their bytes.
+//!
+//! A client opens the connection with a `Hello` frame, the server answers with the
+//! version of the protocol both sides speak. The hello and its answer are in JSON,
+//! so a client of any version can read why it was refused. A connection whose first
+//! byte starts a JSON value speaks the legacy JSON protocol instead.
+
+use super::helper::{Hello, Request, Response};
+use super::Result;
+use crate::KvStoreError;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+
+/// The version of the framed protocol this crate speaks. Version 1 wrote the payloads
+/// in JSON, with keys and values as arrays of numbers.
+pub(crate) const PROTOCOL_VERSION: u16 = 2;
+
+// the bytes of a frame after its length: the request id and the opcode.
+const HEADER_LEN: usize = 9;
+// larger frames are refused, the length of a garbled frame could be anything.
+const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Opcode {
+    Hello = 0,
+    Get = 1,
+    Set = 2,
+    SetWithTtl = 3,
+    Ttl = 4,
+    Rm = 5,
+    Begin = 6,
+    Commit = 7,
+    Abort = 8,
+    Batch = 9,
+    Cas = 10,
+    Scan = 11,
+    Watch = 12,
+    Stats = 13,
+    Response = 0x80,
+}
+
+impl Opcode {
+    fn from_u8(byte: u8) -> Option<Opcode> {
+        let opcode = match byte {
+            0 => Opcode::Hello,
+            1 => Opcode::Get,
+            2 => Opcode::Set,
+            3 => Opcode::SetWithTtl,
+            4 => Opcode::Ttl,
+            5 => Opcode::Rm,
+            6 => Opcode::Begin,
+            7 => Opcode::Commit,
+            8 => Opcode::Abort,
+            9 => Opcode::Batch,
+            10 => Opcode::Cas,
+            11 => Opcode::Scan,
+            12 => Opcode::Watch,
+            13 => Opcode::Stats,
+            0x80 => Opcode::Response,
+            _ => return None,
+        };
+        Some(opcode)
+    }
+}
+
+impl Request {
+    pub(crate) fn opcode(&self) -> Opcode {
+        match self {
+            Request::Set { .. } => Opcode::Set,
+            Request::SetWithTtl { .. } => Opcode::SetWithTtl,
+            Request::Ttl(_) => Opcode::Ttl,
+            Request::Rm(_) => Opcode::Rm,
+            Request::Get(_) => Opcode::Get,
+            Request::Begin => Opcode::Begin,
+            Request::Commit => Opcode::Commit,
+            Request::Abort => Opcode::Abort,
+            Request::Batch(_) => Opcode::Batch,
+            Request::Cas { .. } => Opcode::Cas,
+            Request::Scan { .. } => Opcode::Scan,
+            Request::Watch { .. } => Opcode::Watch,
+            Request::Stats => Opcode::Stats,
+        }
+    }
+}
+
+/// A frame whose opcode is known, see the module documentation.
+#[derive(Debug)]
+pub(crate) struct Frame {
+    pub(crate) id: u64,
+    pub(crate) opcode: Opcode,
+    pub(crate) payload: Vec<u8>,
+}
+
+/// What reading a frame gave.
+#[derive(Debug)]
+pub(crate) enum Incoming {
+    Frame(Frame),
+    /// a frame with an opcode this version does not know, it was skipped.
+    Unknown { id: u64, opcode: u8 },
+    /// the peer closed the connection between two frames.
+    Closed,
+}
+
+impl Incoming {
+    /// The answer to the first frame of a connection, which must be a hello, with the
+    /// id to answer. `None` when the peer closed the connection.
+    pub(crate) fn into_hello_reply(self) -> Option<(u64, Response)> {
+        match self {
+            Incoming::Frame(frame) => {
+                let response = match frame.to_hello() {
+                    Ok(hello) => match negotiate(&hello) {
+                        Ok(version) => Response::Hello { version },
+                        Err(err) => Response::Err(err),
+                    },
+                    Err(err) => Response::Err(err.to_string()),
+                };
+                Some((frame.id, response))
+            }
+            Incoming::Unknown { id, opcode } => Some((id, Response::Err(format!("Expected a hello, got opcode {}", opcode)))),
+            Incoming::Closed => None,
+        }
+    }
+
+    /// The request of the frame with its id, or the error to answer it with. `None`
+    /// when the peer closed the connection.
+    pub(crate) fn into_request(self) -> Option<(u64, std::result::Result<Request, Response>)> {
+        match self {
+            Incoming::Frame(frame) => Some((frame.id, frame.to_request().map_err(|err| Response::Err(err.to_string())))),
+            Incoming::Unknown { id, opcode } => Some((id, Err(Response::Err(format!("Unknown opcode {}", opcode))))),
+            Incoming::Closed => None,
+        }
+    }
+}
+
+impl Frame {
+    pub(crate) fn hello(id: u64) -> Result<Frame> {
+        let hello = Hello { min_version: PROTOCOL_VERSION, max_version: PROTOCOL_VERSION };
+        Ok(Frame { id, opcode: Opcode::Hello, payload: serde_json::to_vec(&hello)? })
+    }
+
+    pub(crate) fn request(id: u64, req: &Request) -> Result<Frame> {
+        let payload = match req {
+            Request::Set { key, value } => encode(&(key, value))?,
+            Request::SetWithTtl { key, value, ttl } => encode(&(key, value, ttl))?,
+            Request::Ttl(key) | Request::Rm(key) | Request::Get(key) => encode(key)?,
+            Request::Begin | Request::Commit | Request::Abort | Request::Stats => Vec::new(),
+            Request::Batch(batch) => encode(batch)?,
+            Request::Cas { key, expected, new } => encode(&(key, expected, new))?,
+            Request::Scan { start, end, options } => encode(&(start, end, options))?,
+            Request::Watch { key_or_prefix } => encode(key_or_prefix)?,
+        };
+        Ok(Frame { id, opcode: req.opcode(), payload })
+    }
+
+    pub(crate) fn response(id: u64, response: &Response) -> Result<Frame> {
+        Ok(Frame { id, opcode: Opcode::Response, payload: encode(response)? })
+    }
+
+    /// The answer to a hello, `Response::Hello` or the error the connection closes with.
+    pub(crate) fn hello_reply(id: u64, response: &Response) -> Result<Frame> {
+        Ok(Frame { id, opcode: Opcode::Response, payload: serde_json::to_vec(response)? })
+    }
+
+    pub(crate) fn to_hello(&self) -> Result<Hello> {
+        match self.opcode {
+            Opcode::Hello => Ok(serde_json::from_slice(&self.payload)?),
+            opcode => Err(KvStoreError::Protocol(format!("expected a hello, got {:?}", opcode))),
+        }
+    }
+
+    pub(crate) fn to_request(&self) -> Result<Request> {
+        let payload = &self.payload;
+        let req = match self.opcode {
+            Opcode::Set => {
+                let (key, value) = decode(payload)?;
+                Request::Set { key, value }
+            }
+            Opcode::SetWithTtl => {
+                let (key, value, ttl) = decode(payload)?;
+                Request::SetWithTtl { key, value, ttl }
+            }
+            Opcode::Ttl => Request::Ttl(decode(payload)?),
+            Opcode::Rm => Request::Rm(decode(payload)?),
+            Opcode::Get => Request::Get(decode(payload)?),
+            Opcode::Begin => Request::Begin,
+            Opcode::Commit => Request::Commit,
+            Opcode::Abort => Request::Abort,
+            Opcode::Batch => Request::Batch(decode(payload)?),
+            Opcode::Cas => {
+                let (key, expected, new) = decode(payload)?;
+                Request::Cas { key, expected, new }
+            }
+            Opcode::Scan => {
+                let (start, end, options) = decode(payload)?;
+                Request::Scan { start, end, options }
+            }
+            Opcode::Watch => Request::Watch { key_or_prefix: decode(payload)? },
+            Opcode::Stats => Request::Stats,
+            opcode => return Err(KvStoreError::Protocol(format!("{:?} is not a request", opcode))),
+        };
+        Ok(req)
+    }
+
+    pub(crate) fn to_response(&self) -> Result<Response> {
+        match self.opcode {
+            Opcode::Response => decode(&self.payload),
+            opcode => Err(KvStoreError::Protocol(format!("{:?} is not a response", opcode))),
+        }
+    }
+
+    pub(crate) fn to_hello_reply(&self) -> Result<Response> {
+        match self.opcode {
+            Opcode::Response => Ok(serde_json::from_slice(&self.payload)?),
+            opcode => Err(KvStoreError::Protocol(format!("{:?} is not a response", opcode))),
+        }
+    }
+
+    /// The whole frame, length included.
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let len = HEADER_LEN + self.payload.len();
+        let mut bytes = Vec::with_capacity(4 + len);
+        bytes.extend_from_slice(&(len as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.id.to_be_bytes());
+        bytes.push(self.opcode as u8);
+        bytes.extend_from_slice(&self.payload);
+        bytes
+    }
+
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.to_bytes())?;
+        Ok(())
+    }
+
+    pub(crate) fn read_from<R: io::Read>(reader: &mut R) -> Result<Incoming> {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(Incoming::Closed),
+            Err(err) => return Err(err.into()),
+        }
+        let len = frame_len(len)?;
+        // the body grows as it arrives, a length alone does not allocate.
+        let mut body = Vec::new();
+        reader.take(len as u64).read_to_end(&mut body)?;
+        if body.len() < len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed inside a frame").into());
+        }
+        Ok(Frame::from_body(body))
+    }
+
+    #[cfg(feature = "async")]
+    /// Takes the first frame out of `buffer`, `None` when it does not hold a whole
+    /// frame yet.
+    pub(crate) fn parse(buffer: &mut Vec<u8>) -> Result<Option<Incoming>> {
+        if buffer.len() < 4 {
+            return Ok(None);
+        }
+        let len = frame_len([buffer[0], buffer[1], buffer[2], buffer[3]])?;
+        if buffer.len() < 4 + len {
+            return Ok(None);
+        }
+        let body = buffer[4..4 + len].to_vec();
+        buffer.drain(..4 + len);
+        Ok(Some(Frame::from_body(body)))
+    }
+
+    fn from_body(mut body: Vec<u8>) -> Incoming {
+        let mut id = [0; 8];
+        id.copy_from_slice(&body[..8]);
+        let id = u64::from_be_bytes(id);
+        let opcode = body[8];
+        let payload = body.split_off(HEADER_LEN);
+        match Opcode::from_u8(opcode) {
+            Some(opcode) => Incoming::Frame(Frame { id, opcode, payload }),
+            None => Incoming::Unknown { id, opcode },
+        }
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+    bincode::serialize(value).map_err(|err| KvStoreError::Protocol(format!("unable to encode payload: {}", err)))
+}
+
+fn decode<'a, T: Deserialize<'a>>(payload: &'a [u8]) -> Result<T> {
+    bincode::deserialize(payload).map_err(|err| KvStoreError::Protocol(format!("invalid payload: {}", err)))
+}
+
+fn frame_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len) as usize;
+    if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+        return Err(KvStoreError::Protocol(format!("invalid frame length {}", len)));
+    }
+    Ok(len)
+}
+
+/// Whether a connection starting with `byte` speaks the legacy JSON protocol, whose
+/// requests are JSON objects or strings. A frame starts with the high byte of its
+/// length, which is 0 for a hello.
+pub(crate) fn is_legacy(byte: u8) -> bool {
+    matches!(byte, b'{' | b'"' | b' ' | b'\t' | b'\n' | b'\r')
+}
+
+// Answers the hello of a client, returns the version to speak or the error to
+// send before closing the connection.
+fn negotiate(hello: &Hello) -> std::result::Result<u16, String> {
+    if hello.min_version <= PROTOCOL_VERSION && PROTOCOL_VERSION <= hello.max_version {
+        Ok(PROTOCOL_VERSION)
+    } else {
+        Err(format!(
+            "Unsupported protocol versions {}..={}, the server speaks {}",
+            hello.min_version, hello.max_version, PROTOCOL_VERSION
+        ))
+    }
+}
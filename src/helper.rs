@@ -1,26 +1,370 @@
+use crate::engine::{ScanOptions, WriteBatch};
+use crate::frame::{Frame, Opcode};
+use crate::KvStoreError;
+use crate::thread_pool::PoolStats;
+use crate::watch::WatchEvent;
 use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::ops::Bound;
+use std::time::Duration;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request{
-    Set{key: String, value: String},
-    Rm(String),
-    Get(String)
+    Set{#[serde(with = "json_bytes")] key: Vec<u8>, #[serde(with = "json_bytes")] value: Vec<u8>},
+    SetWithTtl{#[serde(with = "json_bytes")] key: Vec<u8>, #[serde(with = "json_bytes")] value: Vec<u8>, ttl: Duration},
+    Ttl(#[serde(with = "json_bytes")] Vec<u8>),
+    Rm(#[serde(with = "json_bytes")] Vec<u8>),
+    Get(#[serde(with = "json_bytes")] Vec<u8>),
+    /// Starts a transaction on the connection, the `Get`, `Set` and `Rm` requests
+    /// that follow go through it until `Commit` or `Abort`.
+    Begin,
+    Commit,
+    Abort,
+    Batch(WriteBatch),
+    Cas{
+        #[serde(with = "json_bytes")] key: Vec<u8>,
+        #[serde(with = "json_bytes::option")] expected: Option<Vec<u8>>,
+        #[serde(with = "json_bytes::option")] new: Option<Vec<u8>>,
+    },
+    Scan{start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, options: ScanOptions},
+    /// Streams the changes of the keys starting with `key_or_prefix`, the connection
+    /// takes no other request afterwards.
+    Watch{#[serde(with = "json_bytes")] key_or_prefix: Vec<u8>},
+    /// Reports the load of the thread pool of the server.
+    Stats
 }
 
+/// Answers `Set`, `SetWithTtl`, `Rm` and `Batch`.
 #[derive(Debug, Serialize, Deserialize)]
 pub enum SetResponse{
     Ok(()),
     Err(String)
 }
 
+/// `Ok(false)` when the current value did not match the expected one.
 #[derive(Debug, Serialize, Deserialize)]
-pub enum RmResponse{
+pub enum CasResponse{
+    Ok(bool),
+    Err(String)
+}
+
+/// `Ok(None)` when the key never expires.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TtlResponse{
+    Ok(Option<Duration>),
+    KeyNotFound,
+    Err(String)
+}
+
+/// Answers `Begin`, `Commit` and `Abort`, a commit that lost a race with another
+/// write is answered with `Conflict`.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TransactionResponse{
     Ok(()),
+    Conflict,
     Err(String)
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum GetResponse{
-    Ok(Option<String>),
+    Ok(#[serde(with = "json_bytes::option")] Option<Vec<u8>>),
+    Err(String)
+}
+
+/// A scan is answered with one `Item` per pair followed by `End`, or by `Err`
+/// when it fails.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ScanResponse{
+    Item(Vec<u8>, Vec<u8>),
+    End,
+    Err(String)
+}
+
+/// A watch is answered with `Subscribed` once the changes are recorded, then with
+/// an `Event` per change.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WatchResponse{
+    Subscribed,
+    Event(WatchEvent),
+    Err(String)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StatsResponse{
+    Ok(PoolStats),
+    Err(String)
+}
+
+/// Opens a framed connection, the server answers with `Response::Hello` and the
+/// highest version both sides speak.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hello{
+    pub min_version: u16,
+    pub max_version: u16,
+}
+
+/// The answer to any request of the framed protocol.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Response{
+    Hello{version: u16},
+    /// `Set`, `SetWithTtl`, `Rm`, `Batch` and the transaction requests succeeded,
+    /// also ends the `Item`s of a scan.
+    Done,
+    Value(Option<Vec<u8>>),
+    /// whether a `Cas` swapped the value.
+    Swapped(bool),
+    /// the time left before the key expires, `None` when it never does.
+    Ttl(Option<Duration>),
+    Item(Vec<u8>, Vec<u8>),
+    Subscribed,
+    Event(WatchEvent),
+    Stats(PoolStats),
+    KeyNotFound,
+    /// a commit lost a race with another write.
+    Conflict,
+    /// the server has no room for the connection.
+    Busy,
+    Err(String),
+}
+
+impl From<KvStoreError> for Response {
+    fn from(err: KvStoreError) -> Response {
+        match err {
+            KvStoreError::KeyNotFound => Response::KeyNotFound,
+            KvStoreError::TransactionConflict => Response::Conflict,
+            err => Response::Err(err.to_string()),
+        }
+    }
+}
+
+impl Response {
+    /// The error a client returns for the response, `None` when it is not an error.
+    pub(crate) fn error(&self) -> Option<KvStoreError> {
+        match self {
+            Response::KeyNotFound => Some(KvStoreError::KeyNotFound),
+            Response::Conflict => Some(KvStoreError::TransactionConflict),
+            Response::Busy => Some(KvStoreError::ServerResponseErr("Server busy".to_owned())),
+            Response::Err(err) => Some(KvStoreError::ServerResponseErr(err.clone())),
+            _ => None,
+        }
+    }
+
+    /// Fails with the error of the response, or with `KvStoreError::Protocol` when it
+    /// does not answer the request.
+    pub(crate) fn unexpected<T>(self) -> crate::Result<T> {
+        match self.error() {
+            Some(err) => Err(err),
+            None => Err(KvStoreError::Protocol(format!("unexpected response {:?}", self))),
+        }
+    }
+}
+
+/// Where the response to a request goes: in JSON for the legacy protocol, in a
+/// frame with the id of the request otherwise.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ReplyTo{
+    Json(Opcode),
+    Frame(u64),
+}
+
+impl ReplyTo {
+    pub(crate) fn write<W: Write>(self, writer: &mut W, response: Response) -> crate::Result<()> {
+        match self {
+            ReplyTo::Json(opcode) => write_legacy(writer, opcode, response),
+            ReplyTo::Frame(id) => Frame::response(id, &response)?.write_to(writer),
+        }
+    }
+}
+
+/// Writes `response` the way the JSON protocol answers a request of `opcode`, the
+/// JSON protocol has a response type per request.
+fn write_legacy<W: Write>(writer: &mut W, opcode: Opcode, response: Response) -> crate::Result<()> {
+    match response {
+        Response::Done if opcode == Opcode::Scan => serde_json::to_writer(writer, &ScanResponse::End)?,
+        // every other `Ok(())` is written the same.
+        Response::Done => serde_json::to_writer(writer, &SetResponse::Ok(()))?,
+        Response::Value(value) => serde_json::to_writer(writer, &GetResponse::Ok(value))?,
+        Response::Swapped(swapped) => serde_json::to_writer(writer, &CasResponse::Ok(swapped))?,
+        Response::Ttl(ttl) => serde_json::to_writer(writer, &TtlResponse::Ok(ttl))?,
+        Response::Item(key, value) => serde_json::to_writer(writer, &ScanResponse::Item(key, value))?,
+        Response::Subscribed => serde_json::to_writer(writer, &WatchResponse::Subscribed)?,
+        Response::Event(event) => serde_json::to_writer(writer, &WatchResponse::Event(event))?,
+        Response::Stats(stats) => serde_json::to_writer(writer, &StatsResponse::Ok(stats))?,
+        Response::KeyNotFound if opcode == Opcode::Ttl => serde_json::to_writer(writer, &TtlResponse::KeyNotFound)?,
+        Response::Conflict => serde_json::to_writer(writer, &TransactionResponse::Conflict)?,
+        response => {
+            let err = match response.error() {
+                Some(err) => err.to_string(),
+                None => format!("unexpected response {:?}", response),
+            };
+            serde_json::to_writer(writer, &ErrorResponse::Err(err))?
+        }
+    }
+    Ok(())
+}
+
+/// Sent instead of any response when the server can not serve a JSON connection,
+/// every response has an `Err` variant so the client reads it as the answer to
+/// whatever it asked.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ErrorResponse{
     Err(String)
 }
+
+/// Answers a JSON request that can not be decoded, the connection is closed after it
+/// as the bytes that follow can not be trusted.
+pub(crate) fn write_invalid_request<W: Write>(writer: &mut W, err: &serde_json::Error) -> crate::Result<()> {
+    serde_json::to_writer(writer, &ErrorResponse::Err(format!("Invalid request: {}", err)))?;
+    Ok(())
+}
+
+/// Keys and values in the JSON protocol: a string when they are UTF-8, as the JSON
+/// protocol sent them before keys and values were bytes, an array of bytes otherwise.
+/// Both forms are read.
+pub(crate) mod json_bytes {
+    use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+    use serde::ser::{Serialize, Serializer};
+    use std::fmt;
+
+    pub(crate) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(bytes) {
+            Ok(s) => serializer.serialize_str(s),
+            Err(_) => serializer.collect_seq(bytes),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a string or an array of bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Vec<u8>, E> {
+            Ok(s.as_bytes().to_vec())
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serialize(self.0, serializer)
+        }
+    }
+
+    struct ByteBuf(Vec<u8>);
+
+    impl<'de> Deserialize<'de> for ByteBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ByteBuf, D::Error> {
+            deserialize(deserializer).map(ByteBuf)
+        }
+    }
+
+    /// The same for an optional key or value, `None` is `null`.
+    pub(crate) mod option {
+        use super::{ByteBuf, Bytes};
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        pub(crate) fn serialize<S: Serializer>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error> {
+            bytes.as_deref().map(Bytes).serialize(serializer)
+        }
+
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<u8>>, D::Error> {
+            Ok(Option::<ByteBuf>::deserialize(deserializer)?.map(|bytes| bytes.0))
+        }
+    }
+}
+
+/// Reads the JSON values or the frames a peer sends on an async stream, a value
+/// may arrive over several reads and a read may hold several values.
+#[cfg(feature = "async")]
+pub(crate) struct AsyncReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+#[cfg(feature = "async")]
+impl<R: tokio::io::AsyncRead + Unpin> AsyncReader<R> {
+    pub(crate) fn new(reader: R) -> AsyncReader<R> {
+        AsyncReader { reader, buffer: Vec::new() }
+    }
+
+    /// Returns `None` once the peer closed the stream. Dropping the future before
+    /// it is ready loses nothing, the bytes read so far stay buffered.
+    pub(crate) async fn next_json<T: serde::de::DeserializeOwned>(&mut self) -> crate::Result<Option<T>> {
+        loop {
+            let mut values = serde_json::Deserializer::from_slice(&self.buffer).into_iter::<T>();
+            match values.next() {
+                Some(Ok(value)) => {
+                    let read = values.byte_offset();
+                    self.buffer.drain(..read);
+                    return Ok(Some(value));
+                }
+                Some(Err(err)) if !err.is_eof() => return Err(err.into()),
+                // an incomplete value, or only whitespace.
+                _ => {}
+            }
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Like `next_json`, for the framed protocol.
+    pub(crate) async fn next_frame(&mut self) -> crate::Result<crate::frame::Incoming> {
+        loop {
+            if let Some(incoming) = Frame::parse(&mut self.buffer)? {
+                return Ok(incoming);
+            }
+            if !self.fill().await? {
+                return Ok(crate::frame::Incoming::Closed);
+            }
+        }
+    }
+
+    /// The first byte the peer sends, left in the buffer. `None` when the peer closed
+    /// the stream without sending anything.
+    pub(crate) async fn peek(&mut self) -> crate::Result<Option<u8>> {
+        while self.buffer.is_empty() {
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+        Ok(Some(self.buffer[0]))
+    }
+
+    /// Discards what the peer sends until it closes the stream.
+    pub(crate) async fn closed(&mut self) -> crate::Result<()> {
+        loop {
+            self.buffer.clear();
+            if !self.fill().await? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Returns false once the peer closed the stream.
+    async fn fill(&mut self) -> crate::Result<bool> {
+        use tokio::io::AsyncReadExt;
+        Ok(self.reader.read_buf(&mut self.buffer).await? > 0)
+    }
+}
@@ -0,0 +1,392 @@
+//! An HTTP/1.1 gateway to the store, served by `KvsServer::run_with_http` for the
+//! clients that can not speak the protocols of the crate.
+//!
+//! ```text
+//! GET    /kv/{key}                       the value, 404 when the key does not exist
+//! PUT    /kv/{key}                       sets the value to the body of the request
+//! DELETE /kv/{key}                       removes the key, 404 when it does not exist
+//! GET    /kv?prefix={prefix}&limit={n}&after={key}
+//!                                        the pairs whose key starts with the prefix
+//! ```
+//!
+//! Keys are percent-decoded, and values are the raw bytes of the bodies. A listing is
+//! a JSON array of `{"key": ..., "value": ...}` objects in key order, of at most
+//! 1000 pairs whatever the limit, starting after the key `after` when it is given.
+//! When the limit cut it short, an `X-Next-After` header has the percent-encoded
+//! `after` of the next page. A key or value that is not UTF-8 is sent in base64 with
+//! `"key_base64": true` or `"value_base64": true`. Errors have a JSON body
+//! `{"error": ...}`.
+
+use crate::engine::{self, KvsEngine, ScanOptions};
+use crate::watch::Watchers;
+use crate::{KvStoreError, Result};
+use serde_json::json;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::ops::Bound;
+
+// longer request lines and headers are refused, a line never ends otherwise.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+// larger bodies are refused before they are read.
+const MAX_BODY_LEN: usize = 64 * 1024 * 1024;
+// a listing has at most this many pairs, the store could not fit in one response.
+const MAX_LIST_LEN: usize = 1000;
+
+struct Request {
+    method: String,
+    target: String,
+    body: Vec<u8>,
+    // the client asked to close the connection after the response.
+    close: bool,
+}
+
+// What reading a request gave.
+enum Incoming {
+    Request(Request),
+    /// a request that can not be served, answered with the status before closing the
+    /// connection as the bytes after it can not be trusted.
+    Invalid(u16, String),
+    Closed,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // the methods of the resource, sent with a 405.
+    allow: Option<&'static str>,
+    // the `after` of the next page of a listing, sent as `X-Next-After`.
+    next_after: Option<String>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: Vec<u8>) -> Response {
+        Response { status, content_type, body, allow: None, next_after: None }
+    }
+
+    fn json(status: u16, value: serde_json::Value) -> Response {
+        Response::new(status, "application/json", value.to_string().into_bytes())
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response::json(status, json!({ "error": message }))
+    }
+
+    fn no_content() -> Response {
+        Response::new(204, "application/octet-stream", Vec::new())
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Response {
+        Response { allow: Some(allow), ..Response::error(405, "Method not allowed") }
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W, close: bool) -> io::Result<()> {
+        write!(writer, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        if self.status != 204 {
+            write!(writer, "Content-Type: {}\r\nContent-Length: {}\r\n", self.content_type, self.body.len())?;
+        }
+        if let Some(allow) = self.allow {
+            write!(writer, "Allow: {}\r\n", allow)?;
+        }
+        if let Some(next_after) = &self.next_after {
+            write!(writer, "X-Next-After: {}\r\n", next_after)?;
+        }
+        if close {
+            writer.write_all(b"Connection: close\r\n")?;
+        }
+        writer.write_all(b"\r\n")?;
+        writer.write_all(&self.body)
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+/// Serves the requests of a connection until the client closes it, or asks to.
+///
+/// Like `serve_frames`, the responses are flushed once the requests read so far are
+/// answered.
+pub(crate) fn handle_connection<E: KvsEngine>(engine: &E, watchers: &Watchers, stream: &TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let incoming = match read_request(&mut reader) {
+            Err(KvStoreError::Protocol(reason)) => Incoming::Invalid(400, reason),
+            incoming => incoming?,
+        };
+        let (response, close) = match incoming {
+            Incoming::Request(request) => {
+                let close = request.close;
+                (route(engine, watchers, request), close)
+            }
+            Incoming::Invalid(status, reason) => (Response::error(status, &reason), true),
+            Incoming::Closed => return Ok(()),
+        };
+        response.write_to(&mut writer, close)?;
+        if close {
+            writer.flush()?;
+            stream.shutdown(Shutdown::Write)?;
+            return Ok(());
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Answers a connection the pool has no room for.
+pub(crate) fn answer_busy(mut stream: &TcpStream) -> Result<()> {
+    Response::error(503, "Server busy").write_to(&mut stream, true)?;
+    Ok(())
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Incoming> {
+    // empty lines before a request are ignored, as RFC 9112 asks.
+    let line = loop {
+        match read_line(reader)? {
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+            None => return Ok(Incoming::Closed),
+        }
+    };
+    let line = String::from_utf8(line).map_err(|_| KvStoreError::Protocol("request line is not UTF-8".to_owned()))?;
+    let (method, target, version) = match line.split(' ').collect::<Vec<_>>()[..] {
+        [method, target, version] => (method.to_owned(), target.to_owned(), version),
+        _ => return Ok(Incoming::Invalid(400, format!("invalid request line '{}'", line))),
+    };
+    // HTTP/1.0 closes the connection after the response unless asked otherwise.
+    let mut close = match version {
+        "HTTP/1.1" => false,
+        "HTTP/1.0" => true,
+        _ => return Ok(Incoming::Invalid(505, format!("unsupported version '{}'", version))),
+    };
+    let mut content_length = 0;
+    let mut headers = 0;
+    loop {
+        let line = read_line(reader)?.ok_or_else(unexpected_eof)?;
+        if line.is_empty() {
+            break;
+        }
+        headers += 1;
+        if headers > MAX_HEADERS {
+            return Ok(Incoming::Invalid(431, "too many headers".to_owned()));
+        }
+        let line = String::from_utf8_lossy(&line);
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.to_ascii_lowercase(), value.trim()),
+            None => return Ok(Incoming::Invalid(400, format!("invalid header '{}'", line))),
+        };
+        match name.as_str() {
+            "content-length" => {
+                content_length = match value.parse::<usize>() {
+                    Ok(len) if len <= MAX_BODY_LEN => len,
+                    Ok(_) => return Ok(Incoming::Invalid(413, "body too large".to_owned())),
+                    Err(_) => return Ok(Incoming::Invalid(400, "invalid Content-Length".to_owned())),
+                }
+            }
+            "transfer-encoding" => {
+                return Ok(Incoming::Invalid(501, "chunked request bodies are not supported".to_owned()));
+            }
+            "connection" if value.eq_ignore_ascii_case("close") => close = true,
+            "connection" if value.eq_ignore_ascii_case("keep-alive") => close = false,
+            _ => {}
+        }
+    }
+    // the body is read as it arrives, a client announcing a large one may never send it.
+    let mut body = Vec::new();
+    reader.take(content_length as u64).read_to_end(&mut body)?;
+    if body.len() < content_length {
+        return Ok(Incoming::Invalid(400, "body shorter than its Content-Length".to_owned()));
+    }
+    Ok(Incoming::Request(Request { method, target, body, close }))
+}
+
+// Reads a line without its CRLF, `None` when the client closed the connection
+// before sending one.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if read as u64 == MAX_LINE_LEN {
+            return Err(KvStoreError::Protocol("line too long".to_owned()));
+        }
+        return Err(unexpected_eof());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn unexpected_eof() -> KvStoreError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "the client closed the connection in a request").into()
+}
+
+fn route<E: KvsEngine>(engine: &E, watchers: &Watchers, request: Request) -> Response {
+    let (path, query) = match request.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (request.target.as_str(), ""),
+    };
+    let result = if path == "/kv" {
+        match request.method.as_str() {
+            "GET" => list(engine, query),
+            _ => return Response::method_not_allowed("GET"),
+        }
+    } else if let Some(key) = path.strip_prefix("/kv/") {
+        let key = match percent_decode(key, false) {
+            Some(key) if !key.is_empty() => key,
+            _ => return Response::error(400, "Invalid key"),
+        };
+        match request.method.as_str() {
+            "GET" => engine.get_bytes(key).map(|value| match value {
+                Some(value) => Response::new(200, "application/octet-stream", value),
+                None => Response::error(404, "Key not found"),
+            }),
+            "PUT" => {
+                let value = request.body;
+                watchers
+                    .write([key.as_slice()], || engine.set_bytes(key.clone(), value.clone()).map(|()| ((), vec![(key.clone(), Some(value))])))
+                    .map(|()| Response::no_content())
+            }
+            "DELETE" => watchers
+                .write([key.as_slice()], || engine.remove_bytes(key.clone()).map(|()| ((), vec![(key.clone(), None)])))
+                .map(|()| Response::no_content()),
+            _ => return Response::method_not_allowed("GET, PUT, DELETE"),
+        }
+    } else {
+        return Response::error(404, "Not found");
+    };
+    result.unwrap_or_else(|err| match err {
+        KvStoreError::KeyNotFound => Response::error(404, "Key not found"),
+        err => Response::error(500, &err.to_string()),
+    })
+}
+
+// GET /kv?prefix={prefix}&limit={n}&after={key}, the parameters are optional and the
+// limit is at most `MAX_LIST_LEN`.
+fn list<E: KvsEngine>(engine: &E, query: &str) -> Result<Response> {
+    let mut prefix = Vec::new();
+    let mut limit = None;
+    let mut after = None;
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = match percent_decode(value, true) {
+            Some(value) => value,
+            None => return Ok(Response::error(400, &format!("Invalid query parameter '{}'", name))),
+        };
+        match name {
+            "prefix" => prefix = value,
+            "after" => after = Some(value),
+            "limit" => match String::from_utf8(value).ok().and_then(|limit| limit.parse::<usize>().ok()) {
+                Some(n) => limit = Some(n),
+                None => return Ok(Response::error(400, "Invalid limit")),
+            },
+            _ => {}
+        }
+    }
+    let limit = limit.map_or(MAX_LIST_LEN, |limit| limit.min(MAX_LIST_LEN));
+    let (mut start, end) = engine::prefix_range(prefix);
+    if let (Some(after), Bound::Included(prefix)) = (after, &start) {
+        if after >= *prefix {
+            start = Bound::Excluded(after);
+        }
+    }
+    // one more pair than the limit tells whether the listing is cut short.
+    let options = ScanOptions { limit: Some(limit + 1), reverse: false };
+    let mut pairs = Vec::new();
+    let (mut last, mut more) = (None, false);
+    for pair in engine.scan((start, end), options)? {
+        let (key, value) = pair?;
+        if pairs.len() == limit {
+            more = true;
+            break;
+        }
+        last = Some(percent_encode(&key));
+        let mut pair = serde_json::Map::new();
+        insert_bytes(&mut pair, "key", key);
+        insert_bytes(&mut pair, "value", value);
+        pairs.push(serde_json::Value::Object(pair));
+    }
+    let response = Response::json(200, serde_json::Value::Array(pairs));
+    Ok(Response { next_after: last.filter(|_| more), ..response })
+}
+
+// Inserts `bytes` as a string under `name`, in base64 with `{name}_base64` set when
+// they are not UTF-8.
+fn insert_bytes(object: &mut serde_json::Map<String, serde_json::Value>, name: &str, bytes: Vec<u8>) {
+    match String::from_utf8(bytes) {
+        Ok(string) => {
+            object.insert(name.to_owned(), string.into());
+        }
+        Err(err) => {
+            object.insert(name.to_owned(), base64(err.as_bytes()).into());
+            object.insert(format!("{}_base64", name), true.into());
+        }
+    }
+}
+
+// The standard base64 of RFC 4648, padded.
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, &byte)| group | ((byte as u32) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((group >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+// Escapes the bytes of `bytes` but the unreserved characters of RFC 3986 as `%XX`.
+fn percent_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+// Decodes the `%XX` escapes of a path segment, and the `+` of a query value which
+// stand for spaces. `None` when an escape is invalid.
+fn percent_decode(s: &str, query: bool) -> Option<Vec<u8>> {
+    let mut bytes = s.bytes();
+    let mut decoded = Vec::with_capacity(s.len());
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let high = (bytes.next()? as char).to_digit(16)?;
+                let low = (bytes.next()? as char).to_digit(16)?;
+                decoded.push((high * 16 + low) as u8);
+            }
+            b'+' if query => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    Some(decoded)
+}
@@ -1,17 +1,53 @@
 mod server;
 mod client;
+#[cfg(feature = "async")]
+mod async_server;
+#[cfg(feature = "async")]
+mod async_client;
 mod error;
 mod helper;
+mod frame;
+mod resp;
+mod http;
 mod engine;
+mod watch;
+mod shutdown;
 pub mod thread_pool;
 pub use server::KvsServer;
+pub use server::Protocol;
+pub use shutdown::ShutdownHandle;
 pub use client::KvsClient;
+pub use client::Pipeline;
+pub use client::Reply;
+pub use client::ScanStream;
+pub use client::WatchStream;
+#[cfg(feature = "async")]
+pub use async_server::AsyncKvsServer;
+#[cfg(feature = "async")]
+pub use async_client::{AsyncKvsClient, AsyncWatchStream};
+pub use watch::WatchEvent;
 pub use error::Result;
 pub use error::KvStoreError;
 pub use engine::KvsEngine;
+#[cfg(feature = "async")]
+pub use engine::AsyncKvsEngine;
+pub use engine::KvsSnapshot;
+pub use engine::ScanOptions;
+pub use engine::KvPairs;
+pub use engine::Keys;
+pub use engine::WriteBatch;
+pub use engine::BatchOp;
+pub use engine::Transaction;
+pub use engine::ReadVersion;
 pub use engine::KvStore;
+pub use engine::KvStoreSnapshot;
+pub use engine::Durability;
 pub use engine::SledKvsEngine;
+pub use engine::SledSnapshot;
 pub use thread_pool::ThreadPool;
+pub use thread_pool::PoolStats;
 pub use thread_pool::SharedQueueThreadPool;
+pub use thread_pool::QueuePolicy;
 pub use thread_pool::RayonThreadPool;
 pub use thread_pool::NaiveThreadPool;
+pub use thread_pool::WorkStealingThreadPool;
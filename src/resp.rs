@@ -0,0 +1,439 @@
+//! The RESP protocol of Redis, spoken by a `KvsServer` started with `Protocol::Resp`
+//! so redis-cli, redis-benchmark and the Redis client libraries can use the store.
+//!
+//! GET, SET, DEL, EXISTS, SCAN, PING and INFO are served, any other command is
+//! answered with an error. Commands come as arrays of bulk strings, or as inline
+//! commands split on whitespace like Redis accepts them from a terminal.
+
+use crate::engine::{self, KvsEngine, ScanOptions};
+use crate::thread_pool::PoolStats;
+use crate::watch::Watchers;
+use crate::{KvStoreError, Result, ThreadPool};
+use std::collections::BTreeMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::Bound;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// the keys a SCAN visits when the client does not give a COUNT, as in Redis.
+const SCAN_COUNT: usize = 10;
+// larger bulk strings are refused, the limit of Redis.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+// commands with more arguments are refused, their length could be anything.
+const MAX_ARGS: usize = 1024 * 1024;
+// longer lines are refused, an inline command never ends otherwise.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+// the arguments allocated ahead of a multibulk, more grow as they are read.
+const ARGS_PREALLOCATED: usize = 16;
+// the cursors of the scans in progress, the oldest one is forgotten past this many.
+const MAX_CURSORS: usize = 4096;
+
+/// A reply of RESP.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    /// `None` is the null bulk string, the reply of a missing key.
+    Bulk(Option<Vec<u8>>),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s),
+            Value::Error(err) => write!(writer, "-{}\r\n", err),
+            Value::Integer(i) => write!(writer, ":{}\r\n", i),
+            Value::Bulk(None) => writer.write_all(b"$-1\r\n"),
+            Value::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")
+            }
+            Value::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                values.iter().try_for_each(|value| value.write_to(writer))
+            }
+        }
+    }
+}
+
+/// Serves the commands of a connection until the client closes it, or sends
+/// something that is not RESP.
+///
+/// Like `serve_frames`, the replies are flushed once the commands read so far are
+/// answered, so a pipelining client is not answered one flush per command.
+pub(crate) fn serve<E: KvsEngine, T: ThreadPool, R: Read, W: Write>(
+    engine: &E,
+    watchers: &Watchers,
+    cursors: &Cursors,
+    pool: &T,
+    mut reader: BufReader<R>,
+    writer: &mut W,
+) -> Result<()> {
+    loop {
+        let command = match read_command(&mut reader) {
+            Ok(Some(command)) => command,
+            Ok(None) => return Ok(()),
+            // Redis closes the connection too, the next bytes can not be trusted.
+            Err(KvStoreError::Protocol(reason)) => {
+                Value::Error(format!("ERR Protocol error: {}", reason)).write_to(writer)?;
+                writer.flush()?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        execute(engine, watchers, cursors, pool, command).write_to(writer)?;
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+}
+
+/// Reads the arguments of the next command, `None` once the client closed the
+/// connection.
+pub(crate) fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        if line.first() != Some(&b'*') {
+            let args: Vec<Vec<u8>> = line.split(u8::is_ascii_whitespace).filter(|arg| !arg.is_empty()).map(<[u8]>::to_vec).collect();
+            // an empty inline command is skipped, as Redis does.
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+        let len = parse_len(&line[1..], MAX_ARGS, "multibulk length")?;
+        let mut args = Vec::with_capacity(len.min(ARGS_PREALLOCATED));
+        for _ in 0..len {
+            let header = read_line(reader)?.ok_or_else(unexpected_eof)?;
+            if header.first() != Some(&b'$') {
+                return Err(KvStoreError::Protocol(format!("expected '$', got '{}'", String::from_utf8_lossy(&header))));
+            }
+            let len = parse_len(&header[1..], MAX_BULK_LEN, "bulk length")?;
+            // the buffer grows with the bytes received, not with the length announced.
+            let mut arg = Vec::new();
+            reader.take(len as u64 + 2).read_to_end(&mut arg)?;
+            if arg.len() < len + 2 {
+                return Err(unexpected_eof());
+            }
+            if !arg.ends_with(b"\r\n") {
+                return Err(KvStoreError::Protocol("bulk string not terminated by CRLF".to_owned()));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+// Reads a line without its CRLF, `None` when the client closed the connection
+// before sending one.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    let read = reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if read as u64 == MAX_LINE_LEN {
+            return Err(KvStoreError::Protocol("too big inline request".to_owned()));
+        }
+        return Err(unexpected_eof());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(bytes: &[u8], max: usize, what: &str) -> Result<usize> {
+    match std::str::from_utf8(bytes).ok().and_then(|len| len.parse::<i64>().ok()) {
+        // a null array or bulk string has no argument.
+        Some(-1) => Ok(0),
+        Some(len) if len >= 0 && len as usize <= max => Ok(len as usize),
+        _ => Err(KvStoreError::Protocol(format!("invalid {}", what))),
+    }
+}
+
+fn unexpected_eof() -> KvStoreError {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "the client closed the connection in a command").into()
+}
+
+// Runs a command, the errors of the engine are replied like the ones of the command.
+fn execute<E: KvsEngine, T: ThreadPool>(engine: &E, watchers: &Watchers, cursors: &Cursors, pool: &T, command: Vec<Vec<u8>>) -> Value {
+    let name = String::from_utf8_lossy(&command[0]).to_ascii_uppercase();
+    let args = &command[1..];
+    let result = match name.as_str() {
+        "GET" if args.len() == 1 => engine.get_bytes(args[0].clone()).map(Value::Bulk),
+        "SET" if args.len() >= 2 => set(engine, watchers, args),
+        "DEL" if !args.is_empty() => del(engine, watchers, args),
+        "EXISTS" if !args.is_empty() => exists(engine, args),
+        "SCAN" if !args.is_empty() => scan(engine, cursors, args),
+        "PING" if args.len() <= 1 => Ok(match args.first() {
+            Some(message) => Value::Bulk(Some(message.clone())),
+            None => Value::Simple("PONG".to_owned()),
+        }),
+        "INFO" => Ok(info(&pool.stats())),
+        "GET" | "SET" | "DEL" | "EXISTS" | "SCAN" | "PING" => {
+            return Value::Error(format!("ERR wrong number of arguments for '{}' command", name.to_ascii_lowercase()));
+        }
+        _ => return Value::Error(format!("ERR unknown command '{}'", String::from_utf8_lossy(&command[0]))),
+    };
+    result.unwrap_or_else(|err| Value::Error(format!("ERR {}", err)))
+}
+
+// SET key value [EX seconds | PX milliseconds]
+fn set<E: KvsEngine>(engine: &E, watchers: &Watchers, args: &[Vec<u8>]) -> Result<Value> {
+    let (key, value) = (args[0].clone(), args[1].clone());
+    let ttl = match &args[2..] {
+        [] => None,
+        [unit, amount] => {
+            let unit = match unit.to_ascii_uppercase().as_slice() {
+                b"EX" => Duration::from_secs,
+                b"PX" => Duration::from_millis,
+                _ => return Ok(syntax_error()),
+            };
+            match std::str::from_utf8(amount).ok().and_then(|amount| amount.parse::<u64>().ok()) {
+                Some(amount) if amount > 0 => Some(unit(amount)),
+                _ => return Ok(Value::Error("ERR invalid expire time in 'set' command".to_owned())),
+            }
+        }
+        _ => return Ok(syntax_error()),
+    };
+    watchers.write([key.as_slice()], || {
+        match ttl {
+            Some(ttl) => engine.set_with_ttl(key.clone(), value.clone(), ttl)?,
+            None => engine.set_bytes(key.clone(), value.clone())?,
+        }
+        Ok(((), vec![(key.clone(), Some(value))]))
+    })?;
+    Ok(Value::Simple("OK".to_owned()))
+}
+
+// DEL key [key ...], replies the number of keys removed.
+fn del<E: KvsEngine>(engine: &E, watchers: &Watchers, keys: &[Vec<u8>]) -> Result<Value> {
+    let mut removed = 0;
+    for key in keys {
+        match watchers.write([key.as_slice()], || engine.remove_bytes(key.clone()).map(|()| ((), vec![(key.clone(), None)]))) {
+            Ok(()) => removed += 1,
+            Err(KvStoreError::KeyNotFound) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(Value::Integer(removed))
+}
+
+// EXISTS key [key ...], a key given twice is counted twice like in Redis.
+fn exists<E: KvsEngine>(engine: &E, keys: &[Vec<u8>]) -> Result<Value> {
+    let mut found = 0;
+    for key in keys {
+        if engine.get_bytes(key.clone())?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(Value::Integer(found))
+}
+
+// SCAN cursor [MATCH pattern] [COUNT count]
+//
+// The cursor stands for the next key to visit, see `Cursors`, so as in Redis a key
+// present during the whole iteration is returned exactly once.
+fn scan<E: KvsEngine>(engine: &E, cursors: &Cursors, args: &[Vec<u8>]) -> Result<Value> {
+    let cursor = match std::str::from_utf8(&args[0]).ok().and_then(|cursor| cursor.parse::<u64>().ok()) {
+        Some(0) => None,
+        Some(cursor) => match cursors.key(cursor) {
+            Some(key) => Some(key),
+            None => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+        },
+        None => return Ok(Value::Error("ERR invalid cursor".to_owned())),
+    };
+    let mut pattern = None;
+    let mut count = SCAN_COUNT;
+    let mut options = args[1..].chunks(2);
+    for option in &mut options {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value.as_slice()),
+            [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                count = match std::str::from_utf8(value).ok().and_then(|count| count.parse::<usize>().ok()) {
+                    Some(count) if count > 0 => count,
+                    _ => return Ok(syntax_error()),
+                };
+            }
+            _ => return Ok(syntax_error()),
+        }
+    }
+    // only the keys starting with the literal prefix of the pattern can match it.
+    let (start, end) = engine::prefix_range(pattern.map_or(Vec::new(), literal_prefix));
+    let start = match (start, cursor) {
+        (Bound::Included(prefix), Some(cursor)) => Bound::Included(prefix.max(cursor)),
+        (start, _) => start,
+    };
+    let options = ScanOptions { limit: Some(count.saturating_add(1)), reverse: false };
+    let mut visited = engine.scan_keys((start, end), options)?;
+    let mut keys = Vec::new();
+    for key in visited.by_ref().take(count) {
+        let key = key?;
+        if pattern.is_none_or(|pattern| glob_match(pattern, &key)) {
+            keys.push(Value::Bulk(Some(key)));
+        }
+    }
+    let next = match visited.next().transpose()? {
+        Some(next) => cursors.insert(next),
+        None => 0,
+    };
+    Ok(Value::Array(vec![Value::Bulk(Some(next.to_string().into_bytes())), Value::Array(keys)]))
+}
+
+/// The keys the SCANs of a server resume from.
+///
+/// A cursor is a number handed out by the server for the next key to visit, as the
+/// clients parse it as one, and `0` starts a scan. Any connection may use it, like in
+/// Redis. Only the last `MAX_CURSORS` cursors are kept, an older one is invalid.
+#[derive(Debug, Default)]
+pub(crate) struct Cursors {
+    state: Mutex<CursorState>,
+}
+
+#[derive(Debug, Default)]
+struct CursorState {
+    last: u64,
+    // by cursor, the oldest first.
+    keys: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Cursors {
+    // Returns the cursor of a scan resuming from `key`.
+    fn insert(&self, key: Vec<u8>) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.last += 1;
+        let cursor = state.last;
+        state.keys.insert(cursor, key);
+        if state.keys.len() > MAX_CURSORS {
+            state.keys.pop_first();
+        }
+        cursor
+    }
+
+    // The key the cursor resumes from, the cursor stays valid for a client retrying.
+    fn key(&self, cursor: u64) -> Option<Vec<u8>> {
+        self.state.lock().unwrap().keys.get(&cursor).cloned()
+    }
+}
+
+fn info(stats: &PoolStats) -> Value {
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\n\r\n# Pool\r\nactive:{}\r\nidle:{}\r\nqueued:{}\r\ncompleted:{}\r\npanics:{}\r\naverage_latency_us:{}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        stats.active,
+        stats.idle,
+        stats.queued,
+        stats.completed,
+        stats.panics,
+        stats.average_latency.as_micros(),
+    );
+    Value::Bulk(Some(info.into_bytes()))
+}
+
+fn syntax_error() -> Value {
+    Value::Error("ERR syntax error".to_owned())
+}
+
+// The bytes of a glob pattern before its first special character.
+fn literal_prefix(pattern: &[u8]) -> Vec<u8> {
+    pattern.iter().take_while(|&&byte| !matches!(byte, b'*' | b'?' | b'[' | b'\\')).cloned().collect()
+}
+
+/// Whether `key` matches the glob `pattern` of a SCAN MATCH: `*`, `?`, `[...]`
+/// classes with ranges and `^` negation, and `\` escapes.
+///
+/// Every other token matches one byte, so on a mismatch only the last `*` needs to
+/// take one more byte and the match takes at most `pattern.len() * key.len()` steps.
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // where the pattern after the last `*` and the key it was tried at start.
+    let mut star = None;
+    while k < key.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, k));
+            continue;
+        }
+        match token_match(&pattern[p..], key[k]) {
+            Some(len) => {
+                p += len;
+                k += 1;
+            }
+            None => match star {
+                Some((star_p, star_k)) => {
+                    p = star_p;
+                    k = star_k + 1;
+                    star = Some((star_p, k));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+// The length of the token `pattern` starts with if it matches `byte`, `None` when
+// it does not or the pattern is empty. `pattern` does not start with a `*`.
+fn token_match(pattern: &[u8], byte: u8) -> Option<usize> {
+    let (matched, len) = match pattern {
+        [] => return None,
+        [b'?', ..] => (true, 1),
+        [b'[', rest @ ..] => match class_end(rest) {
+            Some(end) => (class_match(&rest[..end], byte), end + 2),
+            // an unterminated class is taken literally.
+            None => (byte == b'[', 1),
+        },
+        [b'\\', escaped, ..] => (byte == *escaped, 2),
+        [first, ..] => (byte == *first, 1),
+    };
+    matched.then_some(len)
+}
+
+// The index of the `]` closing a class, `class` starts after the `[`.
+fn class_end(class: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i < class.len() {
+        match class[i] {
+            b'\\' => i += 2,
+            b']' => return Some(i),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+fn class_match(class: &[u8], byte: u8) -> bool {
+    let (negated, mut class) = match class.split_first() {
+        Some((b'^', rest)) => (true, rest),
+        _ => (false, class),
+    };
+    let mut matched = false;
+    while let Some((&first, rest)) = class.split_first() {
+        match (first, rest) {
+            (b'\\', [escaped, rest @ ..]) => {
+                matched |= byte == *escaped;
+                class = rest;
+            }
+            (low, [b'-', high, rest @ ..]) => {
+                matched |= (low.min(*high)..=low.max(*high)).contains(&byte);
+                class = rest;
+            }
+            (first, rest) => {
+                matched |= byte == first;
+                class = rest;
+            }
+        }
+    }
+    matched != negated
+}
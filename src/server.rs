@@ -1,81 +1,644 @@
-use super::helper;
+use super::helper::{self, ReplyTo};
 use super::Result;
-use crate::engine::KvsEngine;
+use crate::engine::{BatchOp, KvsEngine, Transaction, ScanOptions};
+use crate::frame::{self, Frame, Incoming};
+use crate::http;
+use crate::resp;
+use crate::shutdown::{Connection, Connections, ShutdownHandle};
+use crate::watch::{WatchEvent, Watchers, WATCH_LAGGED};
+use crate::KvStoreError;
 use crate::ThreadPool;
 use serde_json::Deserializer;
 use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
 use std::net::SocketAddr;
-use std::net::{TcpListener, TcpStream};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub(crate) const EXPIRY_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+// how often an idle watch checks whether its client is still connected.
+const WATCH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+// how long a shutdown waits for the requests being served before giving up on them.
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+// how long a rejected connection waits for the first bytes of the client.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+// at most this many rejected connections wait for their answer, the ones after are
+// closed without one.
+const REJECT_BACKLOG: usize = 64;
+// the first and the longest waits before accepting again after a failed accept.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(5);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// The protocol the clients of a `KvsServer` speak.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Protocol {
+    /// The framed protocol of `KvsClient`, or its legacy JSON protocol.
+    #[default]
+    Kvs,
+    /// The protocol of Redis, see the `resp` module for the commands served.
+    Resp,
+}
+
+/// Parses `kvs` or `resp`.
+impl FromStr for Protocol {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<Protocol> {
+        match s {
+            "kvs" => Ok(Protocol::Kvs),
+            "resp" => Ok(Protocol::Resp),
+            _ => Err(KvStoreError::InvalidProtocol(s.to_owned())),
+        }
+    }
+}
 
 pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
     engine: E,
     thread_pool: T,
+    protocol: Protocol,
+    watchers: Arc<Watchers>,
+    cursors: Arc<resp::Cursors>,
+    connections: Arc<Connections>,
+    shutdown: ShutdownHandle,
 }
 
-impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
+impl<E: KvsEngine, T: ThreadPool + Send + Sync + 'static> KvsServer<E, T> {
     pub fn new(engine: E, thread_pool: T) -> Result<Self> {
+        KvsServer::with_protocol(engine, thread_pool, Protocol::Kvs)
+    }
+
+    /// Creates a server whose clients speak `protocol`.
+    pub fn with_protocol(engine: E, thread_pool: T, protocol: Protocol) -> Result<Self> {
         Ok(KvsServer {
             engine,
             thread_pool,
+            protocol,
+            watchers: Arc::new(Watchers::default()),
+            cursors: Arc::new(resp::Cursors::default()),
+            connections: Arc::new(Connections::default()),
+            shutdown: ShutdownHandle::default(),
         })
     }
 
+    /// Returns a handle that stops `run`.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves the clients connecting to `addr` until the server is shut down.
+    ///
+    /// On shutdown the server stops accepting connections, lets the requests being
+    /// served finish for up to `DRAIN_TIMEOUT`, and flushes the engine to disk.
     pub fn run(self, addr: SocketAddr) -> Result<()> {
+        self.serve(addr, None)
+    }
+
+    /// Like `run`, and serves the HTTP gateway of the `http` module on `http_addr`.
+    /// An HTTP connection is a job of the thread pool like the other ones, and sees
+    /// the same engine.
+    pub fn run_with_http(self, addr: SocketAddr, http_addr: SocketAddr) -> Result<()> {
+        self.serve(addr, Some(http_addr))
+    }
+
+    fn serve(self, addr: SocketAddr, http_addr: Option<SocketAddr>) -> Result<()> {
         let listener = TcpListener::bind(addr)?;
-        let pool = self.thread_pool;
-        for stream in listener.incoming() {
-            let stream = stream?;
-            let engine = self.engine.clone();
-            pool.spawn(move || {
+        let http_listener = http_addr.map(TcpListener::bind).transpose()?;
+        self.shutdown.listening(listener.local_addr()?);
+        spawn_expiry_sweep(self.engine.clone(), self.shutdown.clone());
+        // connections hold the pool to report its stats.
+        let pool = Arc::new(self.thread_pool);
+        let gateway = match http_listener {
+            Some(http_listener) => {
+                self.shutdown.listening(http_listener.local_addr()?);
+                Some(spawn_gateway(
+                    http_listener,
+                    self.engine.clone(),
+                    Arc::clone(&self.watchers),
+                    Arc::clone(&pool),
+                    Arc::clone(&self.connections),
+                    self.shutdown.clone(),
+                ))
+            }
+            None => None,
+        };
+        let (engine, watchers, cursors, protocol) = (&self.engine, &self.watchers, &self.cursors, self.protocol);
+        let serve = |stream, connection| {
+            let engine = engine.clone();
+            let watchers = Arc::clone(watchers);
+            let cursors = Arc::clone(cursors);
+            let stats = Arc::clone(&pool);
+            move || {
                 // panic!("oh no!");
-                handle_connection(engine, stream).expect("Thread operations");
+                if let Err(err) = handle_connection(engine, watchers, cursors, stats, stream, protocol, connection) {
+                    eprintln!("Connection failed: {:?}", err);
+                }
                 println!("Connection established");
-            })
+            }
+        };
+        accept(&listener, &self.shutdown, &self.connections, &*pool, serve, move |stream: &TcpStream| {
+            answer_busy(stream, protocol)
+        })?;
+        drop(listener);
+        if let Some(gateway) = gateway {
+            match gateway.join() {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => eprintln!("HTTP gateway failed: {:?}", err),
+                Err(_) => eprintln!("HTTP gateway panicked"),
+            }
+        }
+        self.connections.close_reads();
+        if !self.connections.wait_closed(DRAIN_TIMEOUT) {
+            eprintln!("Connections still open after {:?}, shutting down anyway", DRAIN_TIMEOUT);
+        }
+        self.engine.flush()
+    }
+}
+
+// Hands the connections of `listener` to the pool until the server is shut down.
+// `serve` makes the job serving a connection, `reject` answers the connections the
+// pool has no room for or evicts from its queue.
+fn accept<T, S, J, R>(
+    listener: &TcpListener,
+    shutdown: &ShutdownHandle,
+    connections: &Arc<Connections>,
+    pool: &T,
+    serve: S,
+    reject: R,
+) -> Result<()>
+where
+    T: ThreadPool,
+    S: Fn(TcpStream, Connection) -> J,
+    J: FnOnce() + Send + 'static,
+    R: Fn(&TcpStream) -> Result<()> + Send + 'static,
+{
+    let rejector = spawn_rejector(reject);
+    let mut backoff = Duration::ZERO;
+    for stream in listener.incoming() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                backoff = accept_failed(&err, backoff);
+                thread::sleep(backoff);
+                continue;
+            }
+        };
+        backoff = Duration::ZERO;
+        // a connection the server can not keep track of is closed, the next one may be.
+        let connection = match connections.register(&stream) {
+            Ok(connection) => connection,
+            Err(err) => {
+                eprintln!("Failed to register connection: {:?}", err);
+                continue;
+            }
+        };
+        let busy = match stream.try_clone() {
+            Ok(busy) => busy,
+            Err(err) => {
+                eprintln!("Failed to clone connection: {:?}", err);
+                continue;
+            }
+        };
+        let job = ConnectionJob { job: Some(serve(stream, connection)), busy: Some(busy), rejector: rejector.clone() };
+        // a job the pool has no room for is dropped, which rejects its connection.
+        let _ = pool.try_spawn(move || job.run());
+    }
+    Ok(())
+}
+
+// The job serving a connection. Dropped without running, because the pool had no room
+// for it or evicted it from its queue, it hands the connection to the rejector.
+struct ConnectionJob<J: FnOnce()> {
+    job: Option<J>,
+    busy: Option<TcpStream>,
+    rejector: SyncSender<TcpStream>,
+}
+
+impl<J: FnOnce()> ConnectionJob<J> {
+    fn run(mut self) {
+        self.busy = None;
+        if let Some(job) = self.job.take() {
+            job();
+        }
+    }
+}
+
+impl<J: FnOnce()> Drop for ConnectionJob<J> {
+    fn drop(&mut self) {
+        if let (Some(_), Some(busy)) = (self.job.take(), self.busy.take()) {
+            // the rejector is behind, the client only sees the connection close.
+            let _ = self.rejector.try_send(busy);
+        }
+    }
+}
+
+// Logs a failed accept and returns how long to wait before the next one, twice the
+// last wait. Running out of file descriptors or a connection reset before it was
+// accepted does not stop the server.
+pub(crate) fn accept_failed(err: &std::io::Error, backoff: Duration) -> Duration {
+    let backoff = (backoff * 2).clamp(ACCEPT_BACKOFF_MIN, ACCEPT_BACKOFF_MAX);
+    eprintln!("Accept failed, retrying in {:?}: {}", backoff, err);
+    backoff
+}
+
+// Accepts the connections of the HTTP gateway on a thread of its own, the thread
+// ends once the server is shut down.
+fn spawn_gateway<E: KvsEngine, T: ThreadPool + Send + Sync + 'static>(
+    listener: TcpListener,
+    engine: E,
+    watchers: Arc<Watchers>,
+    pool: Arc<T>,
+    connections: Arc<Connections>,
+    shutdown: ShutdownHandle,
+) -> thread::JoinHandle<Result<()>> {
+    thread::spawn(move || {
+        let serve = |stream: TcpStream, connection: Connection| {
+            let engine = engine.clone();
+            let watchers = Arc::clone(&watchers);
+            move || {
+                if let Err(err) = http::handle_connection(&engine, &watchers, &stream) {
+                    eprintln!("HTTP connection failed: {:?}", err);
+                }
+                drop(connection);
+            }
+        };
+        accept(&listener, &shutdown, &connections, &*pool, serve, http::answer_busy)
+    })
+}
+
+// Answers the connections the pool has no room for with an error and closes them,
+// on a thread of its own as it may wait for a client to tell which protocol it
+// speaks. The thread ends once the returned sender is dropped.
+fn spawn_rejector<R: Fn(&TcpStream) -> Result<()> + Send + 'static>(answer: R) -> SyncSender<TcpStream> {
+    let (sender, rejected) = mpsc::sync_channel::<TcpStream>(REJECT_BACKLOG);
+    thread::spawn(move || {
+        for stream in rejected {
+            if let Err(err) = answer(&stream).and_then(|()| close_answered(&stream)) {
+                eprintln!("Failed to reject connection: {:?}", err);
+            }
         }
-        Ok(())
+    });
+    sender
+}
+
+fn answer_busy(mut stream: &TcpStream, protocol: Protocol) -> Result<()> {
+    stream.set_read_timeout(Some(BUSY_TIMEOUT))?;
+    let mut first = [0];
+    if protocol == Protocol::Resp {
+        // the reply of Redis when it has no room for a client.
+        resp::Value::Error("ERR max number of clients reached".to_owned()).write_to(&mut stream)?;
+    } else if matches!(stream.peek(&mut first), Ok(1) if !frame::is_legacy(first[0])) {
+        // the client reads the answer to its hello first.
+        let id = match Frame::read_from(&mut stream)? {
+            Incoming::Frame(Frame { id, .. }) | Incoming::Unknown { id, .. } => id,
+            Incoming::Closed => return Ok(()),
+        };
+        Frame::hello_reply(id, &helper::Response::Busy)?.write_to(&mut stream)?;
+    } else {
+        serde_json::to_writer(stream, &helper::ErrorResponse::Err("Server busy".to_owned()))?;
     }
+    Ok(())
 }
 
-fn handle_connection<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
+fn close_answered(mut stream: &TcpStream) -> Result<()> {
+    stream.shutdown(Shutdown::Write)?;
+    // closing with unread data resets the connection, which could discard the error
+    // before the client reads it.
+    stream.set_nonblocking(true)?;
+    let _ = std::io::copy(&mut stream, &mut std::io::sink());
+    Ok(())
+}
+
+// Expired keys are hidden from reads as soon as they expire, the sweep reclaims
+// the space of the keys nobody reads anymore.
+fn spawn_expiry_sweep<E: KvsEngine>(engine: E, shutdown: ShutdownHandle) {
+    thread::spawn(move || loop {
+        thread::sleep(EXPIRY_SWEEP_INTERVAL);
+        if shutdown.is_shutdown() {
+            return;
+        }
+        if let Err(err) = engine.remove_expired() {
+            eprintln!("Expiry sweep failed: {:?}", err);
+        }
+    });
+}
+
+// The state of a connection between two requests.
+struct Session<E: KvsEngine, T> {
+    engine: E,
+    watchers: Arc<Watchers>,
+    pool: Arc<T>,
+    transaction: Option<Transaction<E>>,
+}
+
+impl<E: KvsEngine, T: ThreadPool> Session<E, T> {
+    // Answers the request, or returns the prefix a `Watch` streams the changes of.
+    fn answer<W: Write>(&mut self, req: helper::Request, to: ReplyTo, writer: &mut W) -> Result<Option<Vec<u8>>> {
+        match req {
+            helper::Request::Stats => to.write(writer, helper::Response::Stats(self.pool.stats()))?,
+            helper::Request::Watch { key_or_prefix } => return Ok(Some(key_or_prefix)),
+            req => respond(&self.engine, &self.watchers, &mut self.transaction, req, to, writer)?,
+        }
+        Ok(None)
+    }
+}
+
+fn handle_connection<E: KvsEngine, T: ThreadPool>(
+    engine: E,
+    watchers: Arc<Watchers>,
+    cursors: Arc<resp::Cursors>,
+    pool: Arc<T>,
+    stream: TcpStream,
+    protocol: Protocol,
+    connection: Connection,
+) -> Result<()> {
     let addr = stream.peer_addr()?;
     println!("server listen on {}", addr);
-    let mut writer = std::io::BufWriter::new(&stream);
-    let reader = std::io::BufReader::new(&stream);
-    let request = Deserializer::from_reader(reader).into_iter::<helper::Request>();
-    for req in request {
-        let req = req?;
+    let mut writer = BufWriter::new(&stream);
+    let mut reader = BufReader::new(&stream);
+    if protocol == Protocol::Resp {
+        return resp::serve(&engine, &watchers, &cursors, &*pool, reader, &mut writer);
+    }
+    let mut first = [0];
+    if stream.peek(&mut first)? == 0 {
+        return Ok(());
+    }
+    let mut session = Session { engine, watchers, pool, transaction: None };
+    let watch_to = if frame::is_legacy(first[0]) {
+        serve_json(&mut session, reader, &mut writer)?
+    } else {
+        let (id, response) = match Frame::read_from(&mut reader)?.into_hello_reply() {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        let accepted = matches!(response, helper::Response::Hello { .. });
+        Frame::hello_reply(id, &response)?.write_to(&mut writer)?;
+        writer.flush()?;
+        if !accepted {
+            return Ok(());
+        }
+        serve_frames(&mut session, reader, &mut writer)?
+    };
+    writer.flush()?;
+    // the connection only streams events from here on.
+    if let Some((prefix, to)) = watch_to {
+        watch(stream.try_clone()?, session.watchers, prefix, to, connection);
+    }
+    Ok(())
+}
+
+// Serves the requests of the legacy JSON protocol, returns the watch that ended them.
+fn serve_json<E: KvsEngine, T: ThreadPool, R: Read, W: Write>(
+    session: &mut Session<E, T>,
+    reader: R,
+    writer: &mut W,
+) -> Result<Option<(Vec<u8>, ReplyTo)>> {
+    for req in Deserializer::from_reader(reader).into_iter::<helper::Request>() {
+        let req = match req {
+            Ok(req) => req,
+            Err(err) if err.is_io() => return Err(err.into()),
+            Err(err) => {
+                helper::write_invalid_request(writer, &err)?;
+                return Ok(None);
+            }
+        };
+        let to = ReplyTo::Json(req.opcode());
+        if let Some(prefix) = session.answer(req, to, writer)? {
+            return Ok(Some((prefix, to)));
+        }
+        writer.flush()?;
+    }
+    Ok(None)
+}
+
+// Serves the frames that follow the hello, returns the watch that ended them.
+//
+// A client may send many requests before reading the responses, the responses are
+// flushed once the requests read so far are answered instead of one by one.
+fn serve_frames<E: KvsEngine, T: ThreadPool, R: Read, W: Write>(
+    session: &mut Session<E, T>,
+    mut reader: BufReader<R>,
+    writer: &mut W,
+) -> Result<Option<(Vec<u8>, ReplyTo)>> {
+    while let Some((id, req)) = Frame::read_from(&mut reader)?.into_request() {
+        let to = ReplyTo::Frame(id);
         match req {
-            helper::Request::Set { key, value } => match engine.set(key, value) {
-                Ok(()) => {
-                    serde_json::to_writer(&mut writer, &helper::SetResponse::Ok(()))?;
-                    writer.flush()?;
-                }
-                Err(err) => {
-                    serde_json::to_writer(&mut writer, &helper::SetResponse::Err(err.to_string()))?;
-                    writer.flush()?;
-                }
-            },
-            helper::Request::Rm(key) => match engine.remove(key) {
-                Ok(()) => {
-                    serde_json::to_writer(&mut writer, &helper::RmResponse::Ok(()))?;
-                    writer.flush()?;
+            Ok(req) => {
+                if let Some(prefix) = session.answer(req, to, writer)? {
+                    return Ok(Some((prefix, to)));
                 }
-                Err(err) => {
-                    serde_json::to_writer(&mut writer, &helper::RmResponse::Err(err.to_string()))?;
-                    writer.flush()?;
+            }
+            // a request this server does not understand does not end the connection.
+            Err(response) => to.write(writer, response)?,
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    Ok(None)
+}
+
+// Answers a request of a connection, `Watch` and `Stats` are answered by the server
+// serving the connection.
+pub(crate) fn respond<E: KvsEngine, W: Write>(
+    engine: &E,
+    watchers: &Watchers,
+    transaction: &mut Option<Transaction<E>>,
+    req: helper::Request,
+    to: ReplyTo,
+    writer: &mut W,
+) -> Result<()> {
+    let req = match transaction.as_mut() {
+        Some(txn) => match transaction_request(txn, req) {
+            Ok(response) => return to.write(writer, response),
+            Err(req) => req,
+        },
+        None => req,
+    };
+    let response = match req {
+        helper::Request::Begin => match transaction {
+            Some(_) => helper::Response::Err("A transaction is already open".to_owned()),
+            None => match engine.begin() {
+                Ok(txn) => {
+                    *transaction = Some(txn);
+                    helper::Response::Done
                 }
+                Err(err) => err.into(),
             },
-            helper::Request::Get(key) => match engine.get(key) {
-                Ok(value) => {
-                    serde_json::to_writer(&mut writer, &helper::GetResponse::Ok(value))?;
-                    writer.flush()?;
-                }
-                Err(err) => {
-                    serde_json::to_writer(&mut writer, &helper::GetResponse::Err(err.to_string()))?;
-                    writer.flush()?;
+        },
+        helper::Request::Commit => {
+            let commit = transaction.take().map(|txn| {
+                let changes = txn.changes();
+                watchers.write(changes.iter().map(|(key, _)| key.as_slice()), || {
+                    txn.commit().map(|()| ((), changes.clone()))
+                })
+            });
+            match commit {
+                Some(Ok(())) => helper::Response::Done,
+                Some(Err(err)) => err.into(),
+                None => helper::Response::Err("No open transaction".to_owned()),
+            }
+        }
+        helper::Request::Abort => match transaction.take().map(Transaction::rollback) {
+            Some(()) => helper::Response::Done,
+            None => helper::Response::Err("No open transaction".to_owned()),
+        },
+        helper::Request::Set { key, value } => done(watchers.write([key.as_slice()], || {
+            engine.set_bytes(key.clone(), value.clone()).map(|()| ((), vec![(key.clone(), Some(value))]))
+        })),
+        helper::Request::SetWithTtl { key, value, ttl } => done(watchers.write([key.as_slice()], || {
+            engine.set_with_ttl(key.clone(), value.clone(), ttl).map(|()| ((), vec![(key.clone(), Some(value))]))
+        })),
+        helper::Request::Ttl(key) => match engine.ttl(key) {
+            Ok(ttl) => helper::Response::Ttl(ttl),
+            Err(err) => err.into(),
+        },
+        helper::Request::Rm(key) => done(watchers.write([key.as_slice()], || {
+            engine.remove_bytes(key.clone()).map(|()| ((), vec![(key.clone(), None)]))
+        })),
+        helper::Request::Get(key) => match engine.get_bytes(key) {
+            Ok(value) => helper::Response::Value(value),
+            Err(err) => err.into(),
+        },
+        helper::Request::Batch(batch) => {
+            let changes: Vec<_> = batch
+                .ops()
+                .iter()
+                .map(|op| match op {
+                    BatchOp::Set { key, value } => (key.clone(), Some(value.clone())),
+                    BatchOp::Remove(key) => (key.clone(), None),
+                })
+                .collect();
+            done(watchers.write(changes.iter().map(|(key, _)| key.as_slice()), || {
+                engine.write_batch(batch).map(|()| ((), changes.clone()))
+            }))
+        }
+        helper::Request::Cas { key, expected, new } => match watchers.write([key.as_slice()], || {
+            let swapped = engine.compare_and_swap(key.clone(), expected, new.clone())?;
+            Ok((swapped, if swapped { vec![(key.clone(), new)] } else { Vec::new() }))
+        }) {
+            Ok(swapped) => helper::Response::Swapped(swapped),
+            Err(err) => err.into(),
+        },
+        helper::Request::Scan { start, end, options } => return scan(engine, (start, end), options, to, writer),
+        helper::Request::Stats | helper::Request::Watch { .. } => unreachable!("answered by the server"),
+    };
+    to.write(writer, response)
+}
+
+fn done(result: Result<()>) -> helper::Response {
+    match result {
+        Ok(()) => helper::Response::Done,
+        Err(err) => err.into(),
+    }
+}
+
+// Streams the changes of the keys starting with `prefix` on a thread of its own, so a
+// long lived watch does not hold a thread of the pool. The connection stays tracked
+// until the watch ends.
+fn watch(stream: TcpStream, watchers: Arc<Watchers>, prefix: Vec<u8>, to: ReplyTo, connection: Connection) {
+    let (id, events) = watchers.subscribe(prefix);
+    thread::spawn(move || {
+        if let Err(err) = stream_events(&stream, &events, to) {
+            eprintln!("Watch ended: {:?}", err);
+        }
+        watchers.unsubscribe(id);
+        drop(connection);
+    });
+}
+
+fn stream_events(stream: &TcpStream, events: &Receiver<WatchEvent>, to: ReplyTo) -> Result<()> {
+    let mut writer = std::io::BufWriter::new(stream);
+    to.write(&mut writer, helper::Response::Subscribed)?;
+    writer.flush()?;
+    loop {
+        match events.recv_timeout(WATCH_CHECK_INTERVAL) {
+            Ok(event) => {
+                to.write(&mut writer, helper::Response::Event(event))?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if is_closed(stream)? {
+                    return Ok(());
                 }
-            },
+            }
+            // the watchers outlive the connections, the subscriber was dropped.
+            Err(RecvTimeoutError::Disconnected) => {
+                to.write(&mut writer, helper::Response::Err(WATCH_LAGGED.to_owned()))?;
+                writer.flush()?;
+                return Ok(());
+            }
         }
     }
-    Ok(())
+}
+
+// Whether the client closed its side of the connection, without waiting for data.
+fn is_closed(stream: &TcpStream) -> Result<bool> {
+    stream.set_nonblocking(true)?;
+    let closed = match stream.peek(&mut [0]) {
+        Ok(0) => true,
+        Ok(_) => false,
+        Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => false,
+        Err(_) => true,
+    };
+    stream.set_nonblocking(false)?;
+    Ok(closed)
+}
+
+// Answers `Get`, `Set` and `Rm` inside the open transaction, any other request is given
+// back and runs outside of it.
+fn transaction_request<E: KvsEngine>(
+    txn: &mut Transaction<E>,
+    req: helper::Request,
+) -> std::result::Result<helper::Response, helper::Request> {
+    let response = match req {
+        helper::Request::Get(key) => match txn.get(key) {
+            Ok(value) => helper::Response::Value(value),
+            Err(err) => err.into(),
+        },
+        helper::Request::Set { key, value } => {
+            txn.set(key, value);
+            helper::Response::Done
+        }
+        // like outside of a transaction, removing a missing key fails.
+        helper::Request::Rm(key) => match txn.get(key.clone()) {
+            Ok(Some(_)) => {
+                txn.remove(key);
+                helper::Response::Done
+            }
+            Ok(None) => helper::Response::KeyNotFound,
+            Err(err) => err.into(),
+        },
+        req => return Err(req),
+    };
+    Ok(response)
+}
+
+// streams the pairs of the scan followed by `Done`, an error in the middle of the scan
+// ends it with the error.
+fn scan<E: KvsEngine, W: Write>(
+    engine: &E,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    options: ScanOptions,
+    to: ReplyTo,
+    writer: &mut W,
+) -> Result<()> {
+    let pairs = match engine.scan(range, options) {
+        Ok(pairs) => pairs,
+        Err(err) => return to.write(writer, err.into()),
+    };
+    for pair in pairs {
+        match pair {
+            Ok((key, value)) => to.write(writer, helper::Response::Item(key, value))?,
+            Err(err) => return to.write(writer, err.into()),
+        }
+    }
+    to.write(writer, helper::Response::Done)
 }
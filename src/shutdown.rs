@@ -0,0 +1,129 @@
+use super::Result;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Stops a running `KvsServer`, see `KvsServer::shutdown_handle`.
+///
+/// The handle can be cloned and moved to another thread, a signal handler for
+/// instance. Shutting down a server that is not running yet makes `run` return
+/// as soon as it starts.
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+#[derive(Debug, Default)]
+struct ShutdownState {
+    requested: AtomicBool,
+    // the addresses the server listens on, set once they are bound.
+    addrs: Mutex<Vec<SocketAddr>>,
+}
+
+impl ShutdownHandle {
+    /// Asks the server to stop accepting connections and to finish the requests
+    /// it is serving.
+    pub fn shutdown(&self) {
+        let addrs = self.state.addrs.lock().unwrap();
+        self.state.requested.store(true, Ordering::SeqCst);
+        for &addr in addrs.iter() {
+            wake_up(addr);
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.state.requested.load(Ordering::SeqCst)
+    }
+
+    /// The addresses the server listens on, in the order it bound them, empty until
+    /// `run` bound them. Tells the ports of a server bound to port 0.
+    pub fn listening_addrs(&self) -> Vec<SocketAddr> {
+        self.state.addrs.lock().unwrap().clone()
+    }
+
+    // Records an address the server is bound to, so `shutdown` can wake it up.
+    pub(crate) fn listening(&self, mut addr: SocketAddr) {
+        if addr.ip().is_unspecified() {
+            addr.set_ip(match addr {
+                SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+            });
+        }
+        let mut listening = self.state.addrs.lock().unwrap();
+        listening.push(addr);
+        // a shutdown requested before the server was bound could not wake it up.
+        if self.is_shutdown() {
+            wake_up(addr);
+        }
+    }
+}
+
+// The accept loop only checks for a shutdown when a connection comes in.
+fn wake_up(addr: SocketAddr) {
+    let _ = TcpStream::connect(addr);
+}
+
+/// The connections a server is serving, so a shutdown can close them and wait
+/// for them to finish.
+#[derive(Debug, Default)]
+pub(crate) struct Connections {
+    state: Mutex<ConnectionsState>,
+    closed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct ConnectionsState {
+    next_id: u64,
+    streams: HashMap<u64, TcpStream>,
+}
+
+impl Connections {
+    /// Tracks the stream until the returned guard is dropped.
+    pub(crate) fn register(self: &Arc<Self>, stream: &TcpStream) -> Result<Connection> {
+        let stream = stream.try_clone()?;
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.streams.insert(id, stream);
+        Ok(Connection { id, connections: Arc::clone(self) })
+    }
+
+    /// Closes the read side of every connection: the request being served still gets
+    /// its response, and the connection ends when it waits for the next one.
+    pub(crate) fn close_reads(&self) {
+        for stream in self.state.lock().unwrap().streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Waits until every connection ended, returns false if some are still open after `timeout`.
+    pub(crate) fn wait_closed(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.state.lock().unwrap();
+        while !state.streams.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = self.closed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        true
+    }
+}
+
+/// A connection tracked by `Connections`, it is forgotten when dropped.
+#[derive(Debug)]
+pub(crate) struct Connection {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.connections.state.lock().unwrap();
+        state.streams.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
@@ -1,12 +1,19 @@
 use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 mod naive;
 mod rayon;
 mod shared_queue;
+mod work_stealing;
 
 pub use self::naive::NaiveThreadPool;
 pub use self::rayon::RayonThreadPool;
-pub use self::shared_queue::SharedQueueThreadPool;
+pub use self::shared_queue::{QueuePolicy, SharedQueueThreadPool};
+pub use self::work_stealing::WorkStealingThreadPool;
 
 pub trait ThreadPool {
     fn new(threads: usize) -> Result<Self>
@@ -16,4 +23,181 @@ pub trait ThreadPool {
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static;
+
+    /// Like `spawn`, but fails with `KvStoreError::QueueFull` instead of dropping
+    /// a job the pool has no room for.
+    fn try_spawn<F>(&self, job: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.spawn(job);
+        Ok(())
+    }
+
+    /// Runs every job spawned so far and waits for the threads of the pool to exit.
+    fn join(self);
+
+    /// Drops the jobs that did not start yet and waits up to `timeout` for the running
+    /// ones and the threads of the pool to finish.
+    ///
+    /// Returns false when some of them were still running after `timeout`, they are
+    /// left to finish on their own.
+    fn shutdown(self, timeout: Duration) -> bool;
+
+    /// Returns what the pool is doing right now and what it did so far.
+    fn stats(&self) -> PoolStats;
+}
+
+/// A picture of the load of a thread pool, see `ThreadPool::stats`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PoolStats {
+    /// workers running a job.
+    pub active: usize,
+    /// workers waiting for a job.
+    pub idle: usize,
+    /// jobs spawned that did not start yet.
+    pub queued: usize,
+    /// jobs that returned.
+    pub completed: u64,
+    /// jobs that panicked, the pool recovered from all of them.
+    pub panics: u64,
+    /// mean time from the spawn of a job to its end, queueing included.
+    pub average_latency: Duration,
+}
+
+// The counters behind `PoolStats`, updated by the jobs wrapped with `track`.
+#[derive(Debug, Default)]
+struct Metrics {
+    active: AtomicUsize,
+    queued: AtomicUsize,
+    completed: AtomicU64,
+    panics: AtomicU64,
+    // sum of the latencies of the completed and panicked jobs, in nanoseconds.
+    total_latency: AtomicU64,
+}
+
+impl Metrics {
+    // Wraps a job so it is counted as queued until it starts and as active while it runs.
+    fn track<F>(self: &Arc<Self>, job: F) -> impl FnOnce() + Send + 'static
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        let queued = QueuedJob { metrics: Arc::clone(self), spawned: Instant::now() };
+        move || {
+            let running = RunningJob::start(queued);
+            job();
+            drop(running);
+        }
+    }
+
+    // `workers` is the number of threads of the pool.
+    fn stats(&self, workers: usize) -> PoolStats {
+        let active = self.active.load(Ordering::SeqCst);
+        let completed = self.completed.load(Ordering::SeqCst);
+        let panics = self.panics.load(Ordering::SeqCst);
+        let ended = completed + panics;
+        let average_latency = match ended {
+            0 => Duration::default(),
+            ended => Duration::from_nanos(self.total_latency.load(Ordering::SeqCst) / ended),
+        };
+        PoolStats {
+            active,
+            idle: workers.saturating_sub(active),
+            queued: self.queued.load(Ordering::SeqCst),
+            completed,
+            panics,
+            average_latency,
+        }
+    }
+}
+
+// A job that did not start yet, it may be dropped without ever running.
+struct QueuedJob {
+    metrics: Arc<Metrics>,
+    spawned: Instant,
+}
+
+impl Drop for QueuedJob {
+    fn drop(&mut self) {
+        self.metrics.queued.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// A job being run, dropped when it returns or panics.
+struct RunningJob {
+    metrics: Arc<Metrics>,
+    spawned: Instant,
+}
+
+impl RunningJob {
+    fn start(queued: QueuedJob) -> RunningJob {
+        let running = RunningJob { metrics: Arc::clone(&queued.metrics), spawned: queued.spawned };
+        running.metrics.active.fetch_add(1, Ordering::SeqCst);
+        drop(queued);
+        running
+    }
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        let latency = self.spawned.elapsed().as_nanos() as u64;
+        self.metrics.total_latency.fetch_add(latency, Ordering::SeqCst);
+        if thread::panicking() {
+            self.metrics.panics.fetch_add(1, Ordering::SeqCst);
+        } else {
+            self.metrics.completed.fetch_add(1, Ordering::SeqCst);
+        }
+        self.metrics.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Counts jobs or threads that are still running, so a pool can wait for them.
+#[derive(Debug, Default)]
+struct Running {
+    count: Mutex<usize>,
+    done: Condvar,
+}
+
+impl Running {
+    fn start(&self) {
+        *self.count.lock().unwrap() += 1;
+    }
+
+    // Starts something that is finished when the returned guard is dropped, even by a panic.
+    fn guard(self: &Arc<Self>) -> RunningGuard {
+        self.start();
+        RunningGuard(Arc::clone(self))
+    }
+
+    fn finish(&self) {
+        *self.count.lock().unwrap() -= 1;
+        self.done.notify_all();
+    }
+
+    // Waits until nothing runs anymore, returns false if something still does at `deadline`.
+    fn wait(&self, deadline: Option<Instant>) -> bool {
+        let mut count = self.count.lock().unwrap();
+        while *count > 0 {
+            count = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return false;
+                    }
+                    self.done.wait_timeout(count, deadline - now).unwrap().0
+                }
+                None => self.done.wait(count).unwrap(),
+            };
+        }
+        true
+    }
+}
+
+struct RunningGuard(Arc<Running>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.finish();
+    }
 }
@@ -1,20 +1,43 @@
 use super::Result;
-use super::ThreadPool;
+use super::{Metrics, PoolStats, Running, ThreadPool};
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 
-pub struct NaiveThreadPool;
+/// Runs every job on a thread of its own.
+pub struct NaiveThreadPool {
+    threads: Arc<Running>,
+    metrics: Arc<Metrics>,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: usize) -> Result<Self> {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool { threads: Arc::new(Running::default()), metrics: Arc::new(Metrics::default()) })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
+        let running = self.threads.guard();
+        let job = self.metrics.track(job);
         thread::spawn(move || {
+            let _running = running;
             job();
         });
     }
+
+    fn join(self) {
+        self.threads.wait(None);
+    }
+
+    /// Jobs start as soon as they are spawned, so none is ever dropped.
+    fn shutdown(self, timeout: Duration) -> bool {
+        self.threads.wait(Some(Instant::now() + timeout))
+    }
+
+    /// A job gets a thread of its own, so no thread is ever idle.
+    fn stats(&self) -> PoolStats {
+        self.metrics.stats(0)
+    }
 }
@@ -1,23 +1,73 @@
 use super::Result;
-use super::ThreadPool;
+use super::{Metrics, PoolStats, Running, ThreadPool};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
+    // spawned jobs that did not return yet.
+    jobs: Arc<Running>,
+    threads: Arc<Running>,
+    // set by `shutdown`, the jobs that did not start yet return without running.
+    cancelled: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
 }
 
 impl ThreadPool for RayonThreadPool {
     fn new(threads: usize) -> Result<Self> {
+        let running = Arc::new(Running::default());
+        let exited = Arc::clone(&running);
         let pool = rayon::ThreadPoolBuilder::new()
             .num_threads(threads)
+            .exit_handler(move |_| exited.finish())
+            // the panic is counted by the job, without a handler rayon aborts the process.
+            .panic_handler(|_| {})
             .build()
             .expect("RayonThreadPool was not created");
-        Ok(RayonThreadPool { pool })
+        // the threads only exit once the pool is dropped.
+        for _ in 0..pool.current_num_threads() {
+            running.start();
+        }
+        Ok(RayonThreadPool {
+            pool,
+            jobs: Arc::new(Running::default()),
+            threads: running,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            metrics: Arc::new(Metrics::default()),
+        })
     }
 
     fn spawn<F>(&self, job: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.spawn(job)
+        let running = self.jobs.guard();
+        let cancelled = Arc::clone(&self.cancelled);
+        let job = self.metrics.track(job);
+        self.pool.spawn(move || {
+            let _running = running;
+            if !cancelled.load(Ordering::SeqCst) {
+                job();
+            }
+        })
+    }
+
+    fn join(self) {
+        self.jobs.wait(None);
+        drop(self.pool);
+        self.threads.wait(None);
+    }
+
+    fn shutdown(self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        self.cancelled.store(true, Ordering::SeqCst);
+        let jobs_done = self.jobs.wait(Some(deadline));
+        drop(self.pool);
+        jobs_done && self.threads.wait(Some(deadline))
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.metrics.stats(self.pool.current_num_threads())
     }
 }
@@ -1,9 +1,13 @@
 use super::Result;
-use super::ThreadPool;
-use std::sync::mpsc;
+use super::{Metrics, PoolStats, Running, ThreadPool};
+use crate::KvStoreError;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Condvar, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 enum Message {
@@ -11,6 +15,32 @@ enum Message {
     Terminate,
 }
 
+/// What a `SharedQueueThreadPool` with a bounded queue does with a job spawned
+/// while the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueuePolicy {
+    /// `spawn` and `try_spawn` wait until a worker takes a job off the queue.
+    Block,
+    /// `try_spawn` fails with `KvStoreError::QueueFull`, `spawn` drops the job.
+    Reject,
+    /// the oldest queued job is dropped to make room for the new one.
+    DropOldest,
+}
+
+/// Parses `block`, `reject` or `drop-oldest`.
+impl FromStr for QueuePolicy {
+    type Err = KvStoreError;
+
+    fn from_str(s: &str) -> Result<QueuePolicy> {
+        match s {
+            "block" => Ok(QueuePolicy::Block),
+            "reject" => Ok(QueuePolicy::Reject),
+            "drop-oldest" => Ok(QueuePolicy::DropOldest),
+            _ => Err(KvStoreError::InvalidQueuePolicy(s.to_owned())),
+        }
+    }
+}
+
 // storing an queue vector of workers does not work well if we want to check when a thread has panicked and we
 // want to recreate a new one. The reason of this is because SharedQueueThreadPool does not allow to workers to be
 // destroyed and, therefore, called the Drop trait.
@@ -22,73 +52,169 @@ enum Message {
 // Also I tried to create an kind of sentinel, but the fact to share the queue vec of workers was to much difficult.
 
 pub struct SharedQueueThreadPool {
-    sender: mpsc::Sender<Message>,
+    size: usize,
+    queue: Arc<Queue>,
+    metrics: Arc<Metrics>,
+    // set once the workers were told to terminate, so `Drop` does not tell them again.
+    terminated: bool,
+}
+
+// The state shared by the workers of a pool.
+struct Queue {
+    messages: Mutex<VecDeque<Message>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    // maximum number of queued jobs, `None` for an unbounded queue.
+    capacity: Option<usize>,
+    policy: QueuePolicy,
+    // the worker that replaces a panicked one is counted as the same worker.
+    workers: Running,
+    // set by `shutdown`, the queued jobs are dropped instead of run.
+    cancelled: AtomicBool,
+}
+
+impl Queue {
+    // Queues the job according to the policy, gives it back when it is rejected.
+    fn push(&self, job: Job) -> std::result::Result<(), Job> {
+        let mut messages = self.messages.lock().unwrap();
+        if let Some(capacity) = self.capacity {
+            while messages.len() >= capacity {
+                match self.policy {
+                    QueuePolicy::Block => messages = self.not_full.wait(messages).unwrap(),
+                    QueuePolicy::Reject => return Err(job),
+                    QueuePolicy::DropOldest => {
+                        messages.pop_front();
+                    }
+                }
+            }
+        }
+        messages.push_back(Message::NewJob(job));
+        self.not_empty.notify_one();
+        Ok(())
+    }
+
+    // Terminate messages are never refused, whatever the capacity.
+    fn terminate(&self, workers: usize) {
+        let mut messages = self.messages.lock().unwrap();
+        for _ in 0..workers {
+            messages.push_back(Message::Terminate);
+        }
+        self.not_empty.notify_all();
+    }
+
+    fn pop(&self) -> Message {
+        let mut messages = self.messages.lock().unwrap();
+        loop {
+            match messages.pop_front() {
+                Some(message) => {
+                    self.not_full.notify_one();
+                    return message;
+                }
+                None => messages = self.not_empty.wait(messages).unwrap(),
+            }
+        }
+    }
 }
 
-struct Worker(Arc<Mutex<mpsc::Receiver<Message>>>);
+struct Worker(Arc<Queue>);
 
 impl Drop for Worker {
     fn drop(&mut self) {
+        // the panic is counted in the stats of the pool.
         if thread::panicking() {
-            println!("dropped while unwinding");
             let a = Worker(self.0.clone());
             run_tasks(a);
         } else {
-            println!("dropped while not unwinding");
+            self.0.workers.finish();
         }
     }
 }
 
 fn run_tasks(rx: Worker) {
-    thread::spawn(move || loop {
-        let message =
-            rx.0.lock()
-                .expect("Worker thread unable to lock job_receiver")
-                .recv();
-        match message {
-            Ok(message) => match message {
-                Message::NewJob(job) => {
-                    println!("Worker got a job; executing");
-                    job();
-                }
-                Message::Terminate => {
-                    println!("Worker was told to terminate");
-                    break;
-                }
-            },
-            Err(_) => {
-                println!("Thread exists becuase the thread pool is destroyed");
-                break;
+    thread::spawn(move || {
+        while let Message::NewJob(job) = rx.0.pop() {
+            if !rx.0.cancelled.load(Ordering::SeqCst) {
+                job();
             }
         }
     });
 }
 
+impl SharedQueueThreadPool {
+    /// Creates a pool whose queue holds at most `capacity` jobs, `policy` decides
+    /// what happens to the jobs spawned while it is full.
+    pub fn with_queue(size: usize, capacity: usize, policy: QueuePolicy) -> Result<Self> {
+        Ok(SharedQueueThreadPool::start(size, Some(capacity), policy))
+    }
+
+    fn start(size: usize, capacity: Option<usize>, policy: QueuePolicy) -> Self {
+        let queue = Arc::new(Queue {
+            messages: Mutex::new(VecDeque::new()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity,
+            policy,
+            workers: Running::default(),
+            cancelled: AtomicBool::new(false),
+        });
+        for _ in 0..size {
+            queue.workers.start();
+            let a = Worker(queue.clone());
+            run_tasks(a);
+        }
+        SharedQueueThreadPool { size, queue, metrics: Arc::new(Metrics::default()), terminated: false }
+    }
+
+    // Tells every worker to exit once the jobs queued before are done.
+    fn terminate(&mut self) {
+        if self.terminated {
+            return;
+        }
+        self.terminated = true;
+        self.queue.terminate(self.size);
+    }
+}
+
 impl Drop for SharedQueueThreadPool {
     fn drop(&mut self) {
-        println!("Shutting down all workers");
-        for _ in 0..4 {
-            self.sender.send(Message::Terminate).unwrap();
-        }
+        self.terminate();
     }
 }
 
 impl ThreadPool for SharedQueueThreadPool {
+    /// Creates a pool with an unbounded queue.
     fn new(size: usize) -> Result<Self> {
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        for _ in 0..size {
-            let a = Worker(receiver.clone());
-            run_tasks(a);
-        }
-        Ok(SharedQueueThreadPool { sender })
+        Ok(SharedQueueThreadPool::start(size, None, QueuePolicy::Block))
     }
 
     fn spawn<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
-        self.sender.send(Message::NewJob(job)).unwrap();
+        if self.queue.push(Box::new(self.metrics.track(f))).is_err() {
+            eprintln!("Job queue is full, job dropped");
+        }
+    }
+
+    fn try_spawn<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.queue.push(Box::new(self.metrics.track(f))).map_err(|_| KvStoreError::QueueFull)
+    }
+
+    fn join(mut self) {
+        self.terminate();
+        self.queue.workers.wait(None);
+    }
+
+    fn shutdown(mut self, timeout: Duration) -> bool {
+        self.queue.cancelled.store(true, Ordering::SeqCst);
+        self.terminate();
+        self.queue.workers.wait(Some(Instant::now() + timeout))
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.metrics.stats(self.size)
     }
 }
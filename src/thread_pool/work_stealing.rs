@@ -0,0 +1,158 @@
+use super::Result;
+use super::{Metrics, PoolStats, Running, ThreadPool};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Every worker takes batches of jobs from a global queue into a deque of its own,
+/// and steals from the deques of the others once its deque and the queue are empty.
+///
+/// Workers only touch the global queue when they run out of jobs, instead of taking
+/// a lock for every job, and `spawn` only takes a lock when a worker sleeps.
+pub struct WorkStealingThreadPool {
+    shared: Arc<Shared>,
+    metrics: Arc<Metrics>,
+}
+
+struct Shared {
+    injector: Injector<Job>,
+    stealers: Vec<Stealer<Job>>,
+    // idle workers wait on `wake`, `idle` is only locked to not miss a wake up.
+    idle: Mutex<()>,
+    wake: Condvar,
+    // workers waiting on `wake` or about to, nobody is woken up while it is 0.
+    sleeping: AtomicUsize,
+    // set by `join`, `shutdown` and `drop`, workers exit once they find no job.
+    terminated: AtomicBool,
+    // set by `shutdown`, the jobs that did not start yet are dropped.
+    cancelled: AtomicBool,
+    workers: Running,
+}
+
+impl Shared {
+    fn find_job(&self, local: &Worker<Job>) -> Option<Job> {
+        local.pop().or_else(|| {
+            iter::repeat_with(|| {
+                self.injector
+                    .steal_batch_and_pop(local)
+                    .or_else(|| self.stealers.iter().map(Stealer::steal).collect())
+            })
+            .find(|steal| !steal.is_retry())
+            .and_then(Steal::success)
+        })
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+
+    // Wakes up a sleeping worker, if any, to run a job that was just queued.
+    fn wake_one(&self) {
+        // pairs with the fence of a worker going to sleep: either the worker finds the
+        // job, or it was counted before the job was looked for.
+        atomic::fence(Ordering::SeqCst);
+        if self.sleeping.load(Ordering::SeqCst) > 0 {
+            let _idle = self.idle.lock().unwrap();
+            self.wake.notify_one();
+        }
+    }
+
+    fn terminate(&self) {
+        self.terminated.store(true, Ordering::SeqCst);
+        let _idle = self.idle.lock().unwrap();
+        self.wake.notify_all();
+    }
+}
+
+fn run_worker(shared: Arc<Shared>, local: Worker<Job>) {
+    loop {
+        match shared.find_job(&local) {
+            Some(job) => {
+                // the rest of a batch taken from the global queue is left for a sleeping
+                // worker to steal, instead of waiting behind this job.
+                if !local.is_empty() {
+                    shared.wake_one();
+                }
+                if shared.cancelled.load(Ordering::SeqCst) {
+                    continue;
+                }
+                // a panicking job does not take its worker, and the jobs in its deque, down.
+                if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                    eprintln!("Job panicked");
+                }
+            }
+            None => {
+                let idle = shared.idle.lock().unwrap();
+                shared.sleeping.fetch_add(1, Ordering::SeqCst);
+                atomic::fence(Ordering::SeqCst);
+                let has_jobs = shared.has_jobs();
+                let terminated = shared.terminated.load(Ordering::SeqCst);
+                if !has_jobs && !terminated {
+                    let _idle = shared.wake.wait(idle).unwrap();
+                }
+                shared.sleeping.fetch_sub(1, Ordering::SeqCst);
+                if terminated && !has_jobs {
+                    break;
+                }
+            }
+        }
+    }
+    shared.workers.finish();
+}
+
+impl Drop for WorkStealingThreadPool {
+    fn drop(&mut self) {
+        self.shared.terminate();
+    }
+}
+
+impl ThreadPool for WorkStealingThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let locals: Vec<Worker<Job>> = (0..threads).map(|_| Worker::new_fifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: locals.iter().map(Worker::stealer).collect(),
+            idle: Mutex::new(()),
+            wake: Condvar::new(),
+            sleeping: AtomicUsize::new(0),
+            terminated: AtomicBool::new(false),
+            cancelled: AtomicBool::new(false),
+            workers: Running::default(),
+        });
+        for local in locals {
+            shared.workers.start();
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || run_worker(shared, local));
+        }
+        Ok(WorkStealingThreadPool { shared, metrics: Arc::new(Metrics::default()) })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.injector.push(Box::new(self.metrics.track(job)));
+        self.shared.wake_one();
+    }
+
+    fn join(self) {
+        self.shared.terminate();
+        self.shared.workers.wait(None);
+    }
+
+    fn shutdown(self, timeout: Duration) -> bool {
+        self.shared.cancelled.store(true, Ordering::SeqCst);
+        self.shared.terminate();
+        self.shared.workers.wait(Some(Instant::now() + timeout))
+    }
+
+    fn stats(&self) -> PoolStats {
+        self.metrics.stats(self.shared.stealers.len())
+    }
+}
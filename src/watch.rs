@@ -0,0 +1,132 @@
+use super::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+
+/// A change of a watched key.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WatchEvent {
+    /// Increases with every change published by the server.
+    pub seq: u64,
+    pub key: Vec<u8>,
+    /// The new value, `None` when the key was removed.
+    pub value: Option<Vec<u8>>,
+}
+
+// the writes of keys sharing a lock are serialized.
+const KEY_LOCKS: usize = 64;
+
+/// How many events a subscriber may leave unsent before it is dropped, so a client that
+/// does not read its watch does not make the server buffer every change.
+pub(crate) const WATCH_BACKLOG: usize = 1024;
+
+/// The error a watch dropped for falling behind ends with.
+pub(crate) const WATCH_LAGGED: &str = "The watch fell behind the changes and was dropped";
+
+/// The connections watching keys of a server.
+///
+/// Writes go through `write` so the events of a key are published in the order
+/// the engine applied the writes.
+#[derive(Debug)]
+pub(crate) struct Watchers {
+    state: Mutex<WatchState>,
+    // held across the writes of the keys hashing to them, writes of other keys run
+    // and are synced along with them.
+    key_locks: Vec<Mutex<()>>,
+}
+
+impl Default for Watchers {
+    fn default() -> Watchers {
+        Watchers {
+            state: Mutex::default(),
+            key_locks: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct WatchState {
+    seq: u64,
+    next_id: u64,
+    subscribers: Vec<Subscriber>,
+}
+
+struct Subscriber {
+    id: u64,
+    prefix: Vec<u8>,
+    // returns false once the connection that watches is gone or fell behind.
+    send: Box<dyn Fn(WatchEvent) -> bool + Send>,
+}
+
+impl fmt::Debug for Subscriber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Subscriber").field("id", &self.id).field("prefix", &self.prefix).finish()
+    }
+}
+
+impl Watchers {
+    /// Returns the id of the subscription and the events of the keys starting with `prefix`.
+    ///
+    /// The receiver is disconnected once `WATCH_BACKLOG` events are waiting in it.
+    pub(crate) fn subscribe(&self, prefix: Vec<u8>) -> (u64, Receiver<WatchEvent>) {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_BACKLOG);
+        let id = self.subscribe_with(prefix, move |event| sender.try_send(event).is_ok());
+        (id, receiver)
+    }
+
+    /// Returns the id of the subscription, `send` is called with the events of the keys
+    /// starting with `prefix` and returns false to unsubscribe.
+    pub(crate) fn subscribe_with<F>(&self, prefix: Vec<u8>, send: F) -> u64
+    where
+        F: Fn(WatchEvent) -> bool + Send + 'static,
+    {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.subscribers.push(Subscriber { id, prefix, send: Box::new(send) });
+        id
+    }
+
+    pub(crate) fn unsubscribe(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.subscribers.retain(|subscriber| subscriber.id != id);
+    }
+
+    /// Runs a write of `keys` that returns its result and the keys it changed, and
+    /// publishes those changes.
+    pub(crate) fn write<'k, T, K, F>(&self, keys: K, write: F) -> Result<T>
+    where
+        K: IntoIterator<Item = &'k [u8]>,
+        F: FnOnce() -> Result<(T, Vec<(Vec<u8>, Option<Vec<u8>>)>)>,
+    {
+        // the locks of the keys are held during the write, so two writes of a key can
+        // not publish their events in the opposite order, even when somebody starts
+        // watching in between. They are taken in order so
+        // two writes of several keys can not wait for each other.
+        let mut locks: Vec<usize> = keys.into_iter().map(key_lock).collect();
+        locks.sort_unstable();
+        locks.dedup();
+        let _guards: Vec<_> = locks.into_iter().map(|lock| self.key_locks[lock].lock().unwrap()).collect();
+        let (result, changes) = write()?;
+        let mut state = self.state.lock().unwrap();
+        for (key, value) in changes {
+            state.seq += 1;
+            let event = WatchEvent { seq: state.seq, key, value };
+            // a receiver that is gone belongs to a closed connection, one that is full
+            // to a client that does not keep up.
+            state.subscribers.retain(|subscriber| {
+                !event.key.starts_with(&subscriber.prefix) || (subscriber.send)(event.clone())
+            });
+        }
+        Ok(result)
+    }
+}
+
+fn key_lock(key: &[u8]) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % KEY_LOCKS as u64) as usize
+}
@@ -0,0 +1,125 @@
+mod common;
+
+use kvs::{AsyncKvsClient, KvStore, KvStoreError, KvsClient, KvsEngine, Protocol, Result, ScanOptions, SharedQueueThreadPool, ThreadPool, WriteBatch};
+use std::time::Duration;
+
+#[tokio::test]
+async fn async_client_requests() -> Result<()> {
+    let server = common::start_async_server();
+    let addr = server.addr;
+    let mut client = AsyncKvsClient::connect(addr).await?;
+
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(client.get("key2".to_owned()).await?, None);
+    client.rm("key1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, None);
+    assert!(client.rm("key1".to_owned()).await.is_err());
+
+    client.set_with_ttl(b"session".to_vec(), b"token".to_vec(), Duration::from_secs(60)).await?;
+    assert!(client.ttl(b"session".to_vec()).await?.unwrap() <= Duration::from_secs(60));
+    assert!(matches!(client.ttl(b"missing".to_vec()).await, Err(KvStoreError::KeyNotFound)));
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"a1".to_vec(), b"1".to_vec());
+    batch.set(b"a2".to_vec(), b"2".to_vec());
+    batch.set(b"b1".to_vec(), b"3".to_vec());
+    client.write_batch(batch).await?;
+    assert!(client.compare_and_swap(b"a1".to_vec(), Some(b"1".to_vec()), Some(b"10".to_vec())).await?);
+    assert!(!client.compare_and_swap(b"a1".to_vec(), Some(b"1".to_vec()), None).await?);
+    assert_eq!(
+        client.scan_prefix(b"a".to_vec(), ScanOptions::default()).await?,
+        vec![(b"a1".to_vec(), b"10".to_vec()), (b"a2".to_vec(), b"2".to_vec())]
+    );
+
+    client.begin().await?;
+    client.set("txn".to_owned(), "1".to_owned()).await?;
+    assert_eq!(client.get("txn".to_owned()).await?, Some("1".to_owned()));
+    client.abort().await?;
+    assert_eq!(client.get("txn".to_owned()).await?, None);
+    Ok(())
+}
+
+// Both servers speak the same protocol, so both clients work with both of them.
+#[test]
+fn sync_client_async_server() -> Result<()> {
+    let server = common::start_async_server();
+    let addr = server.addr;
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    client.set("alice".to_owned(), "10".to_owned())?;
+
+    client.begin()?;
+    client.get("alice".to_owned())?;
+    client.set("bob".to_owned(), "5".to_owned())?;
+    other.set("alice".to_owned(), "6".to_owned())?;
+    assert!(matches!(client.commit(), Err(KvStoreError::TransactionConflict)));
+    assert_eq!(client.get("bob".to_owned())?, None);
+
+    let pairs: Vec<_> = client.scan_prefix(b"al".to_vec(), ScanOptions::default())?.collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![(b"alice".to_vec(), b"6".to_vec())]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn async_client_sync_server() -> Result<()> {
+    let server = common::start_server(SharedQueueThreadPool::new(2)?, Protocol::Kvs);
+    let addr = server.addr;
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    assert_eq!(client.stats().await?.active, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn async_watch() -> Result<()> {
+    let server = common::start_async_server();
+    let addr = server.addr;
+    let mut events = AsyncKvsClient::connect(addr).await?.watch(b"user:".to_vec()).await?;
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("user:1".to_owned(), "alice".to_owned()).await?;
+    client.set("other".to_owned(), "ignored".to_owned()).await?;
+    client.rm("user:1".to_owned()).await?;
+
+    let event = events.next().await.unwrap()?;
+    assert_eq!((event.key, event.value), (b"user:1".to_vec(), Some(b"alice".to_vec())));
+    let event = events.next().await.unwrap()?;
+    assert_eq!((event.key, event.value), (b"user:1".to_vec(), None));
+    Ok(())
+}
+
+// Idle connections hold no thread, so a few thousand of them do not stop the
+// server from answering.
+#[tokio::test]
+async fn many_idle_connections() -> Result<()> {
+    let server = common::start_async_server();
+    let addr = server.addr;
+    let mut idle = Vec::new();
+    for _ in 0..2000 {
+        idle.push(AsyncKvsClient::connect(addr).await?);
+    }
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set("key1".to_owned(), "value1".to_owned()).await?;
+    for client in idle.iter_mut().step_by(100) {
+        assert_eq!(client.get("key1".to_owned()).await?, Some("value1".to_owned()));
+    }
+    Ok(())
+}
+
+// A shutdown closes the idle connections and flushes the engine.
+#[test]
+fn async_graceful_shutdown() -> Result<()> {
+    let server = common::start_async_server();
+    let mut client = KvsClient::connect(server.addr)?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+
+    server.handle.shutdown();
+    server.stopped.recv_timeout(Duration::from_secs(10)).expect("server did not stop")?;
+    assert!(client.get("key1".to_owned()).is_err());
+
+    let store = KvStore::open(server.temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -0,0 +1,89 @@
+// Each test crate uses the fixtures it needs.
+#![allow(dead_code)]
+
+use kvs::{KvStore, KvsEngine, KvsServer, Protocol, Result, ShutdownHandle, ThreadPool};
+use std::net::SocketAddr;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// how long a server may take to bind its listeners.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running on a thread of its own, on ports the system picked. It lives
+/// until the test process exits unless it is shut down.
+pub struct TestServer {
+    /// the directory of the engine.
+    pub temp_dir: TempDir,
+    pub addr: SocketAddr,
+    /// the address of the HTTP gateway, the same as `addr` when there is none.
+    pub http_addr: SocketAddr,
+    pub handle: ShutdownHandle,
+    /// receives what `run` returned once the server stopped.
+    pub stopped: mpsc::Receiver<Result<()>>,
+}
+
+/// Starts a server on a `KvStore` in a new directory.
+pub fn start_server<T: ThreadPool + Send + Sync + 'static>(pool: T, protocol: Protocol) -> TestServer {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    start_server_with(temp_dir, store, pool, protocol, false)
+}
+
+/// Starts a server on a `KvStore` in a new directory, with its HTTP gateway.
+pub fn start_http_server<T: ThreadPool + Send + Sync + 'static>(pool: T) -> TestServer {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    start_server_with(temp_dir, store, pool, Protocol::Kvs, true)
+}
+
+/// Starts a server on `engine`, which stores its data in `temp_dir`.
+pub fn start_server_with<E, T>(temp_dir: TempDir, engine: E, pool: T, protocol: Protocol, http: bool) -> TestServer
+where
+    E: KvsEngine,
+    T: ThreadPool + Send + Sync + 'static,
+{
+    let server = KvsServer::with_protocol(engine, pool, protocol).unwrap();
+    let handle = server.shutdown_handle();
+    let any_port = "127.0.0.1:0".parse::<SocketAddr>().unwrap();
+    let (sender, stopped) = mpsc::channel();
+    thread::spawn(move || {
+        let result = if http { server.run_with_http(any_port, any_port) } else { server.run(any_port) };
+        let _ = sender.send(result);
+    });
+    listening(temp_dir, handle, stopped, if http { 2 } else { 1 })
+}
+
+/// Starts an async server on a `KvStore` in a new directory, on a runtime of its own.
+#[cfg(feature = "async")]
+pub fn start_async_server() -> TestServer {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).unwrap();
+    let server = kvs::AsyncKvsServer::new(store).unwrap();
+    let handle = server.shutdown_handle();
+    let (sender, stopped) = mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let _ = sender.send(runtime.block_on(server.run("127.0.0.1:0".parse().unwrap())));
+    });
+    listening(temp_dir, handle, stopped, 1)
+}
+
+// Waits until the server is bound to its `count` addresses, the connections made from
+// then on wait for it to accept them.
+fn listening(temp_dir: TempDir, handle: ShutdownHandle, stopped: mpsc::Receiver<Result<()>>, count: usize) -> TestServer {
+    let started = Instant::now();
+    loop {
+        let addrs = handle.listening_addrs();
+        if addrs.len() == count {
+            let (addr, http_addr) = (addrs[0], addrs[count - 1]);
+            return TestServer { temp_dir, addr, http_addr, handle, stopped };
+        }
+        if let Ok(result) = stopped.try_recv() {
+            panic!("server stopped before listening: {:?}", result);
+        }
+        assert!(started.elapsed() < START_TIMEOUT, "server not listening after {:?}", START_TIMEOUT);
+        thread::sleep(Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,192 @@
+mod common;
+
+use kvs::{KvStore, KvsClient, KvsEngine, Result, SharedQueueThreadPool, ThreadPool, WriteBatch};
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+// Reads a response, returns its status, its headers in lower case and its body.
+fn read_response<R: BufRead>(reader: &mut R) -> (u16, String, Vec<u8>) {
+    let mut status = String::new();
+    reader.read_line(&mut status).unwrap();
+    let status = status.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut headers = String::new();
+    let mut len = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line == "\r\n" {
+            break;
+        }
+        let line = line.to_ascii_lowercase();
+        if let Some(value) = line.strip_prefix("content-length: ") {
+            len = value.trim().parse().unwrap();
+        }
+        headers.push_str(&line);
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).unwrap();
+    (status, headers, body)
+}
+
+// Sends a request on a connection of its own.
+fn request(addr: SocketAddr, method: &str, target: &str, body: &[u8]) -> (u16, String, Vec<u8>) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", method, target, body.len()).unwrap();
+    stream.write_all(body).unwrap();
+    let response = read_response(&mut BufReader::new(&stream));
+    assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0, "the connection was not closed");
+    response
+}
+
+fn json_body(body: &[u8]) -> serde_json::Value {
+    serde_json::from_slice(body).unwrap()
+}
+
+#[test]
+fn http_key_value() -> Result<()> {
+    let server = common::start_http_server(SharedQueueThreadPool::new(4).unwrap());
+    let http_addr = server.http_addr;
+    assert_eq!(request(http_addr, "PUT", "/kv/user%3A1", b"alice").0, 204);
+    let (status, headers, body) = request(http_addr, "GET", "/kv/user:1", b"");
+    assert_eq!((status, body), (200, b"alice".to_vec()));
+    assert!(headers.contains("content-type: application/octet-stream"));
+
+    let (status, headers, body) = request(http_addr, "GET", "/kv/user:2", b"");
+    assert_eq!(status, 404);
+    assert!(headers.contains("content-type: application/json"));
+    assert_eq!(json_body(&body), json!({"error": "Key not found"}));
+
+    assert_eq!(request(http_addr, "DELETE", "/kv/user:1", b"").0, 204);
+    assert_eq!(request(http_addr, "DELETE", "/kv/user:1", b"").0, 404);
+    assert_eq!(request(http_addr, "GET", "/kv/user:1", b"").0, 404);
+
+    let (status, headers, _) = request(http_addr, "POST", "/kv/user:1", b"");
+    assert_eq!(status, 405);
+    assert!(headers.contains("allow: get, put, delete"));
+    assert_eq!(request(http_addr, "GET", "/other", b"").0, 404);
+    assert_eq!(request(http_addr, "GET", "/kv/%zz", b"").0, 400);
+    Ok(())
+}
+
+#[test]
+fn http_listing() -> Result<()> {
+    let server = common::start_http_server(SharedQueueThreadPool::new(4).unwrap());
+    let addr = server.addr;
+    let http_addr = server.http_addr;
+    for (key, value) in [("user:1", "alice"), ("user:2", "bob"), ("user:3", "carol"), ("other", "x")] {
+        assert_eq!(request(http_addr, "PUT", &format!("/kv/{}", key), value.as_bytes()).0, 204);
+    }
+    let (status, _, body) = request(http_addr, "GET", "/kv?prefix=user%3A&limit=2", b"");
+    assert_eq!(status, 200);
+    assert_eq!(json_body(&body), json!([{"key": "user:1", "value": "alice"}, {"key": "user:2", "value": "bob"}]));
+
+    let (_, _, body) = request(http_addr, "GET", "/kv", b"");
+    assert_eq!(json_body(&body).as_array().unwrap().len(), 4);
+    assert_eq!(request(http_addr, "GET", "/kv?limit=many", b"").0, 400);
+    assert_eq!(request(http_addr, "PUT", "/kv", b"").0, 405);
+
+    // bytes that are not UTF-8 are sent in base64 rather than replaced.
+    assert_eq!(request(http_addr, "PUT", "/kv/bin%FF", &[0, 0xfe, 0xff, b'a']).0, 204);
+    let (_, _, body) = request(http_addr, "GET", "/kv?prefix=bin", b"");
+    assert_eq!(json_body(&body), json!([{"key": "Ymlu/w==", "key_base64": true, "value": "AP7/YQ==", "value_base64": true}]));
+
+    // a page cut short by the limit tells where the next one starts.
+    let (_, headers, _) = request(http_addr, "GET", "/kv?prefix=user%3A&limit=2", b"");
+    assert!(headers.contains("x-next-after: user%3a2\r\n"), "{}", headers);
+    let (_, headers, body) = request(http_addr, "GET", "/kv?prefix=user%3A&limit=2&after=user%3A2", b"");
+    assert_eq!(json_body(&body), json!([{"key": "user:3", "value": "carol"}]));
+    assert!(!headers.contains("x-next-after"));
+    let (_, headers, _) = request(http_addr, "GET", "/kv?prefix=user%3A&limit=3", b"");
+    assert!(!headers.contains("x-next-after"));
+    // an `after` before the prefix starts the listing at the prefix.
+    let (_, _, body) = request(http_addr, "GET", "/kv?prefix=user%3A&limit=1&after=a", b"");
+    assert_eq!(json_body(&body), json!([{"key": "user:1", "value": "alice"}]));
+
+    // a listing without a limit stops at 1000 pairs, so does a larger limit.
+    let mut client = KvsClient::connect(addr)?;
+    let mut batch = WriteBatch::new();
+    for i in 0..1100 {
+        batch.set(format!("many:{:04}", i).into_bytes(), b"x".to_vec());
+    }
+    client.write_batch(batch)?;
+    let (_, _, body) = request(http_addr, "GET", "/kv?prefix=many%3A", b"");
+    assert_eq!(json_body(&body).as_array().unwrap().len(), 1000);
+    let (_, headers, body) = request(http_addr, "GET", "/kv?prefix=many%3A&limit=5000", b"");
+    assert_eq!(json_body(&body).as_array().unwrap().len(), 1000);
+    assert!(headers.contains("x-next-after: many%3a0999\r\n"), "{}", headers);
+    let (_, headers, body) = request(http_addr, "GET", "/kv?prefix=many%3A&after=many%3A0999", b"");
+    assert_eq!(json_body(&body).as_array().unwrap().len(), 100);
+    assert!(!headers.contains("x-next-after"));
+    Ok(())
+}
+
+// The gateway and the TCP clients share the engine and the watches.
+#[test]
+fn http_shares_engine() -> Result<()> {
+    let server = common::start_http_server(SharedQueueThreadPool::new(4).unwrap());
+    let addr = server.addr;
+    let http_addr = server.http_addr;
+    let mut events = KvsClient::connect(addr)?.watch(b"user:".to_vec())?;
+    let mut client = KvsClient::connect(addr)?;
+    client.set("user:1".to_owned(), "alice".to_owned())?;
+    assert_eq!(request(http_addr, "GET", "/kv/user:1", b"").2, b"alice".to_vec());
+
+    assert_eq!(request(http_addr, "PUT", "/kv/user:2", b"bob").0, 204);
+    assert_eq!(client.get("user:2".to_owned())?, Some("bob".to_owned()));
+
+    let event = events.next().unwrap()?;
+    assert_eq!((event.key, event.value), (b"user:1".to_vec(), Some(b"alice".to_vec())));
+    let event = events.next().unwrap()?;
+    assert_eq!((event.key, event.value), (b"user:2".to_vec(), Some(b"bob".to_vec())));
+    Ok(())
+}
+
+// HTTP/1.1 keeps the connection open, a request that can not be parsed closes it.
+#[test]
+fn http_keep_alive() -> Result<()> {
+    let server = common::start_http_server(SharedQueueThreadPool::new(4).unwrap());
+    let http_addr = server.http_addr;
+    let mut stream = TcpStream::connect(http_addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    stream.write_all(b"PUT /kv/a HTTP/1.1\r\nContent-Length: 1\r\n\r\n1GET /kv/a HTTP/1.1\r\n\r\n")?;
+    assert_eq!(read_response(&mut reader).0, 204);
+    assert_eq!(read_response(&mut reader), (200, "content-type: application/octet-stream\r\ncontent-length: 1\r\n".to_owned(), b"1".to_vec()));
+
+    stream.write_all(b"GET /kv/a\r\n\r\n")?;
+    let (status, headers, _) = read_response(&mut reader);
+    assert_eq!(status, 400);
+    assert!(headers.contains("connection: close"));
+    assert_eq!(reader.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+// A body shorter than announced is refused rather than waited for.
+#[test]
+fn http_short_body() -> Result<()> {
+    let server = common::start_http_server(SharedQueueThreadPool::new(4).unwrap());
+    let mut stream = TcpStream::connect(server.http_addr)?;
+    stream.write_all(b"PUT /kv/a HTTP/1.1\r\nContent-Length: 1000000\r\n\r\nabc")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let (status, headers, _) = read_response(&mut BufReader::new(&stream));
+    assert_eq!(status, 400);
+    assert!(headers.contains("connection: close"));
+    assert_eq!(request(server.http_addr, "GET", "/kv/a", b"").0, 404);
+    Ok(())
+}
+
+// A shutdown stops both listeners.
+#[test]
+fn http_graceful_shutdown() -> Result<()> {
+    let server = common::start_http_server(SharedQueueThreadPool::new(2)?);
+    let http_addr = server.http_addr;
+    assert_eq!(request(http_addr, "PUT", "/kv/key1", b"value1").0, 204);
+
+    server.handle.shutdown();
+    server.stopped.recv_timeout(Duration::from_secs(10)).expect("server did not stop")?;
+    assert!(TcpStream::connect(http_addr).is_err());
+    let store = KvStore::open(server.temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
@@ -1,6 +1,9 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{Durability, KvStore, KvStoreError, KvsEngine, KvsSnapshot, Result, ScanOptions, SledKvsEngine, WriteBatch};
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -209,3 +212,819 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// A `KvStore` is `Sync`, threads can share one handle as well as use clones of it.
+#[test]
+fn concurrent_get_shared_handle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+
+    let store = &store;
+    thread::scope(|scope| {
+        for thread_id in 0..10 {
+            scope.spawn(move || {
+                for i in 0..100 {
+                    let key_id = (i + thread_id) % 100;
+                    assert_eq!(store.get(format!("key{}", key_id)).unwrap(), Some(format!("value{}", key_id)));
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+// Reads and writes issued while a background compaction is running should see consistent data.
+#[test]
+fn compaction_concurrent_with_reads_and_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let reader = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for i in 0..10000 {
+                let key = format!("key{}", i % 100);
+                let value = store.get(key).unwrap();
+                assert!(value.is_some());
+            }
+        })
+    };
+    for iter in 1..200 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{:0>100}", iter))?;
+        }
+    }
+    reader.join().unwrap();
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{:0>100}", 199)));
+    }
+    Ok(())
+}
+
+// A log removed from under the store is an error, a get must not wait for a
+// compaction to move the entry.
+#[test]
+fn get_from_missing_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "log") {
+            std::fs::remove_file(path)?;
+        }
+    }
+
+    // a new handle has no open file to read the removed log from.
+    let handle = store.clone();
+    let (sender, result) = std::sync::mpsc::channel();
+    thread::spawn(move || sender.send(handle.get("key1".to_owned()).is_err()).unwrap());
+    assert!(result.recv_timeout(Duration::from_secs(5)).expect("get did not return"));
+    Ok(())
+}
+
+// Dropping the last handle waits for a running compaction, so the store can be opened
+// again right away without the compaction removing logs under the new handle.
+#[test]
+fn reopen_while_compacting() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value = |round: usize| format!("{:0>10000}", round);
+    for round in 0..10 {
+        let store = KvStore::open(temp_dir.path())?;
+        // every round writes a bit more than the compaction threshold, so the last
+        // writes of a round start a compaction which is still running at the drop.
+        for key_id in 0..110 {
+            store.set(format!("key{}", key_id), value(round))?;
+        }
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        for key_id in 0..110 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(value(round)));
+        }
+    }
+    Ok(())
+}
+
+// Logs written with the legacy JSON format should still be readable.
+#[test]
+fn open_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("1.log"),
+        r#"{"Set":{"key":"key1","value":"value1"}}{"Set":{"key":"key2","value":"value2"}}{"Rm":"key1"}"#,
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    // Open from disk again, the legacy and the binary log are both replayed
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A partial record at the end of a log, left by a crash in the middle of a write,
+// should be discarded when the store is opened.
+#[test]
+fn recover_torn_tail_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let good_len = std::fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(b"KV\x01\x01\x20\x00\x00\x00partial")?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log)?.len(), good_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A power loss can leave a tail of zeroes after the last record instead of a
+// partial one, it is discarded as well.
+#[test]
+fn recover_zero_filled_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let good_len = std::fs::metadata(&log)?.len();
+    let mut file = OpenOptions::new().append(true).open(&log)?;
+    file.write_all(&[0; 4096])?;
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(std::fs::metadata(&log)?.len(), good_len);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Corruption before the end of a log should be reported with its location.
+#[test]
+fn report_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let mut content = std::fs::read(&log)?;
+    let last = content.len() / 2 - 1;
+    content[last] ^= 0xff;
+    std::fs::write(&log, content)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvStoreError::CorruptedLog { log_id, offset, .. }) => {
+            assert_eq!(log_id, 1);
+            assert_eq!(offset, 0);
+        }
+        other => panic!("expected a corrupted log error, got {:?}", other.map(|_| ())),
+    }
+
+    Ok(())
+}
+
+// Concurrent writers sharing group commits should all be acknowledged and persisted.
+#[test]
+fn group_commit_concurrent_set() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let durability = Durability::GroupCommit { interval: Duration::from_millis(1) };
+    let store = KvStore::open_with_durability(temp_dir.path(), durability)?;
+    let mut handles = Vec::new();
+    for thread_id in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for i in 0..50 {
+                store
+                    .set(format!("key{}-{}", thread_id, i), format!("value{}", i))
+                    .unwrap();
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    drop(store);
+    let store = KvStore::open_with_durability(temp_dir.path(), Durability::Always)?;
+    for thread_id in 0..8 {
+        for i in 0..50 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, i))?,
+                Some(format!("value{}", i))
+            );
+        }
+    }
+    store.remove("key0-0".to_owned())?;
+    assert_eq!(store.get("key0-0".to_owned())?, None);
+
+    Ok(())
+}
+
+// After a compaction the store should be reopened from the hint file plus the logs written after it,
+// and fall back to replaying the logs when the hint is unusable.
+#[test]
+fn open_from_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let hint_exists = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension() == Some(std::ffi::OsStr::new("hint")))
+    };
+
+    let mut iter = 0;
+    while !hint_exists() {
+        iter += 1;
+        assert!(iter < 1000, "No compaction detected");
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    // let the background compaction retire the old logs
+    thread::sleep(Duration::from_millis(500));
+    store.set("key0".to_owned(), "latest".to_owned())?;
+    store.remove("key1".to_owned())?;
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("latest".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    for key_id in 2..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("{}", iter)));
+    }
+
+    // A corrupted hint is ignored
+    drop(store);
+    for entry in WalkDir::new(temp_dir.path()).into_iter().filter_map(|entry| entry.ok()) {
+        if entry.path().extension() == Some(std::ffi::OsStr::new("hint")) {
+            std::fs::write(entry.path(), b"garbage")?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0".to_owned())?, Some("latest".to_owned()));
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key999".to_owned())?, Some(format!("{}", iter)));
+
+    Ok(())
+}
+
+// Keys and values are arbitrary bytes
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let key = vec![0u8, 159, 146, 150, 255];
+    let value = vec![255u8, 0, 1, 2, 128];
+
+    store.set_bytes(key.clone(), value.clone())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+    store.set_bytes(b"text".to_vec(), value.clone())?;
+    assert!(store.get("text".to_owned()).is_err());
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(key.clone())?, Some(value));
+    store.remove_bytes(key.clone())?;
+    assert_eq!(store.get_bytes(key)?, None);
+
+    Ok(())
+}
+
+fn scan_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    for key in ["a", "ab", "abc", "b", "ba"] {
+        engine.set(key.to_owned(), format!("value-{}", key))?;
+    }
+    engine.set_bytes(vec![b'a', 0xff], b"high".to_vec())?;
+    engine.remove("ab".to_owned())?;
+
+    let keys = |pairs: kvs::KvPairs| -> Result<Vec<Vec<u8>>> {
+        pairs.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    let all = engine.scan(.., ScanOptions::default())?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 5);
+    assert_eq!(all[0], (b"a".to_vec(), b"value-a".to_vec()));
+    assert_eq!(
+        keys(engine.scan(b"ab".to_vec()..b"b".to_vec(), ScanOptions::default())?)?,
+        vec![b"abc".to_vec(), vec![b'a', 0xff]]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix(b"a".to_vec(), ScanOptions { limit: Some(2), reverse: true })?)?,
+        vec![vec![b'a', 0xff], b"abc".to_vec()]
+    );
+    assert_eq!(
+        keys(engine.scan_prefix(vec![b'a', 0xff], ScanOptions::default())?)?,
+        vec![vec![b'a', 0xff]]
+    );
+    assert_eq!(keys(engine.scan_prefix(b"c".to_vec(), ScanOptions::default())?)?.len(), 0);
+    assert_eq!(keys(engine.scan(b"b".to_vec()..b"a".to_vec(), ScanOptions::default())?)?.len(), 0);
+    assert_eq!(
+        engine.scan_keys(b"ab".to_vec().., ScanOptions { limit: Some(2), reverse: false })?.collect::<Result<Vec<_>>>()?,
+        vec![b"abc".to_vec(), vec![b'a', 0xff]]
+    );
+    assert_eq!(engine.scan_keys(b"b".to_vec()..b"a".to_vec(), ScanOptions::default())?.count(), 0);
+
+    Ok(())
+}
+
+// Scans return the live pairs ordered by key
+#[test]
+fn scan_range_and_prefix() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_engine(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn write_batch_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("key1".to_owned(), "old".to_owned())?;
+    engine.set("key2".to_owned(), "old".to_owned())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key1".to_vec(), b"new".to_vec())
+        .remove(b"key2".to_vec())
+        .set(b"key3".to_vec(), b"first".to_vec())
+        .set(b"key3".to_vec(), b"second".to_vec())
+        .remove(b"missing".to_vec());
+    engine.write_batch(batch)?;
+
+    assert_eq!(engine.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("second".to_owned()));
+    Ok(())
+}
+
+// A batch applies all of its writes
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_engine(KvStore::open(temp_dir.path())?)?;
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("second".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    write_batch_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A batch cut by a crash should be discarded entirely
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "old".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"key1".to_vec(), b"new".to_vec()).set(b"key2".to_vec(), b"new".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let len = std::fs::metadata(&log)?.len();
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 3)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Compaction keeps the keys written by batches
+#[test]
+fn compaction_of_write_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for iter in 0..200 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..100 {
+            batch.set(format!("key{}", key_id).into_bytes(), format!("{}", iter).into_bytes());
+        }
+        store.write_batch(batch)?;
+    }
+    // compaction runs in the background, wait until the old logs are gone.
+    for _ in 0..100 {
+        let logs = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some(std::ffi::OsStr::new("log")))
+            .count();
+        if logs <= 3 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("199".to_owned()));
+    }
+    Ok(())
+}
+
+fn compare_and_swap_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = b"lease".to_vec();
+    assert!(engine.compare_and_swap(key.clone(), None, Some(b"a".to_vec()))?);
+    assert!(!engine.compare_and_swap(key.clone(), None, Some(b"b".to_vec()))?);
+    assert!(!engine.compare_and_swap(key.clone(), Some(b"b".to_vec()), Some(b"c".to_vec()))?);
+    assert_eq!(engine.get_bytes(key.clone())?, Some(b"a".to_vec()));
+    assert!(engine.compare_and_swap(key.clone(), Some(b"a".to_vec()), None)?);
+    assert_eq!(engine.get_bytes(key.clone())?, None);
+    assert!(engine.compare_and_swap(key, None, None)?);
+
+    // concurrent increments of a counter never lose an update
+    let counter = b"counter".to_vec();
+    engine.set_bytes(counter.clone(), b"0".to_vec())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            let counter = counter.clone();
+            thread::spawn(move || {
+                for _ in 0..50 {
+                    loop {
+                        let current = engine.get_bytes(counter.clone()).unwrap().unwrap();
+                        let next: u64 = String::from_utf8(current.clone()).unwrap().parse::<u64>().unwrap() + 1;
+                        if engine
+                            .compare_and_swap(counter.clone(), Some(current), Some(next.to_string().into_bytes()))
+                            .unwrap()
+                        {
+                            break;
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(engine.get_bytes(counter)?, Some(b"200".to_vec()));
+    Ok(())
+}
+
+// Compare-and-swap only writes when the current value matches
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_engine(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn ttl_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set_with_ttl(b"session".to_vec(), b"token".to_vec(), Duration::from_millis(200))?;
+    engine.set_with_ttl(b"long".to_vec(), b"token".to_vec(), Duration::from_secs(3600))?;
+    engine.set_with_ttl(b"renewed".to_vec(), b"token".to_vec(), Duration::from_millis(200))?;
+    engine.set_bytes(b"renewed".to_vec(), b"permanent".to_vec())?;
+
+    assert_eq!(engine.get_bytes(b"session".to_vec())?, Some(b"token".to_vec()));
+    let ttl = engine.ttl(b"long".to_vec())?.expect("key without ttl");
+    assert!(ttl > Duration::from_secs(3500) && ttl <= Duration::from_secs(3600));
+    assert_eq!(engine.ttl(b"renewed".to_vec())?, None);
+
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get_bytes(b"session".to_vec())?, None);
+    assert!(matches!(engine.ttl(b"session".to_vec()), Err(KvStoreError::KeyNotFound)));
+    assert!(matches!(engine.remove_bytes(b"session".to_vec()), Err(KvStoreError::KeyNotFound)));
+    let keys: Vec<Vec<u8>> = engine
+        .scan(.., ScanOptions::default())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"long".to_vec(), b"renewed".to_vec()]);
+    assert_eq!(engine.get_bytes(b"renewed".to_vec())?, Some(b"permanent".to_vec()));
+
+    engine.set_with_ttl(b"session".to_vec(), b"token".to_vec(), Duration::from_millis(1))?;
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(engine.remove_expired()?, 1);
+    assert_eq!(engine.remove_expired()?, 0);
+    Ok(())
+}
+
+// Keys written with a ttl disappear once it elapsed
+#[test]
+fn ttl_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ttl_engine(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    ttl_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// The expiry time survives a restart and a compaction
+#[test]
+fn ttl_persistence() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(b"short".to_vec(), b"value".to_vec(), Duration::from_millis(100))?;
+    store.set_with_ttl(b"long".to_vec(), b"value".to_vec(), Duration::from_secs(3600))?;
+    drop(store);
+
+    thread::sleep(Duration::from_millis(200));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"short".to_vec())?, None);
+    assert!(store.ttl(b"long".to_vec())?.is_some());
+
+    // overwrite keys until a compaction, which writes a hint file, went through.
+    for iter in 0..2000 {
+        store.set(format!("key{}", iter % 10), "x".repeat(1000))?;
+    }
+    for _ in 0..100 {
+        let hints = WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some(std::ffi::OsStr::new("hint")))
+            .count();
+        if hints > 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    drop(store);
+    thread::sleep(Duration::from_millis(100));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"short".to_vec())?, None);
+    assert_eq!(store.get_bytes(b"long".to_vec())?, Some(b"value".to_vec()));
+    assert!(store.ttl(b"long".to_vec())?.is_some());
+    Ok(())
+}
+
+fn transaction_engine<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set("alice".to_owned(), "10".to_owned())?;
+    engine.set("bob".to_owned(), "0".to_owned())?;
+
+    // a transfer commits both writes
+    let mut txn = engine.begin()?;
+    assert_eq!(txn.get(b"alice".to_vec())?, Some(b"10".to_vec()));
+    txn.set(b"alice".to_vec(), b"7".to_vec());
+    txn.set(b"bob".to_vec(), b"3".to_vec());
+    txn.remove(b"carol".to_vec());
+    assert_eq!(txn.get(b"alice".to_vec())?, Some(b"7".to_vec()));
+    assert_eq!(engine.get("alice".to_owned())?, Some("10".to_owned()));
+    txn.commit()?;
+    assert_eq!(engine.get("alice".to_owned())?, Some("7".to_owned()));
+    assert_eq!(engine.get("bob".to_owned())?, Some("3".to_owned()));
+
+    // a write to a key read by the transaction makes the commit fail
+    let mut txn = engine.begin()?;
+    txn.get(b"alice".to_vec())?;
+    txn.get(b"dave".to_vec())?;
+    txn.set(b"bob".to_vec(), b"100".to_vec());
+    engine.set("alice".to_owned(), "8".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvStoreError::TransactionConflict)));
+    assert_eq!(engine.get("bob".to_owned())?, Some("3".to_owned()));
+
+    // so does the creation of a key read as missing
+    let mut txn = engine.begin()?;
+    txn.get(b"dave".to_vec())?;
+    txn.set(b"bob".to_vec(), b"100".to_vec());
+    engine.set("dave".to_owned(), "1".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvStoreError::TransactionConflict)));
+
+    // reads see the engine as it was at begin, even for keys first read after a write
+    let mut txn = engine.begin()?;
+    engine.set("dave".to_owned(), "2".to_owned())?;
+    assert_eq!(txn.get(b"dave".to_vec())?, Some(b"1".to_vec()));
+    assert!(matches!(txn.commit(), Err(KvStoreError::TransactionConflict)));
+
+    // of two transactions writing the same key without reading it, the first one to
+    // commit wins
+    let mut first = engine.begin()?;
+    let mut second = engine.begin()?;
+    first.set(b"carol".to_vec(), b"1".to_vec());
+    second.set(b"carol".to_vec(), b"2".to_vec());
+    first.commit()?;
+    assert!(matches!(second.commit(), Err(KvStoreError::TransactionConflict)));
+    assert_eq!(engine.get("carol".to_owned())?, Some("1".to_owned()));
+
+    // two transactions reading both keys and writing one each do not both commit,
+    // the write skew plain snapshot isolation would allow
+    let mut first = engine.begin()?;
+    let mut second = engine.begin()?;
+    for txn in [&mut first, &mut second] {
+        txn.get(b"alice".to_vec())?;
+        txn.get(b"bob".to_vec())?;
+    }
+    first.set(b"alice".to_vec(), b"0".to_vec());
+    second.set(b"bob".to_vec(), b"0".to_vec());
+    first.commit()?;
+    assert!(matches!(second.commit(), Err(KvStoreError::TransactionConflict)));
+    assert_eq!(engine.get("bob".to_owned())?, Some("3".to_owned()));
+
+    // a rolled back transaction writes nothing
+    let mut txn = engine.begin()?;
+    txn.set(b"bob".to_vec(), b"100".to_vec());
+    txn.rollback();
+    assert_eq!(engine.get("bob".to_owned())?, Some("3".to_owned()));
+    Ok(())
+}
+
+// Transactions read a snapshot and commit atomically unless a key they read or wrote changed
+#[test]
+fn transaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transaction_engine(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    transaction_engine(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Concurrent transfers retried on conflict never lose money
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1000".to_owned())?;
+    store.set("b".to_owned(), "1000".to_owned())?;
+
+    let balance = |value: Option<Vec<u8>>| -> i64 { String::from_utf8(value.unwrap()).unwrap().parse().unwrap() };
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                let (from, to) = if thread_id % 2 == 0 { (b"a", b"b") } else { (b"b", b"a") };
+                for _ in 0..50 {
+                    loop {
+                        let mut txn = store.begin().unwrap();
+                        let from_balance = balance(txn.get(from.to_vec()).unwrap());
+                        let to_balance = balance(txn.get(to.to_vec()).unwrap());
+                        txn.set(from.to_vec(), (from_balance - 1).to_string().into_bytes());
+                        txn.set(to.to_vec(), (to_balance + 1).to_string().into_bytes());
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(KvStoreError::TransactionConflict) => continue,
+                            Err(err) => panic!("{:?}", err),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    let a = balance(store.get_bytes(b"a".to_vec())?);
+    let b = balance(store.get_bytes(b"b".to_vec())?);
+    assert_eq!(a + b, 2000);
+    assert_eq!(a, 1000);
+    Ok(())
+}
+
+fn snapshot_engine<E: KvsEngine>(engine: &E) -> Result<E::Snapshot> {
+    engine.set("key1".to_owned(), "old".to_owned())?;
+    engine.set("key2".to_owned(), "old".to_owned())?;
+    let snapshot = engine.snapshot()?;
+    engine.set("key1".to_owned(), "new".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    engine.set("key3".to_owned(), "new".to_owned())?;
+
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key3".to_owned())?, None);
+    let keys: Vec<Vec<u8>> = snapshot
+        .scan(.., ScanOptions::default())?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec()]);
+    assert_eq!(engine.get("key1".to_owned())?, Some("new".to_owned()));
+    Ok(snapshot)
+}
+
+fn log_count(path: &std::path::Path) -> usize {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some(std::ffi::OsStr::new("log")))
+        .count()
+}
+
+// A snapshot keeps seeing the values of the time it was taken
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled = SledKvsEngine::open(temp_dir.path())?;
+    let snapshot = snapshot_engine(&sled)?;
+    // nor the keys written and expired after it was taken
+    sled.set_with_ttl(b"key2".to_vec(), b"ttl".to_vec(), Duration::from_millis(50))?;
+    thread::sleep(Duration::from_millis(100));
+    sled.remove_expired()?;
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("old".to_owned()));
+    let reversed: Vec<(Vec<u8>, Vec<u8>)> = snapshot.scan(.., ScanOptions { limit: Some(1), reverse: true })?.collect::<Result<_>>()?;
+    assert_eq!(reversed, vec![(b"key2".to_vec(), b"old".to_vec())]);
+    drop(snapshot);
+    assert_eq!(sled.get("key2".to_owned())?, None);
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let snapshot = snapshot_engine(&store)?;
+    assert!(snapshot.lsn() > 0);
+
+    // a compaction keeps the logs the snapshot reads
+    for iter in 0..2000 {
+        store.set(format!("filler{}", iter % 10), "x".repeat(1000))?;
+    }
+    for _ in 0..100 {
+        if WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().extension() == Some(std::ffi::OsStr::new("hint")))
+        {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    thread::sleep(Duration::from_millis(100));
+    assert!(temp_dir.path().join("1.log").exists());
+    assert_eq!(snapshot.get("key1".to_owned())?, Some("old".to_owned()));
+    assert_eq!(snapshot.get("key2".to_owned())?, Some("old".to_owned()));
+
+    let logs = log_count(temp_dir.path());
+    drop(snapshot);
+    assert!(!temp_dir.path().join("1.log").exists());
+    assert!(log_count(temp_dir.path()) < logs);
+    assert_eq!(store.get("key1".to_owned())?, Some("new".to_owned()));
+    Ok(())
+}
+
+// A snapshot taken while a writer runs sees every write made before it and none
+// made after, even the ones made while the snapshot is read.
+#[test]
+fn snapshot_concurrent_with_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_concurrent_with_writes_engine(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    snapshot_concurrent_with_writes_engine(SledKvsEngine::open_with_durability(temp_dir.path(), Durability::None)?)
+}
+
+fn snapshot_concurrent_with_writes_engine<E: KvsEngine>(store: E) -> Result<()> {
+    for key_id in 0..100 {
+        store.set(format!("filler{}", key_id), "x".to_owned())?;
+    }
+    let number = |value: Vec<u8>| -> u64 { String::from_utf8(value).unwrap().parse().unwrap() };
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            // "a" is always written before "z".
+            for i in 0..3000 {
+                store.set("a".to_owned(), i.to_string()).unwrap();
+                store.set("z".to_owned(), i.to_string()).unwrap();
+            }
+        })
+    };
+    while !writer.is_finished() {
+        let snapshot = store.snapshot()?;
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = snapshot.scan(.., ScanOptions::default())?.collect::<Result<_>>()?;
+        let (first, last) = (pairs.first().unwrap(), pairs.last().unwrap());
+        if first.0 != b"a" {
+            // taken before the first write.
+            assert_eq!(pairs.len(), 100);
+            continue;
+        }
+        let (a, z) = (number(first.1.clone()), last.0.as_slice().eq(b"z").then(|| number(last.1.clone())));
+        assert!(z.map_or(a == 0, |z| z == a || z + 1 == a), "a = {}, z = {:?}", a, z);
+        assert_eq!(snapshot.get_bytes(b"a".to_vec())?.map(number), Some(a));
+        assert_eq!(snapshot.get_bytes(b"z".to_vec())?.map(number), z);
+    }
+    writer.join().unwrap();
+    Ok(())
+}
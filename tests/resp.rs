@@ -0,0 +1,149 @@
+mod common;
+
+use common::TestServer;
+use kvs::{Protocol, Result, SharedQueueThreadPool, ThreadPool};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+fn start_server() -> (TestServer, TcpStream) {
+    let server = common::start_server(SharedQueueThreadPool::new(2).unwrap(), Protocol::Resp);
+    let stream = TcpStream::connect(server.addr).unwrap();
+    (server, stream)
+}
+
+// Sends `request` as it is and checks the bytes of the reply.
+fn assert_reply(stream: &mut TcpStream, request: &str, reply: &str) {
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut read = vec![0; reply.len()];
+    stream.read_exact(&mut read).unwrap();
+    assert_eq!(String::from_utf8_lossy(&read), reply, "reply to {:?}", request);
+}
+
+#[test]
+fn resp_commands() -> Result<()> {
+    let (_server, mut stream) = start_server();
+    assert_reply(&mut stream, "*1\r\n$4\r\nPING\r\n", "+PONG\r\n");
+    assert_reply(&mut stream, "*2\r\n$4\r\nping\r\n$5\r\nhello\r\n", "$5\r\nhello\r\n");
+
+    assert_reply(&mut stream, "*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$7\r\nval\r\nue\r\n", "+OK\r\n");
+    assert_reply(&mut stream, "*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n", "$7\r\nval\r\nue\r\n");
+    assert_reply(&mut stream, "*2\r\n$3\r\nGET\r\n$4\r\nkey2\r\n", "$-1\r\n");
+    assert_reply(&mut stream, "*5\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$1\r\n2\r\n$2\r\nEX\r\n$2\r\n60\r\n", "+OK\r\n");
+    assert_reply(&mut stream, "*4\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n$4\r\nkey3\r\n", ":2\r\n");
+    assert_reply(&mut stream, "*3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$4\r\nkey3\r\n", ":1\r\n");
+    assert_reply(&mut stream, "*2\r\n$6\r\nEXISTS\r\n$4\r\nkey1\r\n", ":0\r\n");
+
+    // inline commands, as typed in a terminal.
+    assert_reply(&mut stream, "GET key2\r\n", "$1\r\n2\r\n");
+    assert_reply(&mut stream, "\r\nPING\n", "+PONG\r\n");
+
+    assert_reply(&mut stream, "*1\r\n$3\r\nGET\r\n", "-ERR wrong number of arguments for 'get' command\r\n");
+    assert_reply(&mut stream, "*5\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n0\r\n", "-ERR invalid expire time in 'set' command\r\n");
+    assert_reply(&mut stream, "*4\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n$2\r\nNX\r\n", "-ERR syntax error\r\n");
+    assert_reply(&mut stream, "*1\r\n$6\r\nCONFIG\r\n", "-ERR unknown command 'CONFIG'\r\n");
+    Ok(())
+}
+
+// The cursor is the next key to visit, it is 0 once the scan is over.
+#[test]
+fn resp_scan() -> Result<()> {
+    let (_server, mut stream) = start_server();
+    for key in ["user:1", "user:2", "user:3", "other"] {
+        assert_reply(&mut stream, &format!("SET {} x\r\n", key), "+OK\r\n");
+    }
+    assert_reply(
+        &mut stream,
+        "*4\r\n$4\r\nSCAN\r\n$1\r\n0\r\n$5\r\nCOUNT\r\n$1\r\n2\r\n",
+        "*2\r\n$1\r\n1\r\n*2\r\n$5\r\nother\r\n$6\r\nuser:1\r\n",
+    );
+    // a key written before the cursor does not shift the keys after it.
+    assert_reply(&mut stream, "SET aaa x\r\n", "+OK\r\n");
+    assert_reply(
+        &mut stream,
+        "*4\r\n$4\r\nSCAN\r\n$1\r\n1\r\n$5\r\nCOUNT\r\n$1\r\n2\r\n",
+        "*2\r\n$1\r\n0\r\n*2\r\n$6\r\nuser:2\r\n$6\r\nuser:3\r\n",
+    );
+    assert_reply(&mut stream, "SCAN 0 MATCH user:[13]\r\n", "*2\r\n$1\r\n0\r\n*2\r\n$6\r\nuser:1\r\n$6\r\nuser:3\r\n");
+    assert_reply(&mut stream, "SCAN 0 MATCH *er*\r\n", "*2\r\n$1\r\n0\r\n*4\r\n$5\r\nother\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n$6\r\nuser:3\r\n");
+    assert_reply(&mut stream, "SCAN 0 MATCH *s?r*[0-9]\r\n", "*2\r\n$1\r\n0\r\n*3\r\n$6\r\nuser:1\r\n$6\r\nuser:2\r\n$6\r\nuser:3\r\n");
+    assert_reply(&mut stream, "SCAN x\r\n", "-ERR invalid cursor\r\n");
+    assert_reply(&mut stream, "SCAN 1999\r\n", "-ERR invalid cursor\r\n");
+
+    // a pattern of many stars against a long key that almost matches it.
+    let key = "a".repeat(100);
+    assert_reply(&mut stream, &format!("SET {} x\r\n", key), "+OK\r\n");
+    let start = std::time::Instant::now();
+    assert_reply(&mut stream, &format!("SCAN 0 MATCH {}b\r\n", "a*".repeat(30)), "*2\r\n$1\r\n0\r\n*0\r\n");
+    assert!(start.elapsed() < Duration::from_secs(1));
+    Ok(())
+}
+
+// Cursors stay small numbers however long the keys they resume from.
+#[test]
+fn resp_scan_long_keys() -> Result<()> {
+    let (_server, stream) = start_server();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let keys: Vec<String> = (0..9).map(|i| format!("{}{}", "k".repeat(100), i)).collect();
+    for key in &keys {
+        (&stream).write_all(format!("SET {} x\r\n", key).as_bytes())?;
+        let mut reply = String::new();
+        reader.read_line(&mut reply)?;
+        assert_eq!(reply, "+OK\r\n");
+    }
+    let (mut cursor, mut scanned) = ("0".to_owned(), Vec::new());
+    loop {
+        (&stream).write_all(format!("SCAN {} COUNT 2\r\n", cursor).as_bytes())?;
+        let mut lines = Vec::new();
+        for _ in 0..4 {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            lines.push(line.trim_end().to_owned());
+        }
+        assert_eq!(lines[0], "*2");
+        cursor = lines[2].clone();
+        assert!(cursor.parse::<u64>().is_ok(), "cursor {:?}", cursor);
+        let count: usize = lines[3].trim_start_matches('*').parse().unwrap();
+        for _ in 0..count * 2 {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            if !line.starts_with('$') {
+                scanned.push(line.trim_end().to_owned());
+            }
+        }
+        if cursor == "0" {
+            break;
+        }
+    }
+    assert_eq!(scanned, keys);
+    Ok(())
+}
+
+// Commands sent back to back are answered in order, garbage ends the connection.
+#[test]
+fn resp_pipeline_and_protocol_error() -> Result<()> {
+    let (_server, mut stream) = start_server();
+    assert_reply(&mut stream, "SET a 1\r\nSET b 2\r\nGET a\r\nGET b\r\n", "+OK\r\n+OK\r\n$1\r\n1\r\n$1\r\n2\r\n");
+
+    assert_reply(&mut stream, "*1\r\n+PING\r\n", "-ERR Protocol error: expected '$', got '+PING'\r\n");
+    assert_eq!(stream.read(&mut [0; 1])?, 0);
+    Ok(())
+}
+
+#[test]
+fn resp_info() -> Result<()> {
+    let (_server, stream) = start_server();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    (&stream).write_all(b"INFO\r\n")?;
+    let mut header = String::new();
+    reader.read_line(&mut header)?;
+    let len: usize = header.trim_start_matches('$').trim_end().parse().unwrap();
+    let mut info = vec![0; len + 2];
+    reader.read_exact(&mut info)?;
+    let info = String::from_utf8(info).unwrap();
+    assert!(info.starts_with("# Server\r\nkvs_version:"));
+    // the connection asking is the one job running.
+    assert!(info.contains("\r\nactive:1\r\n"));
+    assert!(info.contains("\r\nqueued:0\r\n"));
+    Ok(())
+}
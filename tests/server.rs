@@ -0,0 +1,460 @@
+mod common;
+
+use kvs::{Durability, KvStore, KvStoreError, KvsClient, KvsEngine, KvsServer, NaiveThreadPool, Protocol, QueuePolicy, Reply, Result, SharedQueueThreadPool, ThreadPool, WriteBatch};
+use serde_json::json;
+use std::io::{BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A transaction opened by a client only applies its writes on commit
+#[test]
+fn remote_transaction() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut client = KvsClient::connect(addr)?;
+    let mut other = KvsClient::connect(addr)?;
+    client.set("alice".to_owned(), "10".to_owned())?;
+
+    client.begin()?;
+    assert_eq!(client.get("alice".to_owned())?, Some("10".to_owned()));
+    client.set("alice".to_owned(), "5".to_owned())?;
+    client.set("bob".to_owned(), "5".to_owned())?;
+    assert_eq!(client.get("bob".to_owned())?, Some("5".to_owned()));
+    assert_eq!(other.get("bob".to_owned())?, None);
+    client.commit()?;
+    assert_eq!(other.get("alice".to_owned())?, Some("5".to_owned()));
+    assert_eq!(other.get("bob".to_owned())?, Some("5".to_owned()));
+
+    client.begin()?;
+    client.get("alice".to_owned())?;
+    client.set("bob".to_owned(), "0".to_owned())?;
+    other.set("alice".to_owned(), "6".to_owned())?;
+    assert!(matches!(client.commit(), Err(KvStoreError::TransactionConflict)));
+    assert_eq!(client.get("bob".to_owned())?, Some("5".to_owned()));
+
+    client.begin()?;
+    client.rm("bob".to_owned())?;
+    client.abort()?;
+    assert_eq!(client.get("bob".to_owned())?, Some("5".to_owned()));
+    assert!(client.commit().is_err());
+    Ok(())
+}
+
+// A watch streams the changes of the keys under its prefix in order
+#[test]
+fn watch_prefix() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut client = KvsClient::connect(addr)?;
+    let events = KvsClient::connect(addr)?.watch(b"config/".to_vec())?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for event in events {
+            if sender.send(event.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+
+    client.set("config/a".to_owned(), "1".to_owned())?;
+    client.set("other".to_owned(), "1".to_owned())?;
+    client.rm("config/a".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"config/b".to_vec(), b"2".to_vec()).set(b"other".to_vec(), b"2".to_vec());
+    client.write_batch(batch)?;
+    assert!(client.compare_and_swap(b"config/b".to_vec(), Some(b"2".to_vec()), Some(b"3".to_vec()))?);
+    assert!(!client.compare_and_swap(b"config/b".to_vec(), Some(b"2".to_vec()), Some(b"4".to_vec()))?);
+
+    let timeout = Duration::from_secs(5);
+    let changes: Vec<(Vec<u8>, Option<Vec<u8>>)> = (0..4)
+        .map(|_| receiver.recv_timeout(timeout).map(|event| (event.key, event.value)))
+        .collect::<std::result::Result<_, _>>()
+        .expect("missing watch event");
+    assert_eq!(
+        changes,
+        vec![
+            (b"config/a".to_vec(), Some(b"1".to_vec())),
+            (b"config/a".to_vec(), None),
+            (b"config/b".to_vec(), Some(b"2".to_vec())),
+            (b"config/b".to_vec(), Some(b"3".to_vec())),
+        ]
+    );
+    assert!(receiver.recv_timeout(Duration::from_millis(200)).is_err());
+    Ok(())
+}
+
+// Events carry increasing sequence numbers across watchers
+#[test]
+fn watch_sequence_numbers() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut client = KvsClient::connect(addr)?;
+    let mut first = KvsClient::connect(addr)?.watch(b"key".to_vec())?;
+    let mut second = KvsClient::connect(addr)?.watch(b"key2".to_vec())?;
+
+    client.set("key1".to_owned(), "a".to_owned())?;
+    client.set("key2".to_owned(), "b".to_owned())?;
+
+    let event1 = first.next().unwrap()?;
+    let event2 = first.next().unwrap()?;
+    assert_eq!(event1.key, b"key1".to_vec());
+    assert_eq!(event2.key, b"key2".to_vec());
+    assert!(event1.seq < event2.seq);
+    assert_eq!(second.next().unwrap()?, event2);
+    Ok(())
+}
+
+// A watch whose client does not read is dropped once it falls behind, the writes do
+// not wait for it
+#[test]
+fn watch_lagging_dropped() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut client = KvsClient::connect(addr)?;
+    let events = KvsClient::connect(addr)?.watch(b"big/".to_vec())?;
+
+    // more events than the socket buffers and the backlog of the watch hold.
+    for batch_id in 0..6 {
+        let mut batch = WriteBatch::new();
+        for key_id in 0..1000 {
+            batch.set(format!("big/{}/{}", batch_id, key_id).into_bytes(), vec![b'x'; 2 * 1024]);
+        }
+        client.write_batch(batch)?;
+    }
+
+    let results: Vec<_> = events.collect();
+    assert!(results.len() < 6000, "{} events", results.len());
+    match results.last() {
+        Some(Err(err)) => assert!(err.to_string().contains("fell behind"), "{}", err),
+        last => panic!("the watch did not end with an error: {:?}", last.map(|result| result.is_ok())),
+    }
+    assert!(results[..results.len() - 1].iter().all(|result| result.is_ok()));
+    assert_eq!(client.get("big/5/999".to_owned())?.map(|value| value.len()), Some(2 * 1024));
+    Ok(())
+}
+
+// While somebody watches, writes of different keys still wait for the same group
+// commit instead of one after the other
+#[test]
+fn watch_keeps_group_commit() -> Result<()> {
+    const WRITERS: usize = 8;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let interval = Duration::from_millis(200);
+    let store = KvStore::open_with_durability(temp_dir.path(), Durability::GroupCommit { interval })?;
+    let server = common::start_server_with(temp_dir, store, NaiveThreadPool::new(WRITERS)?, Protocol::Kvs, false);
+    let addr = server.addr;
+    let mut events = KvsClient::connect(addr)?.watch(b"key".to_vec())?;
+    let mut clients = (0..WRITERS).map(|_| KvsClient::connect(addr)).collect::<Result<Vec<_>>>()?;
+
+    let started = Instant::now();
+    let writers: Vec<_> = clients
+        .drain(..)
+        .enumerate()
+        .map(|(i, mut client)| thread::spawn(move || client.set(format!("key{}", i), "value".to_owned()).unwrap()))
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    assert!(started.elapsed() < interval * WRITERS as u32 / 2, "writes took {:?}", started.elapsed());
+
+    let mut seqs: Vec<u64> = (0..WRITERS).map(|_| events.next().unwrap().map(|event| event.seq)).collect::<Result<_>>()?;
+    seqs.dedup();
+    assert_eq!(seqs.len(), WRITERS);
+    Ok(())
+}
+
+// A shutdown stops accepting clients, ends the idle connections and flushes the engine
+#[test]
+fn graceful_shutdown() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let (addr, handle) = (server.addr, &server.handle);
+
+    let mut client = KvsClient::connect(addr)?;
+    let _idle = KvsClient::connect(addr)?;
+    let _watch = KvsClient::connect(addr)?.watch(b"key".to_vec())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+
+    handle.shutdown();
+    assert!(handle.is_shutdown());
+    server.stopped.recv_timeout(Duration::from_secs(5)).expect("server still running")?;
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(KvsClient::connect(addr).is_err());
+
+    let store = KvStore::open(server.temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A server shut down before it runs returns right away
+#[test]
+fn shutdown_before_run() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let server = KvsServer::new(KvStore::open(temp_dir.path())?, NaiveThreadPool::new(1)?)?;
+    server.shutdown_handle().shutdown();
+    server.run("127.0.0.1:0".parse::<SocketAddr>().unwrap())
+}
+
+// A connection the pool has no room for is answered with an error
+#[test]
+fn server_busy() -> Result<()> {
+    let server = common::start_server(SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::Reject)?, Protocol::Kvs);
+    let addr = server.addr;
+
+    // the first connection holds the only worker, the second one waits in the queue.
+    let mut served = KvsClient::connect(addr)?;
+    served.set("key1".to_owned(), "value1".to_owned())?;
+    let _queued = KvsClient::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+
+    let mut rejected = KvsClient::connect(addr)?;
+    match rejected.get("key1".to_owned()) {
+        Err(KvStoreError::ServerResponseErr(err)) => assert_eq!(err, "Server busy"),
+        other => panic!("expected a busy server, got {:?}", other),
+    }
+    assert_eq!(served.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A queued connection evicted for a newer one is answered with an error too
+#[test]
+fn server_busy_drop_oldest() -> Result<()> {
+    let server = common::start_server(SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::DropOldest)?, Protocol::Kvs);
+    let addr = server.addr;
+
+    let mut served = KvsClient::connect(addr)?;
+    served.set("key1".to_owned(), "value1".to_owned())?;
+    let mut evicted = KvsClient::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+
+    let _queued = KvsClient::connect(addr)?;
+    match evicted.get("key1".to_owned()) {
+        Err(KvStoreError::ServerResponseErr(err)) => assert_eq!(err, "Server busy"),
+        other => panic!("expected a busy server, got {:?}", other),
+    }
+    assert_eq!(served.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Rejected connections are answered one at a time, the ones the rejector has no
+// room for are closed without an answer instead of waiting for one.
+#[test]
+fn server_busy_flood() -> Result<()> {
+    let server = common::start_server(SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::Reject)?, Protocol::Kvs);
+    let addr = server.addr;
+
+    let mut served = KvsClient::connect(addr)?;
+    served.set("key1".to_owned(), "value1".to_owned())?;
+    let _queued = KvsClient::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+
+    // silent clients keep the rejector waiting for their first bytes.
+    let flood: Vec<_> = (0..200).map(|_| TcpStream::connect(addr).unwrap()).collect();
+    let mut last = flood.last().unwrap();
+    last.set_read_timeout(Some(Duration::from_millis(500)))?;
+    let mut reply = Vec::new();
+    assert_eq!(last.read_to_end(&mut reply)?, 0);
+    assert_eq!(served.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Every connection is a job of the pool, the one asking for the stats included.
+#[test]
+fn remote_stats() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut first = KvsClient::connect(addr)?;
+    first.set("key1".to_owned(), "value1".to_owned())?;
+    drop(first);
+    thread::sleep(Duration::from_millis(100));
+
+    let mut client = KvsClient::connect(addr)?;
+    let stats = client.stats()?;
+    assert_eq!(stats.active, 1);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.completed, 1);
+    assert_eq!(stats.panics, 0);
+    assert!(stats.average_latency > Duration::default());
+    Ok(())
+}
+
+fn write_frame(stream: &mut TcpStream, id: u64, opcode: u8, payload: &[u8]) {
+    let mut frame = ((9 + payload.len()) as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.push(opcode);
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame).unwrap();
+}
+
+// Returns the id and the payload of the next frame, `None` once the server closed
+// the connection.
+fn read_frame(stream: &mut TcpStream) -> Option<(u64, Vec<u8>)> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len).ok()?;
+    let mut body = vec![0; u32::from_be_bytes(len) as usize];
+    stream.read_exact(&mut body).unwrap();
+    let mut id = [0; 8];
+    id.copy_from_slice(&body[..8]);
+    let id = u64::from_be_bytes(id);
+    assert_eq!(body[8], 0x80);
+    Some((id, body.split_off(9)))
+}
+
+// Like `read_frame` for the answer to a hello, which is in JSON.
+fn read_hello_reply(stream: &mut TcpStream) -> Option<(u64, serde_json::Value)> {
+    read_frame(stream).map(|(id, payload)| (id, serde_json::from_slice(&payload).unwrap()))
+}
+
+// A string in bincode: its length in a little endian u64, then its bytes.
+fn bincode_bytes(bytes: &[u8]) -> Vec<u8> {
+    let mut encoded = (bytes.len() as u64).to_le_bytes().to_vec();
+    encoded.extend_from_slice(bytes);
+    encoded
+}
+
+// Clients of the JSON protocol keep working, the first byte tells the protocols apart.
+#[test]
+fn legacy_json_client() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut stream = TcpStream::connect(addr)?;
+    let mut responses = serde_json::Deserializer::from_reader(stream.try_clone()?).into_iter::<serde_json::Value>();
+
+    stream.write_all(br#"{"Set":{"key":[107],"value":[118]}}"#)?;
+    assert_eq!(responses.next().unwrap()?, json!({"Ok": null}));
+    stream.write_all(br#"{"Get":[107]}"#)?;
+    assert_eq!(responses.next().unwrap()?, json!({"Ok": "v"}));
+    // bytes that are not UTF-8 stay an array.
+    stream.write_all(br#"{"Set":{"key":[98],"value":[255]}}"#)?;
+    assert_eq!(responses.next().unwrap()?, json!({"Ok": null}));
+    stream.write_all(br#"{"Get":"b"}"#)?;
+    assert_eq!(responses.next().unwrap()?, json!({"Ok": [255]}));
+    stream.write_all(br#"{"Ttl":[109]}"#)?;
+    assert_eq!(responses.next().unwrap()?, json!("KeyNotFound"));
+
+    // both protocols see the same store.
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("k".to_owned())?, Some("v".to_owned()));
+    Ok(())
+}
+
+// The exact bytes the clients written before keys and values were binary-safe send
+// and read, and a request that can not be decoded is answered before the server
+// closes the connection.
+#[test]
+fn baseline_json_client() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut stream = TcpStream::connect(addr)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut assert_reply = |request: &str, reply: &str| {
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut read = vec![0; reply.len()];
+        reader.read_exact(&mut read).unwrap();
+        assert_eq!(String::from_utf8_lossy(&read), reply, "reply to {}", request);
+    };
+    assert_reply(r#"{"Set":{"key":"a","value":"b"}}"#, r#"{"Ok":null}"#);
+    assert_reply(r#"{"Get":"a"}"#, r#"{"Ok":"b"}"#);
+    assert_reply(r#"{"Get":"c"}"#, r#"{"Ok":null}"#);
+    assert_reply(r#"{"Rm":"a"}"#, r#"{"Ok":null}"#);
+    assert_reply(r#"{"Rm":"a"}"#, r#"{"Err":"Key not found"}"#);
+
+    assert_reply(r#"{"Set":{"key":1}}"#, r#"{"Err":"Invalid request: "#);
+    let mut rest = Vec::new();
+    reader.read_to_end(&mut rest)?;
+    assert!(rest.ends_with(b"\"}"));
+    Ok(())
+}
+
+#[test]
+fn hello_negotiates_version() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(&mut stream, 7, 0, br#"{"min_version":1,"max_version":5}"#);
+    assert_eq!(read_hello_reply(&mut stream), Some((7, json!({"Hello": {"version": 2}}))));
+
+    // clients speaking only older or newer versions are refused and disconnected.
+    for hello in [br#"{"min_version":1,"max_version":1}"#, br#"{"min_version":3,"max_version":4}"#] {
+        let mut stream = TcpStream::connect(addr)?;
+        write_frame(&mut stream, 0, 0, hello);
+        let (id, response) = read_hello_reply(&mut stream).unwrap();
+        assert_eq!(id, 0);
+        assert!(response["Err"].as_str().unwrap().contains("Unsupported protocol versions"));
+        assert_eq!(read_frame(&mut stream), None);
+    }
+    Ok(())
+}
+
+// A request the server does not know is answered with an error, the requests after
+// it are served.
+#[test]
+fn unknown_opcode() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(&mut stream, 0, 0, br#"{"min_version":2,"max_version":2}"#);
+    read_hello_reply(&mut stream).unwrap();
+
+    // `Response::Err` is the variant 12, `Response::Value` the variant 2.
+    write_frame(&mut stream, 1, 0x42, b"");
+    let err = [&12u32.to_le_bytes()[..], &bincode_bytes(b"Unknown opcode 66")].concat();
+    assert_eq!(read_frame(&mut stream), Some((1, err)));
+
+    write_frame(&mut stream, 2, 1, &bincode_bytes(b"k"));
+    let no_value = [&2u32.to_le_bytes()[..], &[0]].concat();
+    assert_eq!(read_frame(&mut stream), Some((2, no_value)));
+    Ok(())
+}
+
+// A frame cut short by the client ends its connection, whatever length it announced
+#[test]
+fn truncated_frame() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut stream = TcpStream::connect(addr)?;
+    write_frame(&mut stream, 0, 0, br#"{"min_version":2,"max_version":2}"#);
+    read_hello_reply(&mut stream).unwrap();
+
+    stream.write_all(&(64 * 1024 * 1024u32).to_be_bytes())?;
+    stream.write_all(&[0, 0, 0, 0, 0, 0, 0, 1, 1])?;
+    stream.write_all(&bincode_bytes(b"k"))?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    assert_eq!(read_frame(&mut stream), None);
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("k".to_owned())?, None);
+    Ok(())
+}
+
+// More requests than the pipeline keeps in flight, the replies come in the order of
+// the requests.
+#[test]
+fn pipelined_requests() -> Result<()> {
+    let server = common::start_server(NaiveThreadPool::new(4)?, Protocol::Kvs);
+    let addr = server.addr;
+    let mut client = KvsClient::connect(addr)?;
+    let mut pipeline = client.pipeline();
+    for i in 0..3000 {
+        pipeline.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())?;
+    }
+    pipeline.get(b"key42".to_vec())?;
+    pipeline.rm(b"missing".to_vec())?;
+    pipeline.compare_and_swap(b"key1".to_vec(), Some(b"value1".to_vec()), None)?;
+    let mut replies = pipeline.execute()?;
+
+    assert!(matches!(replies.pop(), Some(Ok(Reply::Swapped(true)))));
+    assert!(matches!(replies.pop(), Some(Err(KvStoreError::KeyNotFound))));
+    assert_eq!(replies.pop().unwrap()?, Reply::Value(Some(b"value42".to_vec())));
+    assert_eq!(replies.len(), 3000);
+    assert!(replies.into_iter().all(|reply| matches!(reply, Ok(Reply::Done))));
+
+    // a pipeline dropped before `execute` leaves the connection usable.
+    client.pipeline().set(b"key1".to_vec(), b"again".to_vec())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("again".to_owned()));
+    Ok(())
+}
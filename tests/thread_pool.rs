@@ -1,8 +1,10 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 use kvs::thread_pool::*;
-use kvs::Result;
+use kvs::{KvStoreError, Result};
 
 use crossbeam_utils::sync::WaitGroup;
 
@@ -46,6 +48,164 @@ fn spawn_panic_task<P: ThreadPool>() -> Result<()> {
     spawn_counter(pool)
 }
 
+// `join` runs every queued job and returns once the workers exited.
+fn join_runs_queued_jobs<P: ThreadPool + Send + 'static>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..20 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        pool.join();
+        sender.send(()).unwrap();
+    });
+    receiver.recv_timeout(Duration::from_secs(5)).expect("join did not return");
+    assert_eq!(counter.load(Ordering::SeqCst), 20);
+    Ok(())
+}
+
+// `shutdown` lets the running jobs finish and gives up on the ones that outlive `timeout`.
+fn shutdown_waits_for_running_jobs<P: ThreadPool>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..2 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+    thread::sleep(Duration::from_millis(20));
+    assert!(pool.shutdown(Duration::from_secs(5)));
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+    let pool = P::new(2)?;
+    pool.spawn(|| thread::sleep(Duration::from_secs(1)));
+    thread::sleep(Duration::from_millis(20));
+    assert!(!pool.shutdown(Duration::from_millis(50)));
+    Ok(())
+}
+
+// `shutdown` drops the jobs that are still queued.
+fn shutdown_cancels_queued_jobs<P: ThreadPool>(pool: P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    for _ in 0..20 {
+        let counter = Arc::clone(&counter);
+        pool.spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+    thread::sleep(Duration::from_millis(20));
+    assert!(pool.shutdown(Duration::from_secs(5)));
+    assert_eq!(counter.load(Ordering::SeqCst), 2);
+    Ok(())
+}
+
+// `stats` counts the jobs that returned and the ones that panicked once they ended.
+fn stats_count_jobs<P: ThreadPool>(pool: P, workers: usize) -> Result<()> {
+    let release = occupy_worker(&pool);
+    let stats = pool.stats();
+    assert_eq!(stats.active, 1);
+    assert_eq!(stats.idle, workers.saturating_sub(1));
+    drop(release);
+
+    let wg = WaitGroup::new();
+    for i in 0..10 {
+        let wg = wg.clone();
+        pool.spawn(move || {
+            let _wg = wg;
+            if i % 2 == 0 {
+                panic_control::disable_hook_in_current_thread();
+                panic!();
+            }
+        })
+    }
+    wg.wait();
+    // the counters are updated once the job and its captures were dropped.
+    thread::sleep(Duration::from_millis(50));
+    let stats = pool.stats();
+    assert_eq!(stats.active, 0);
+    assert_eq!(stats.idle, workers);
+    assert_eq!(stats.queued, 0);
+    assert_eq!(stats.completed, 6);
+    assert_eq!(stats.panics, 5);
+    assert!(stats.average_latency > Duration::default());
+    Ok(())
+}
+
+// Starts a job that holds the only worker of the pool until the returned sender is dropped.
+fn occupy_worker<P: ThreadPool>(pool: &P) -> mpsc::Sender<()> {
+    let (release, released) = mpsc::channel::<()>();
+    let (started, is_started) = mpsc::channel();
+    pool.spawn(move || {
+        started.send(()).unwrap();
+        let _ = released.recv();
+    });
+    is_started.recv().unwrap();
+    release
+}
+
+// Spawns a job that records `id` once it runs.
+fn spawn_recorded(pool: &SharedQueueThreadPool, ran: &Arc<Mutex<Vec<usize>>>, id: usize) -> Result<()> {
+    let ran = Arc::clone(ran);
+    pool.try_spawn(move || ran.lock().unwrap().push(id))
+}
+
+#[test]
+fn bounded_queue_reject() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::Reject)?;
+    let ran = Arc::new(Mutex::new(Vec::new()));
+    let release = occupy_worker(&pool);
+    spawn_recorded(&pool, &ran, 1)?;
+    assert!(matches!(spawn_recorded(&pool, &ran, 2), Err(KvStoreError::QueueFull)));
+    drop(release);
+    pool.join();
+    assert_eq!(*ran.lock().unwrap(), vec![1]);
+    Ok(())
+}
+
+#[test]
+fn bounded_queue_drop_oldest() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::DropOldest)?;
+    let ran = Arc::new(Mutex::new(Vec::new()));
+    let release = occupy_worker(&pool);
+    spawn_recorded(&pool, &ran, 1)?;
+    spawn_recorded(&pool, &ran, 2)?;
+    drop(release);
+    pool.join();
+    assert_eq!(*ran.lock().unwrap(), vec![2]);
+    Ok(())
+}
+
+#[test]
+fn bounded_queue_block() -> Result<()> {
+    let pool = Arc::new(SharedQueueThreadPool::with_queue(1, 1, QueuePolicy::Block)?);
+    let ran = Arc::new(Mutex::new(Vec::new()));
+    let release = occupy_worker(&*pool);
+    spawn_recorded(&pool, &ran, 1)?;
+    let (spawned, is_spawned) = mpsc::channel();
+    let spawner = {
+        let pool = Arc::clone(&pool);
+        let ran = Arc::clone(&ran);
+        thread::spawn(move || {
+            spawn_recorded(&pool, &ran, 2).unwrap();
+            spawned.send(()).unwrap();
+        })
+    };
+    assert!(is_spawned.recv_timeout(Duration::from_millis(100)).is_err());
+    drop(release);
+    is_spawned.recv_timeout(Duration::from_secs(5)).expect("spawn still blocked");
+    spawner.join().unwrap();
+    Arc::try_unwrap(pool).ok().expect("pool still shared").join();
+    assert_eq!(*ran.lock().unwrap(), vec![1, 2]);
+    Ok(())
+}
+
 #[test]
 fn naive_thread_pool_spawn_counter() -> Result<()> {
     let pool = NaiveThreadPool::new(4)?;
@@ -64,7 +224,120 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
     spawn_counter(pool)
 }
 
+#[test]
+fn work_stealing_thread_pool_spawn_counter() -> Result<()> {
+    let pool = WorkStealingThreadPool::new(4)?;
+    spawn_counter(pool)
+}
+
+// Sleeping workers are woken up for the jobs another worker took in a batch, so jobs
+// that wait for each other all run
+#[test]
+fn work_stealing_thread_pool_wakes_idle_workers() -> Result<()> {
+    const THREADS: usize = 4;
+    let pool = WorkStealingThreadPool::new(THREADS)?;
+    thread::sleep(Duration::from_millis(100));
+
+    let barrier = Arc::new(std::sync::Barrier::new(THREADS));
+    let (sender, receiver) = mpsc::channel();
+    for _ in 0..THREADS {
+        let barrier = Arc::clone(&barrier);
+        let sender = sender.clone();
+        pool.spawn(move || {
+            barrier.wait();
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..THREADS {
+        receiver.recv_timeout(Duration::from_secs(5)).expect("jobs not running in parallel");
+    }
+    Ok(())
+}
+
 #[test]
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn work_stealing_thread_pool_panic_task() -> Result<()> {
+    spawn_panic_task::<WorkStealingThreadPool>()
+}
+
+#[test]
+fn naive_thread_pool_join() -> Result<()> {
+    join_runs_queued_jobs(NaiveThreadPool::new(4)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_join() -> Result<()> {
+    // more workers than the pool used to terminate.
+    join_runs_queued_jobs(SharedQueueThreadPool::new(8)?)
+}
+
+#[test]
+fn rayon_thread_pool_join() -> Result<()> {
+    join_runs_queued_jobs(RayonThreadPool::new(4)?)
+}
+
+#[test]
+fn naive_thread_pool_shutdown() -> Result<()> {
+    shutdown_waits_for_running_jobs(NaiveThreadPool::new(2)?)
+}
+
+#[test]
+fn shared_queue_thread_pool_shutdown() -> Result<()> {
+    shutdown_waits_for_running_jobs(SharedQueueThreadPool::new(2)?)?;
+    shutdown_cancels_queued_jobs(SharedQueueThreadPool::new(2)?)
+}
+
+#[test]
+fn rayon_thread_pool_shutdown() -> Result<()> {
+    shutdown_waits_for_running_jobs(RayonThreadPool::new(2)?)?;
+    shutdown_cancels_queued_jobs(RayonThreadPool::new(2)?)
+}
+
+#[test]
+fn work_stealing_thread_pool_join() -> Result<()> {
+    join_runs_queued_jobs(WorkStealingThreadPool::new(4)?)
+}
+
+#[test]
+fn work_stealing_thread_pool_shutdown() -> Result<()> {
+    shutdown_waits_for_running_jobs(WorkStealingThreadPool::new(2)?)?;
+    shutdown_cancels_queued_jobs(WorkStealingThreadPool::new(2)?)
+}
+
+#[test]
+fn naive_thread_pool_stats() -> Result<()> {
+    stats_count_jobs(NaiveThreadPool::new(2)?, 0)
+}
+
+#[test]
+fn shared_queue_thread_pool_stats() -> Result<()> {
+    stats_count_jobs(SharedQueueThreadPool::new(2)?, 2)
+}
+
+#[test]
+fn rayon_thread_pool_stats() -> Result<()> {
+    stats_count_jobs(RayonThreadPool::new(2)?, 2)
+}
+
+#[test]
+fn work_stealing_thread_pool_stats() -> Result<()> {
+    stats_count_jobs(WorkStealingThreadPool::new(2)?, 2)
+}
+
+#[test]
+fn bounded_queue_stats() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue(1, 2, QueuePolicy::Reject)?;
+    let ran = Arc::new(Mutex::new(Vec::new()));
+    let release = occupy_worker(&pool);
+    spawn_recorded(&pool, &ran, 1)?;
+    spawn_recorded(&pool, &ran, 2)?;
+    assert_eq!(pool.stats().queued, 2);
+    drop(release);
+    pool.join();
+    assert_eq!(*ran.lock().unwrap(), vec![1, 2]);
+    Ok(())
+}